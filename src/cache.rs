@@ -6,32 +6,169 @@ NOTICE: Adobe permits you to use, modify, and distribute this file in
 accordance with the terms of the Adobe license agreement accompanying
 it.
 */
-use crate::cops::{Kind, Request as CRequest, Response as CResponse};
-use crate::settings::{ProxyMode, Settings};
+pub mod checksum;
+pub mod lock;
+
+use crate::cops::{
+    current_timestamp, has_passed, is_expired, is_older_than, millis_between,
+    set_clock_skew, timestamp_after, Kind, Request as CRequest, Response as CResponse,
+};
+use crate::metrics;
+use crate::settings::{
+    CachePolicy, Checksum, EmptyBodyPolicy, Forward, FullPolicy, Kinds, ProxyMode,
+    Settings,
+};
 use dialoguer::Confirm;
 use eyre::{eyre, Result, WrapErr};
-use log::{debug, error, info};
+use hyper::HeaderMap;
+use lock::CacheLock;
+use log::{debug, error, info, warn};
+use rand::Rng;
+use serde::Serialize;
 use sqlx::sqlite::SqliteRow;
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
     ConnectOptions, Row,
 };
-use std::{env, str::FromStr, sync::Arc};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
 
 #[derive(Default)]
 pub struct Cache {
     enabled: bool,
+    // a standby's view of another proxy's cache, which it never changes
+    read_only: bool,
     mode: ProxyMode,
+    kinds: Option<Kinds>,
+    response_ttl: u64,
+    pinned_keys: Vec<String>,
+    // seconds past the ttl a response may be served while it's refreshed
+    stale_window: Option<u64>,
+    max_forward_age: u64,
+    namespace: String,
+    keep_history: bool,
+    max_stored: u64,
+    when_full: FullPolicy,
+    key_headers: Vec<String>,
+    key_ignore: Vec<String>,
+    checksum: Checksum,
+    empty_bodies: EmptyBodyPolicy,
+    verify_checksums: bool,
+    delete_corrupt: bool,
+    db_path: String,
+    retry_backoff: Option<Forward>,
     db_pool: Option<SqlitePool>,
+    // whether the last attempt to store a request failed
+    unavailable: AtomicBool,
+    // held for as long as the cache is open
+    _lock: Option<CacheLock>,
+}
+
+/// A cached response which never expires.
+#[derive(Debug)]
+pub struct PinnedResponse {
+    pub kind: Kind,
+    pub key: String,
+    pub timestamp: String,
+}
+
+/// The number of entries removed by a purge.
+#[derive(Debug, Default)]
+pub struct PurgeCounts {
+    pub responses: u64,
+    pub requests: u64,
+}
+
+/// The number of orphaned responses removed by a garbage collection.
+#[derive(Debug, Default)]
+pub struct GcCounts {
+    pub activation_responses: u64,
+    pub deactivation_responses: u64,
+}
+
+/// The number of entries removed by deleting a cache key.
+#[derive(Debug, Default)]
+pub struct DeleteCounts {
+    pub responses: u64,
+    pub requests: u64,
+}
+
+/// A summary of the cache contents (in the current namespace).
+#[derive(Debug, Default, Serialize)]
+pub struct CacheStats {
+    pub namespace: String,
+    pub stored_requests: u64,
+    pub pending_forwards: u64,
+    pub cached_responses: u64,
+    /// The limit on un-forwarded requests (0 for none).
+    pub max_stored_requests: u64,
+    pub unforwarded_requests: u64,
+    pub at_capacity: bool,
+    /// Requests set aside after too many failed forwarding attempts.
+    pub dead_letters: u64,
+    pub oldest_entry: Option<String>,
+    pub newest_entry: Option<String>,
+    pub db_size_bytes: u64,
+}
+
+/// The differences between two cache dbs, such as two exports.
+///
+/// Entries are described by their kind and cache key.
+#[derive(Debug, Default)]
+pub struct CacheDiff {
+    pub requests_only_in_a: Vec<String>,
+    pub requests_only_in_b: Vec<String>,
+    pub responses_only_in_a: Vec<String>,
+    pub responses_only_in_b: Vec<String>,
+    /// Responses in both dbs, with different bodies.
+    pub responses_differing: Vec<String>,
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.requests_only_in_a.is_empty()
+            && self.requests_only_in_b.is_empty()
+            && self.responses_only_in_a.is_empty()
+            && self.responses_only_in_b.is_empty()
+            && self.responses_differing.is_empty()
+    }
 }
 
 impl Cache {
     pub async fn from(conf: &Settings, can_create: bool) -> Result<Arc<Cache>> {
+        Cache::open(conf, can_create, None).await
+    }
+
+    /// Open the cache for a proxy which makes changes to it while it
+    /// runs, locking the cache so that no other such proxy can use it.
+    pub async fn exclusive(conf: &Settings, can_create: bool) -> Result<Arc<Cache>> {
+        if let ProxyMode::Passthrough = conf.proxy.mode {
+            return Ok(Arc::new(Cache::default()));
+        }
+        if conf.cache.read_only {
+            // the proxy which writes to the cache holds the lock
+            return Cache::open(conf, false, None).await;
+        }
+        let timeout = Duration::from_secs(conf.cache.lock_timeout_secs);
+        let lock = CacheLock::acquire(&conf.cache.db_path, timeout).await?;
+        Cache::open(conf, can_create, Some(lock)).await
+    }
+
+    async fn open(
+        conf: &Settings, can_create: bool, lock: Option<CacheLock>,
+    ) -> Result<Arc<Cache>> {
         if let ProxyMode::Passthrough = conf.proxy.mode {
             return Ok(Arc::new(Cache::default()));
         }
+        set_clock_skew(conf.cache.clock_skew_secs);
         let db_name = &conf.cache.db_path;
-        let mode = if can_create {
+        let mode = if conf.cache.read_only {
+            std::fs::metadata(db_name)
+                .wrap_err(format!("Can't open read-only cache db: {}", db_name))?;
+            "ro"
+        } else if can_create {
             "rwc"
         } else {
             std::fs::metadata(db_name)
@@ -41,11 +178,39 @@ impl Cache {
         let pool = db_init(db_name, mode)
             .await
             .wrap_err(format!("Can't connect to cache db: {}", db_name))?;
-        info!("Valid cache database: {}", &db_name);
+        if conf.cache.read_only {
+            info!("Valid cache database (read-only): {}", &db_name);
+        } else {
+            info!("Valid cache database: {}", &db_name);
+        }
         Ok(Arc::new(Cache {
             enabled: true,
+            read_only: conf.cache.read_only,
             mode: conf.proxy.mode.clone(),
+            kinds: Some(conf.kinds.clone()),
+            response_ttl: conf.cache.response_ttl_secs,
+            pinned_keys: conf.cache.pinned_keys.clone(),
+            stale_window: if conf.cache.stale_while_revalidate {
+                Some(conf.cache.stale_window_secs)
+            } else {
+                None
+            },
+            max_forward_age: conf.cache.max_forward_age_secs,
+            namespace: conf.cache.namespace.clone(),
+            keep_history: conf.cache.keep_response_history,
+            max_stored: conf.cache.max_stored_requests,
+            when_full: conf.cache.when_full.clone(),
+            key_headers: conf.cache.key_headers.clone(),
+            key_ignore: conf.cache.key_ignore.clone(),
+            checksum: conf.cache.checksum.clone(),
+            empty_bodies: conf.cache.empty_success_body.clone(),
+            verify_checksums: conf.cache.verify_checksums,
+            delete_corrupt: conf.cache.delete_corrupt,
+            db_path: db_name.clone(),
+            retry_backoff: Some(conf.forward.clone()),
             db_pool: Some(pool),
+            unavailable: AtomicBool::new(false),
+            _lock: lock,
         }))
     }
 
@@ -79,23 +244,127 @@ impl Cache {
         Ok(())
     }
 
+    /// Check the cache db can be read and (unless it's read-only) written,
+    /// leaving it unchanged.
+    pub async fn round_trip(&self) -> Result<()> {
+        let pool = match &self.db_pool {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+        if self.read_only {
+            sqlx::query(READ_ONLY_CHECK).fetch_one(pool).await?;
+            return Ok(());
+        }
+        let value = current_timestamp();
+        let mut tx = pool.begin().await?;
+        sqlx::query(ROUND_TRIP_SCHEMA).execute(&mut tx).await?;
+        sqlx::query(ROUND_TRIP_WRITE).bind(&value).execute(&mut tx).await?;
+        let row = sqlx::query(ROUND_TRIP_READ).fetch_one(&mut tx).await?;
+        let found: String = row.get("value");
+        // nothing written is kept
+        tx.rollback().await?;
+        if found == value {
+            Ok(())
+        } else {
+            Err(eyre!("Read back {:?} after writing {:?}", found, value))
+        }
+    }
+
+    /// Summarize the cache contents.
+    ///
+    /// This only reads the cache, so it's safe to use while the proxy is running.
+    pub async fn stats(&self) -> Result<CacheStats> {
+        let mut stats =
+            CacheStats { namespace: self.namespace.clone(), ..Default::default() };
+        if !self.enabled {
+            return Ok(stats);
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        let row = sqlx::query(CACHE_STATS)
+            .bind(ns)
+            .bind(ns)
+            .bind(ns)
+            .bind(ns)
+            .fetch_one(pool)
+            .await?;
+        let requests: i64 = row.get("requests");
+        let responses: i64 = row.get("responses");
+        stats.stored_requests = requests as u64;
+        stats.cached_responses = responses as u64;
+        stats.oldest_entry = row.get("oldest");
+        stats.newest_entry = row.get("newest");
+        let unanswered = fetch_unanswered_requests(pool, ns).await?;
+        stats.dead_letters =
+            unanswered.iter().filter(|req| self.is_dead_letter(req)).count() as u64;
+        stats.pending_forwards = self.eligible(unanswered).len() as u64;
+        stats.max_stored_requests = self.max_stored;
+        stats.unforwarded_requests = count_unforwarded(pool, ns).await?;
+        stats.at_capacity =
+            self.max_stored > 0 && stats.unforwarded_requests >= self.max_stored;
+        stats.db_size_bytes =
+            std::fs::metadata(&self.db_path).map(|m| m.len()).unwrap_or(0);
+        Ok(stats)
+    }
+
+    /// The namespaces with entries in the cache, and how many entries each has.
+    pub async fn namespaces(&self) -> Result<Vec<(String, u64)>> {
+        let pool = self.db_pool.as_ref().unwrap();
+        let rows = sqlx::query(NAMESPACE_COUNTS).fetch_all(pool).await?;
+        let mut result = Vec::new();
+        for row in rows.iter() {
+            let entries: i64 = row.get("entries");
+            result.push((row.get("namespace"), entries as u64));
+        }
+        Ok(result)
+    }
+
+    pub async fn clear_namespace(&self, ns: &str, yes: bool) -> Result<()> {
+        let confirm = match yes {
+            true => true,
+            false => Confirm::new()
+                .with_prompt(format!(
+                    "Really clear namespace '{}'? This operation cannot be undone.",
+                    ns
+                ))
+                .default(false)
+                .show_default(true)
+                .interact()?,
+        };
+        if confirm {
+            let pool = self.db_pool.as_ref().unwrap();
+            let mut tx = pool.begin().await?;
+            for table in TABLES.iter() {
+                let d_str = format!("delete from {} where namespace = ?", table);
+                sqlx::query(&d_str).bind(ns).execute(&mut tx).await?;
+            }
+            tx.commit().await?;
+            eprintln!("Namespace '{}' has been cleared.", ns);
+        }
+        Ok(())
+    }
+
     pub async fn import(&self, path: &str) -> Result<()> {
         std::fs::metadata(path)?;
         // first read the forwarded pairs
         let in_pool = db_init(path, "rw").await?;
-        let pairs = fetch_forwarded_pairs(&in_pool).await?;
+        let pairs = fetch_forwarded_pairs(&in_pool, &self.namespace).await?;
         in_pool.close().await;
         // now add them to the cache
         let out_pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
         let pairs_count = pairs.len();
         for (req, resp) in pairs.iter() {
+            let sum = checksum::compute(&self.checksum, &resp.body);
             match req.kind {
                 Kind::Activation => {
-                    store_activation_response(ProxyMode::Cache, out_pool, req, resp)
+                    let mode = ProxyMode::Cache;
+                    store_activation_response(mode, out_pool, ns, req, resp, &sum)
                         .await?;
                 }
                 Kind::Deactivation => {
-                    store_deactivation_response(ProxyMode::Cache, out_pool, req, resp)
+                    let mode = ProxyMode::Cache;
+                    store_deactivation_response(mode, out_pool, ns, req, resp, &sum)
                         .await?;
                 }
             }
@@ -114,15 +383,18 @@ impl Cache {
         }
         // first read the unanswered requests
         let in_pool = self.db_pool.as_ref().unwrap();
-        let requests = fetch_unanswered_requests(in_pool).await?;
+        let ns = &self.namespace;
+        let requests = self.eligible(fetch_unanswered_requests(in_pool, ns).await?);
         in_pool.close().await;
         // now store them to the other database
         let request_count = requests.len();
         let out_pool = db_init(path, "rwc").await?;
         for req in requests.iter() {
             match req.kind {
-                Kind::Activation => store_activation_request(&out_pool, req).await?,
-                Kind::Deactivation => store_deactivation_request(&out_pool, req).await?,
+                Kind::Activation => store_activation_request(&out_pool, ns, req).await?,
+                Kind::Deactivation => {
+                    store_deactivation_request(&out_pool, ns, req).await?
+                }
             }
         }
         out_pool.close().await;
@@ -130,45 +402,309 @@ impl Cache {
         Ok(())
     }
 
-    pub async fn store_request(&self, req: &CRequest) {
-        if !self.enabled {
+    /// Apply the configured cache key factors to a newly received request.
+    ///
+    /// With the default factors the request is left alone, so its key is
+    /// the same as in caches made before key factors were configurable.
+    pub fn set_key_factors(&self, req: &mut CRequest, headers: &HeaderMap) {
+        if self.key_headers.is_empty() && self.key_ignore.is_empty() {
             return;
         }
+        let mut factors: Vec<String> = Vec::new();
+        for (field, value) in [("app_id", &req.app_id), ("ngl_version", &req.ngl_version)]
+        {
+            if !self.key_ignore.iter().any(|f| f == field) {
+                factors.push(value.clone());
+            }
+        }
+        for name in self.key_headers.iter() {
+            let value =
+                headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+            factors.push(format!("{}={}", name.to_ascii_lowercase(), value));
+        }
+        // the marker keeps custom keys distinct from default ones
+        req.key_extra = format!("~{}", factors.join("|"));
+    }
+
+    /// Whether the last attempt to store a request failed, so that a
+    /// request might not be stored.
+    pub fn is_unavailable(&self) -> bool {
+        self.unavailable.load(Ordering::Relaxed)
+    }
+
+    /// Store a request for forwarding, unless the cache is full.
+    ///
+    /// Returns whether the request was stored (or there is no cache),
+    /// or an error if the cache couldn't be written.
+    pub async fn store_request(&self, req: &CRequest) -> Result<bool> {
+        if !self.enabled {
+            return Ok(true);
+        }
+        if self.read_only {
+            debug!(
+                "Not storing {} request {} (read-only cache)",
+                req.kind, req.request_id
+            );
+            return Ok(true);
+        }
         let pool = self.db_pool.as_ref().unwrap();
-        if let Err(err) = match req.kind {
-            Kind::Activation => store_activation_request(pool, req).await,
-            Kind::Deactivation => store_deactivation_request(pool, req).await,
-        } {
+        let ns = &self.namespace;
+        if self.max_stored > 0 && !self.make_room(pool, req).await {
+            return Ok(false);
+        }
+        let result = match req.kind {
+            Kind::Activation => store_activation_request(pool, ns, req).await,
+            Kind::Deactivation => store_deactivation_request(pool, ns, req).await,
+        };
+        self.unavailable.store(result.is_err(), Ordering::Relaxed);
+        if let Err(err) = result {
             error!("Cache of {} request {} failed: {:?}", req.kind, req.request_id, err);
+            return Err(err);
         }
+        Ok(true)
+    }
+
+    /// Make sure there's room for a new request under the configured
+    /// cap, evicting the oldest if that's the policy.
+    ///
+    /// Returns whether the new request should be stored.
+    async fn make_room(&self, pool: &SqlitePool, req: &CRequest) -> bool {
+        let ns = &self.namespace;
+        let mut count = match count_unforwarded(pool, ns).await {
+            Ok(count) => count,
+            Err(err) => {
+                // don't lose requests because the count isn't known
+                error!("Count of un-forwarded requests failed: {:?}", err);
+                return true;
+            }
+        };
+        metrics::set_unforwarded_requests(count);
+        while count >= self.max_stored {
+            if let FullPolicy::Reject = self.when_full {
+                info!(
+                    "Not storing {} request {}: the cache holds {} request(s) already",
+                    req.kind, req.request_id, count
+                );
+                metrics::count_unstored_request();
+                return false;
+            }
+            match evict_oldest_request(pool, ns).await {
+                Ok(true) => {
+                    metrics::count_evicted_request();
+                    count -= 1;
+                }
+                Ok(false) => break,
+                Err(err) => {
+                    error!("Eviction of oldest request failed: {:?}", err);
+                    break;
+                }
+            }
+        }
+        metrics::set_unforwarded_requests(count + 1);
+        true
     }
 
     pub async fn store_response(&self, req: &CRequest, resp: &CResponse) {
         if !self.enabled {
             return;
         }
+        if self.read_only {
+            debug!(
+                "Not caching {} response {} (read-only cache)",
+                req.kind, req.request_id
+            );
+            return;
+        }
+        if !self.caches(req) {
+            debug!("Not caching {} response {} (live policy)", req.kind, req.request_id);
+            return;
+        }
+        if resp.body.is_empty() && !matches!(self.empty_bodies, EmptyBodyPolicy::Cache) {
+            info!("Not caching {} response {} (empty body)", req.kind, req.request_id);
+            return;
+        }
         let pool = self.db_pool.as_ref().unwrap();
         let mode = self.mode.clone();
+        let ns = &self.namespace;
+        let sum = checksum::compute(&self.checksum, &resp.body);
         if let Err(err) = match req.kind {
-            Kind::Activation => store_activation_response(mode, pool, req, resp).await,
+            Kind::Activation => {
+                store_activation_response(mode, pool, ns, req, resp, &sum).await
+            }
             Kind::Deactivation => {
-                store_deactivation_response(mode, pool, req, resp).await
+                store_deactivation_response(mode, pool, ns, req, resp, &sum).await
             }
         } {
             error!("Cache of {} response {} failed: {:?}", req.kind, req.request_id, err);
+        } else if self.keep_history {
+            if let Err(err) = store_response_history(pool, ns, req, resp).await {
+                error!(
+                    "Archive of {} response {} failed: {:?}",
+                    req.kind, req.request_id, err
+                );
+            }
+        }
+    }
+
+    /// Make the cached response to a request fresh again, because COPS
+    /// has confirmed (with a 304) that it hasn't changed.
+    pub async fn refresh_response(&self, req: &CRequest) {
+        if !self.enabled || self.read_only {
+            return;
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let a_key = activation_id(&self.namespace, req);
+        let u_str =
+            "update activation_responses set timestamp = ? where activation_key = ?";
+        let result =
+            sqlx::query(u_str).bind(current_timestamp()).bind(&a_key).execute(pool).await;
+        if let Err(err) = result {
+            error!(
+                "Refresh of {} response {} failed: {:?}",
+                req.kind, req.request_id, err
+            );
         }
     }
 
     pub async fn fetch_response(&self, req: &CRequest) -> Option<CResponse> {
+        self.fetch_response_within(req, self.response_ttl(&req.kind)).await
+    }
+
+    /// Fetch the cached response to a request however old it is.
+    pub async fn fetch_any_response(&self, req: &CRequest) -> Option<CResponse> {
+        self.fetch_response_within(req, 0).await
+    }
+
+    /// Whether cached responses are served before asking COPS.
+    pub fn serves_stale(&self) -> bool {
+        self.enabled && self.stale_window.is_some()
+    }
+
+    /// Fetch a cached response which may be served while it's refreshed:
+    /// one that expired no more than the stale window ago.
+    ///
+    /// Responses that are still fresh, or that never expire (because
+    /// their TTL is 0 or they are pinned), are never stale.
+    pub async fn fetch_stale_response(&self, req: &CRequest) -> Option<CResponse> {
+        let ttl = self.response_ttl(&req.kind);
+        let window = match self.stale_window {
+            Some(window) if ttl > 0 => window,
+            _ => return None,
+        };
+        if self.pinned_keys.contains(&self.response_key(req)) {
+            return None;
+        }
+        let resp = self.fetch_response_within(req, ttl + window).await?;
+        if is_expired(&resp.timestamp, ttl) {
+            Some(resp)
+        } else {
+            None
+        }
+    }
+
+    /// The key a request's response is cached under.
+    pub fn response_key(&self, req: &CRequest) -> String {
+        response_key(&self.namespace, req)
+    }
+
+    /// How long cached responses of a kind may be served (0 means forever).
+    fn response_ttl(&self, kind: &Kind) -> u64 {
+        if self.kind_pinned(kind) {
+            return 0;
+        }
+        let policy =
+            self.kinds.as_ref().and_then(|kinds| kinds.policy(kind).response_ttl_secs);
+        policy.unwrap_or(self.response_ttl)
+    }
+
+    fn kind_pinned(&self, kind: &Kind) -> bool {
+        self.kinds.as_ref().is_some_and(|kinds| kinds.policy(kind).pinned)
+    }
+
+    /// The cached responses which never expire, because their kind or
+    /// key is pinned, with the keys of pinned responses that aren't in
+    /// the cache.
+    pub async fn pinned_responses(&self) -> Result<(Vec<PinnedResponse>, Vec<String>)> {
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        let mut pinned = Vec::new();
+        let mut missing = self.pinned_keys.clone();
+        for (kind, table, key) in [
+            (Kind::Activation, "activation_responses", "activation_key"),
+            (Kind::Deactivation, "deactivation_responses", "deactivation_key"),
+        ]
+        .iter()
+        {
+            let q_str =
+                format!("select {}, timestamp from {} where namespace = ?", key, table);
+            let rows = sqlx::query(&q_str).bind(ns).fetch_all(pool).await?;
+            for row in rows.iter() {
+                let k: String = row.get(*key);
+                if self.kind_pinned(kind) || self.pinned_keys.contains(&k) {
+                    missing.retain(|m| *m != k);
+                    pinned.push(PinnedResponse {
+                        kind: kind.clone(),
+                        key: k,
+                        timestamp: row.get("timestamp"),
+                    });
+                }
+            }
+        }
+        Ok((pinned, missing))
+    }
+
+    /// The age at which cached responses of a kind are no use (0 means never).
+    fn max_response_age(&self, kind: &Kind) -> u64 {
+        let ttl = self.response_ttl(kind);
+        match self.stale_window {
+            Some(window) if ttl > 0 => ttl + window,
+            _ => ttl,
+        }
+    }
+
+    async fn fetch_response_within(&self, req: &CRequest, ttl: u64) -> Option<CResponse> {
         if !self.enabled {
             return None;
         }
+        if !self.caches(req) {
+            debug!(
+                "Not using cache for {} request {} (live policy)",
+                req.kind, req.request_id
+            );
+            return None;
+        }
         let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
         match match req.kind {
-            Kind::Activation => fetch_activation_response(pool, req).await,
-            Kind::Deactivation => fetch_deactivation_response(pool, req).await,
+            Kind::Activation => fetch_activation_response(pool, ns, req).await,
+            Kind::Deactivation => fetch_deactivation_response(pool, ns, req).await,
         } {
-            Ok(resp) => resp,
+            Ok(Some((resp, sum))) if self.is_corrupt(&resp, &sum) => {
+                warn!(
+                    "Cached {} response {} doesn't match its checksum: ignoring it",
+                    req.kind, req.request_id
+                );
+                metrics::count_corrupt_response();
+                if self.delete_corrupt && !self.read_only {
+                    match delete_response(pool, ns, req).await {
+                        Ok(()) => info!("Deleted corrupt {} response", req.kind),
+                        Err(err) => error!(
+                            "Delete of corrupt {} response {} failed: {:?}",
+                            req.kind, req.request_id, err
+                        ),
+                    }
+                }
+                None
+            }
+            Ok(Some((resp, _)))
+                if ttl > 0
+                    && is_expired(&resp.timestamp, ttl)
+                    && !self.pinned_keys.contains(&response_key(ns, req)) =>
+            {
+                debug!("Cached {} response {} has expired", req.kind, req.request_id);
+                None
+            }
+            Ok(resp) => resp.map(|(resp, _)| resp),
             Err(err) => {
                 error!(
                     "Fetch of {} response {} failed: {:?}",
@@ -179,19 +715,419 @@ impl Cache {
         }
     }
 
-    pub async fn fetch_forwarding_requests(&self) -> Vec<CRequest> {
+    /// Whether checksums are verified, and a response doesn't match its own.
+    fn is_corrupt(&self, resp: &CResponse, sum: &str) -> bool {
+        if !self.verify_checksums {
+            return false;
+        }
+        match checksum::verify(sum, &resp.body) {
+            Some(ok) => !ok,
+            None => {
+                debug!("Cached response {} has no checksum to verify", resp.request_id);
+                false
+            }
+        }
+    }
+
+    /// Remove all expired responses and over-age requests.
+    pub async fn purge(&self) -> Result<PurgeCounts> {
+        let mut counts = PurgeCounts::default();
+        if !self.enabled {
+            return Ok(counts);
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        // stale responses are kept for as long as they may be served
+        let max_ages = [
+            self.max_response_age(&Kind::Activation),
+            self.max_response_age(&Kind::Deactivation),
+        ];
+        counts.responses =
+            purge_expired_responses(pool, ns, max_ages, &self.pinned_keys).await?;
+        if self.max_forward_age > 0 {
+            counts.requests =
+                purge_over_age_requests(pool, ns, self.max_forward_age).await?;
+        }
+        Ok(counts)
+    }
+
+    /// Remove cached responses which have no stored request, except
+    /// those with pinned keys.
+    ///
+    /// Each table is cleaned in a single transaction, so this is safe
+    /// to do while a proxy is using the cache.
+    pub async fn gc(&self) -> Result<GcCounts> {
+        let mut counts = GcCounts::default();
+        if !self.enabled {
+            return Ok(counts);
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        let removed = remove_orphaned_responses(pool, ns, &self.pinned_keys).await?;
+        counts.activation_responses = removed[0];
+        counts.deactivation_responses = removed[1];
+        Ok(counts)
+    }
+
+    /// Drop any requests which are too old to be forwarded, or which
+    /// have been dead-lettered.
+    fn eligible(&self, requests: Vec<CRequest>) -> Vec<CRequest> {
+        let (dead, requests): (Vec<CRequest>, Vec<CRequest>) =
+            requests.into_iter().partition(|req| self.is_dead_letter(req));
+        for req in dead.iter() {
+            debug!("Skipping dead-lettered {} request {}", req.kind, req.request_id);
+        }
+        if self.max_forward_age == 0 {
+            return requests;
+        }
+        let age = self.max_forward_age;
+        let (keep, drop): (Vec<CRequest>, Vec<CRequest>) =
+            requests.into_iter().partition(|req| !is_older_than(&req.timestamp, age));
+        for req in drop.iter() {
+            debug!("Skipping over-age {} request {}", req.kind, req.request_id);
+        }
+        keep
+    }
+
+    /// Whether responses to a request are cached (or served from the cache).
+    pub fn caches_responses(&self, req: &CRequest) -> bool {
+        self.enabled && self.caches(req)
+    }
+
+    /// Whether responses to this request are kept in the cache.
+    ///
+    /// Forwarders always keep responses, because they are exported
+    /// to the store that asked for them.
+    fn caches(&self, req: &CRequest) -> bool {
+        if let ProxyMode::Forward = self.mode {
+            return true;
+        }
+        match &self.kinds {
+            Some(kinds) => matches!(kinds.policy(&req.kind).policy, CachePolicy::Cache),
+            None => true,
+        }
+    }
+
+    /// The stored requests which should be forwarded now.
+    ///
+    /// They are in the order they were received: by timestamp and then,
+    /// for requests received in the same millisecond, in the order they
+    /// were stored.  Forwarding runs send them in this order.
+    ///
+    /// Requests waiting out a retry backoff are left out, unless
+    /// `include_deferred` is set.
+    pub async fn fetch_forwarding_requests(
+        &self, include_deferred: bool,
+    ) -> Vec<CRequest> {
         if !self.enabled {
             return Vec::new();
         }
         let pool = self.db_pool.as_ref().unwrap();
-        match fetch_unanswered_requests(pool).await {
-            Ok(result) => result,
+        match fetch_unanswered_requests(pool, &self.namespace).await {
+            Ok(result) => {
+                let (due, waiting): (Vec<CRequest>, Vec<CRequest>) = self
+                    .eligible(result)
+                    .into_iter()
+                    .partition(|r| include_deferred || has_passed(&r.next_retry));
+                for req in waiting.iter() {
+                    debug!(
+                        "Not retrying {} request {} until {}",
+                        req.kind, req.request_id, req.next_retry
+                    );
+                }
+                due
+            }
             Err(err) => {
                 error!("Fetch of forwarding requests failed: {:?}", err);
                 Vec::new()
             }
         }
     }
+
+    /// How many seconds the oldest stored request which may be forwarded
+    /// now has been waiting, if there is one.
+    pub async fn oldest_pending_age_secs(&self) -> Option<u64> {
+        let requests = self.fetch_forwarding_requests(false).await;
+        let oldest = requests.first()?;
+        let millis = millis_between(&oldest.timestamp, &current_timestamp())?;
+        Some(millis.max(0) as u64 / 1000)
+    }
+
+    /// Whether a request has failed too many times to be forwarded again
+    /// (until it's requeued).
+    fn is_dead_letter(&self, req: &CRequest) -> bool {
+        match &self.retry_backoff {
+            Some(conf) => {
+                conf.max_attempts > 0 && req.attempts >= conf.max_attempts as i64
+            }
+            None => false,
+        }
+    }
+
+    /// The stored requests which have been dead-lettered.
+    pub async fn dead_letters(&self) -> Result<Vec<CRequest>> {
+        let pool = self.db_pool.as_ref().unwrap();
+        let requests = fetch_unanswered_requests(pool, &self.namespace).await?;
+        Ok(requests.into_iter().filter(|req| self.is_dead_letter(req)).collect())
+    }
+
+    /// The key under which a request's responses are cached, as listed
+    /// by the `pinned`, `list` and `diff --details` commands.
+    pub fn key(&self, req: &CRequest) -> String {
+        response_key(&self.namespace, req)
+    }
+
+    /// Remove the cached responses with a key and, if `with_requests`,
+    /// the stored requests with it too (even if they haven't been
+    /// forwarded).
+    pub async fn delete_key(
+        &self, key: &str, with_requests: bool,
+    ) -> Result<DeleteCounts> {
+        let mut counts = DeleteCounts::default();
+        if !self.enabled {
+            return Ok(counts);
+        }
+        if self.read_only {
+            return Err(eyre!("The cache is read-only"));
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let mut tx = pool.begin().await?;
+        for (table, column) in [
+            ("activation_responses", "activation_key"),
+            ("deactivation_responses", "deactivation_key"),
+        ]
+        .iter()
+        {
+            let d_str = format!("delete from {} where {} = ?", table, column);
+            let result = sqlx::query(&d_str).bind(key).execute(&mut tx).await?;
+            counts.responses += result.rows_affected();
+        }
+        if with_requests {
+            for (table, column) in [
+                ("activation_requests", "activation_key"),
+                ("deactivation_requests", "deactivation_key"),
+            ]
+            .iter()
+            {
+                let d_str = format!("delete from {} where {} = ?", table, column);
+                let result = sqlx::query(&d_str).bind(key).execute(&mut tx).await?;
+                counts.requests += result.rows_affected();
+            }
+        }
+        tx.commit().await?;
+        Ok(counts)
+    }
+
+    /// Put dead-lettered requests back in line for forwarding, with
+    /// their attempts forgotten.  With no `request_id`, all of them are
+    /// requeued.  Returns the number requeued.
+    pub async fn requeue(&self, request_id: Option<&str>) -> Result<u64> {
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        let mut count = 0;
+        for req in self.dead_letters().await?.iter() {
+            if matches!(request_id, Some(id) if id != req.request_id) {
+                continue;
+            }
+            let (table, key, id) = request_row_key(ns, req);
+            let u_str = format!(
+                "update {} set attempts = 0, next_retry = '' where {} = ?",
+                table, key
+            );
+            sqlx::query(&u_str).bind(&id).execute(pool).await?;
+            info!(
+                "Requeued dead-lettered {} request {} after {} attempt(s)",
+                req.kind, req.request_id, req.attempts
+            );
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Note why a call to COPS for a stored request failed, without
+    /// counting it as a forwarding attempt.
+    pub async fn record_call_error(&self, req: &CRequest, error: &str) {
+        if !self.enabled || self.read_only {
+            return;
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        let (table, key, id) = request_row_key(&self.namespace, req);
+        let u_str = format!(
+            "update {} set last_error = ?, last_attempt_at = ? where {} = ?",
+            table, key
+        );
+        if let Err(err) = sqlx::query(&u_str)
+            .bind(error)
+            .bind(current_timestamp())
+            .bind(&id)
+            .execute(pool)
+            .await
+        {
+            error!("Update of {} request {} failed: {:?}", req.kind, req.request_id, err);
+        }
+    }
+
+    /// The stored requests which haven't been forwarded yet, including
+    /// those backing off and those dead-lettered.
+    pub async fn unforwarded_requests(&self) -> Result<Vec<CRequest>> {
+        if !self.enabled {
+            return Ok(Vec::new());
+        }
+        let pool = self.db_pool.as_ref().unwrap();
+        fetch_unanswered_requests(pool, &self.namespace).await
+    }
+
+    /// Note that forwarding a request failed, and why, and back off
+    /// before retrying it.
+    ///
+    /// The delay doubles with each failed attempt, up to the configured
+    /// maximum, and is randomly varied by the configured jitter so that
+    /// requests which failed together aren't all retried together.
+    pub async fn record_forward_failure(&self, req: &CRequest, error: &str) {
+        if !self.enabled || self.read_only {
+            return;
+        }
+        let attempts = req.attempts + 1;
+        let delay = match &self.retry_backoff {
+            Some(conf) => backoff_secs(conf, attempts),
+            None => 0,
+        };
+        let next_retry = if delay > 0 { timestamp_after(delay) } else { String::new() };
+        let pool = self.db_pool.as_ref().unwrap();
+        let ns = &self.namespace;
+        debug!(
+            "{} request {} has failed {} time(s), next retry in {} seconds",
+            req.kind, req.request_id, attempts, delay
+        );
+        let (table, key, id) = request_row_key(ns, req);
+        let u_str = format!(
+            "update {} set attempts = ?, next_retry = ?, last_error = ?, last_attempt_at = ? where {} = ?",
+            table, key
+        );
+        if let Err(err) = sqlx::query(&u_str)
+            .bind(attempts)
+            .bind(&next_retry)
+            .bind(error)
+            .bind(current_timestamp())
+            .bind(&id)
+            .execute(pool)
+            .await
+        {
+            error!("Update of {} request {} failed: {:?}", req.kind, req.request_id, err);
+            return;
+        }
+        let req = CRequest { attempts, ..req.clone() };
+        if self.is_dead_letter(&req) {
+            warn!(
+                "Dead-lettered {} request {} after {} failed attempt(s)",
+                req.kind, req.request_id, attempts
+            );
+        }
+    }
+}
+
+/// The table, key column, and key of the row holding a stored request.
+fn request_row_key(ns: &str, req: &CRequest) -> (&'static str, &'static str, String) {
+    match req.kind {
+        Kind::Activation => {
+            ("activation_requests", "activation_key", activation_id(ns, req))
+        }
+        Kind::Deactivation => {
+            ("deactivation_requests", "deactivation_key", deactivation_id(ns, req))
+        }
+    }
+}
+
+fn backoff_secs(conf: &Forward, attempts: i64) -> u64 {
+    if conf.retry_backoff_secs == 0 {
+        return 0;
+    }
+    let doublings = (attempts - 1).clamp(0, 32) as u32;
+    let mut delay =
+        conf.retry_backoff_secs.saturating_mul(2u64.saturating_pow(doublings));
+    if conf.retry_backoff_max_secs > 0 {
+        delay = delay.min(conf.retry_backoff_max_secs);
+    }
+    if conf.retry_jitter > 0.0 {
+        let factor = rand::thread_rng().gen_range(-conf.retry_jitter..=conf.retry_jitter);
+        delay = (delay as f64 * (1.0 + factor)).round() as u64;
+    }
+    delay
+}
+
+/// Compare the contents of two cache dbs (in all namespaces), without
+/// changing either of them.
+pub async fn diff(path_a: &str, path_b: &str) -> Result<CacheDiff> {
+    let (requests_a, responses_a) = diff_entries(path_a).await?;
+    let (requests_b, responses_b) = diff_entries(path_b).await?;
+    let mut diff = CacheDiff::default();
+    for key in requests_a.keys() {
+        if !requests_b.contains_key(key) {
+            diff.requests_only_in_a.push(key.clone());
+        }
+    }
+    for key in requests_b.keys() {
+        if !requests_a.contains_key(key) {
+            diff.requests_only_in_b.push(key.clone());
+        }
+    }
+    for (key, body) in responses_a.iter() {
+        match responses_b.get(key) {
+            None => diff.responses_only_in_a.push(key.clone()),
+            Some(other) if other != body => diff.responses_differing.push(key.clone()),
+            Some(_) => (),
+        }
+    }
+    for key in responses_b.keys() {
+        if !responses_a.contains_key(key) {
+            diff.responses_only_in_b.push(key.clone());
+        }
+    }
+    Ok(diff)
+}
+
+/// The request ids and response bodies in a cache db, by kind and key.
+async fn diff_entries(
+    path: &str,
+) -> Result<(BTreeMap<String, String>, BTreeMap<String, String>)> {
+    std::fs::metadata(path).wrap_err(format!("Can't open cache db: {}", path))?;
+    let pool = db_init(path, "ro")
+        .await
+        .wrap_err(format!("Can't connect to cache db: {}", path))?;
+    let rows = sqlx::query(DIFF_REQUESTS).fetch_all(&pool).await?;
+    let requests = rows.iter().map(|row| diff_entry(row, "request_id")).collect();
+    let rows = sqlx::query(DIFF_RESPONSES).fetch_all(&pool).await?;
+    let responses = rows.iter().map(|row| diff_entry(row, "body")).collect();
+    pool.close().await;
+    Ok((requests, responses))
+}
+
+fn diff_entry(row: &SqliteRow, value: &str) -> (String, String) {
+    let kind: String = row.get("kind");
+    let key: String = row.get("key");
+    (format!("{} {}", kind, key), row.get(value))
+}
+
+/// Start a background task which purges the cache on the configured interval.
+pub fn spawn_sweeper(conf: &Settings, cache: Arc<Cache>) -> Option<JoinHandle<()>> {
+    let secs = conf.cache.purge_interval_secs;
+    if secs == 0 || !cache.enabled || cache.read_only {
+        return None;
+    }
+    info!("Purging the cache every {} seconds", secs);
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            match cache.purge().await {
+                Ok(counts) => info!(
+                    "Purged {} expired response(s) and {} over-age request(s)",
+                    counts.responses, counts.requests
+                ),
+                Err(err) => error!("Purge of cache failed: {:?}", err),
+            }
+        }
+    }))
 }
 
 async fn db_init(db_name: &str, mode: &str) -> Result<SqlitePool> {
@@ -201,31 +1137,73 @@ async fn db_init(db_name: &str, mode: &str) -> Result<SqlitePool> {
         options.disable_statement_logging();
     }
     let pool = SqlitePoolOptions::new().max_connections(5).connect_with(options).await?;
+    if mode == "ro" {
+        // the proxy which writes to the db keeps its schema up to date
+        return Ok(pool);
+    }
     sqlx::query(ACTIVATION_REQUEST_SCHEMA).execute(&pool).await?;
     sqlx::query(DEACTIVATION_REQUEST_SCHEMA).execute(&pool).await?;
     sqlx::query(ACTIVATION_RESPONSE_SCHEMA).execute(&pool).await?;
     sqlx::query(DEACTIVATION_RESPONSE_SCHEMA).execute(&pool).await?;
+    sqlx::query(RESPONSE_HISTORY_SCHEMA).execute(&pool).await?;
+    migrate(&pool).await?;
+    init_seq(&pool).await?;
     Ok(pool)
 }
 
-async fn store_activation_request(pool: &SqlitePool, req: &CRequest) -> Result<()> {
+/// Create the counter of stored requests if there isn't one, starting
+/// it after the requests already stored.
+async fn init_seq(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(SEQ_SCHEMA).execute(pool).await?;
+    let rows: i64 =
+        sqlx::query("select count(*) from request_seq").fetch_one(pool).await?.get(0);
+    if rows == 0 {
+        sqlx::query(SEQ_SEED).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Add any columns introduced since the cache db was created.
+async fn migrate(pool: &SqlitePool) -> Result<()> {
+    for (table, column, definition) in MIGRATIONS.iter() {
+        let q_str =
+            format!("select 1 from pragma_table_info('{}') where name = ?", table);
+        let found = sqlx::query(&q_str).bind(column).fetch_optional(pool).await?;
+        if found.is_none() {
+            info!("Adding column {} to cache table {}", column, table);
+            let a_str =
+                format!("alter table {} add column {} {}", table, column, definition);
+            sqlx::query(&a_str).execute(pool).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn store_activation_request(
+    pool: &SqlitePool, ns: &str, req: &CRequest,
+) -> Result<()> {
     let field_list = r#"
             (
                 activation_key, deactivation_key, api_key, request_id, session_id, device_date,
                 package_id, asnp_id, device_id, os_user_id, is_vdi, is_domain_user, is_virtual,
-                os_name, os_version, app_id, app_version, ngl_version, timestamp
+                os_name, os_version, app_id, app_version, ngl_version, timestamp, namespace,
+                query, key_extra, client_headers, seq
             )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list = format!(
+        "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, {})",
+        NEXT_SEQ
+    );
     let i_str = format!(
         "insert or replace into activation_requests {} values {}",
         field_list, value_list
     );
-    let a_key = activation_id(req);
+    let a_key = activation_id(ns, req);
     debug!("Storing activation request {} with key: {}", &req.request_id, &a_key);
     let mut tx = pool.begin().await?;
+    sqlx::query(SEQ_INCREMENT).execute(&mut tx).await?;
     let result = sqlx::query(&i_str)
         .bind(&a_key)
-        .bind(deactivation_id(req))
+        .bind(deactivation_id(ns, req))
         .bind(&req.api_key)
         .bind(&req.request_id)
         .bind(&req.session_id)
@@ -243,6 +1221,10 @@ async fn store_activation_request(pool: &SqlitePool, req: &CRequest) -> Result<(
         .bind(&req.app_version)
         .bind(&req.ngl_version)
         .bind(&req.timestamp)
+        .bind(ns)
+        .bind(&req.query)
+        .bind(&req.key_extra)
+        .bind(encode_headers(&req.client_headers))
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -250,21 +1232,24 @@ async fn store_activation_request(pool: &SqlitePool, req: &CRequest) -> Result<(
     Ok(())
 }
 
-async fn store_deactivation_request(pool: &SqlitePool, req: &CRequest) -> Result<()> {
+async fn store_deactivation_request(
+    pool: &SqlitePool, ns: &str, req: &CRequest,
+) -> Result<()> {
     let field_list = r#"
             (
                 deactivation_key, api_key, request_id, package_id,
                 device_id, os_user_id, is_vdi, is_domain_user, is_virtual,
-                timestamp
+                timestamp, namespace, query, client_headers, seq
             )"#;
-    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+    let value_list = format!("(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, {})", NEXT_SEQ);
     let i_str = format!(
         "insert or replace into deactivation_requests {} values {}",
         field_list, value_list
     );
-    let d_key = deactivation_id(req);
+    let d_key = deactivation_id(ns, req);
     debug!("Storing deactivation request {} with key: {}", &req.request_id, &d_key);
     let mut tx = pool.begin().await?;
+    sqlx::query(SEQ_INCREMENT).execute(&mut tx).await?;
     let result = sqlx::query(&i_str)
         .bind(&d_key)
         .bind(&req.api_key)
@@ -276,6 +1261,9 @@ async fn store_deactivation_request(pool: &SqlitePool, req: &CRequest) -> Result
         .bind(req.is_domain_user)
         .bind(req.is_virtual)
         .bind(&req.timestamp)
+        .bind(ns)
+        .bind(&req.query)
+        .bind(encode_headers(&req.client_headers))
         .execute(&mut tx)
         .await?;
     tx.commit().await?;
@@ -284,16 +1272,17 @@ async fn store_deactivation_request(pool: &SqlitePool, req: &CRequest) -> Result
 }
 
 async fn store_activation_response(
-    mode: ProxyMode, pool: &SqlitePool, req: &CRequest, resp: &CResponse,
+    mode: ProxyMode, pool: &SqlitePool, ns: &str, req: &CRequest, resp: &CResponse,
+    checksum: &str,
 ) -> Result<()> {
-    let field_list = "(activation_key, deactivation_key, body, timestamp)";
-    let value_list = "(?, ?, ?, ?)";
+    let field_list = "(activation_key, deactivation_key, body, timestamp, namespace, checksum, etag, last_modified)";
+    let value_list = "(?, ?, ?, ?, ?, ?, ?, ?)";
     let i_str = format!(
         "insert or replace into activation_responses {} values {}",
         field_list, value_list
     );
-    let a_key = activation_id(req);
-    let d_key = deactivation_id(req);
+    let a_key = activation_id(ns, req);
+    let d_key = deactivation_id(ns, req);
     let mut tx = pool.begin().await?;
     debug!("Storing activation response {} with key: {}", &req.request_id, &a_key);
     let result = sqlx::query(&i_str)
@@ -301,6 +1290,10 @@ async fn store_activation_response(
         .bind(&d_key)
         .bind(std::str::from_utf8(&resp.body).unwrap())
         .bind(&req.timestamp)
+        .bind(ns)
+        .bind(checksum)
+        .bind(&resp.etag)
+        .bind(&resp.last_modified)
         .execute(&mut tx)
         .await?;
     debug!("Stored activation response has rowid {}", result.last_insert_rowid());
@@ -308,7 +1301,7 @@ async fn store_activation_response(
         // if we are forwarding, then we just remember the response
     } else {
         // otherwise we remove all stored deactivation requests/responses as they are now invalid
-        let d_key = deactivation_id(req);
+        let d_key = deactivation_id(ns, req);
         debug!("Removing deactivation requests with key: {}", d_key);
         let d_str = "delete from deactivation_requests where deactivation_key = ?";
         sqlx::query(d_str).bind(&d_key).execute(&mut tx).await?;
@@ -321,29 +1314,35 @@ async fn store_activation_response(
 }
 
 async fn store_deactivation_response(
-    mode: ProxyMode, pool: &SqlitePool, req: &CRequest, resp: &CResponse,
+    mode: ProxyMode, pool: &SqlitePool, ns: &str, req: &CRequest, resp: &CResponse,
+    checksum: &str,
 ) -> Result<()> {
     let mut tx = pool.begin().await?;
     if let ProxyMode::Forward = mode {
         // when we are forwarding, we store the response for later processing
-        let field_list = "(deactivation_key, body, timestamp)";
-        let value_list = "(?, ?, ?)";
+        let field_list =
+            "(deactivation_key, body, timestamp, namespace, checksum, etag, last_modified)";
+        let value_list = "(?, ?, ?, ?, ?, ?, ?)";
         let i_str = format!(
             "insert or replace into deactivation_responses {} values {}",
             field_list, value_list
         );
-        let d_key = deactivation_id(req);
+        let d_key = deactivation_id(ns, req);
         debug!("Storing deactivation response {} with key: {}", &req.request_id, &d_key);
         let result = sqlx::query(&i_str)
             .bind(&d_key)
             .bind(std::str::from_utf8(&resp.body).unwrap())
             .bind(&req.timestamp)
+            .bind(ns)
+            .bind(checksum)
+            .bind(&resp.etag)
+            .bind(&resp.last_modified)
             .execute(&mut tx)
             .await?;
         debug!("Stored deactivation response has rowid {}", result.last_insert_rowid());
     } else {
         // when we are live, we remove all activation requests/responses as they are now invalid
-        let d_key = deactivation_id(req);
+        let d_key = deactivation_id(ns, req);
         debug!("Removing activation requests with deactivation key: {}", d_key);
         let d_str = "delete from activation_requests where deactivation_key = ?";
         sqlx::query(d_str).bind(&d_key).execute(&mut tx).await?;
@@ -362,24 +1361,48 @@ async fn store_deactivation_response(
     Ok(())
 }
 
+/// Keep a copy of a response, which is never replaced by later responses.
+async fn store_response_history(
+    pool: &SqlitePool, ns: &str, req: &CRequest, resp: &CResponse,
+) -> Result<()> {
+    let i_str = r#"insert into response_history
+            (kind, response_key, request_id, body, timestamp, namespace)
+            values (?, ?, ?, ?, ?, ?)"#;
+    let key = response_key(ns, req);
+    debug!("Archiving {} response {} with key: {}", req.kind, &req.request_id, &key);
+    sqlx::query(i_str)
+        .bind(req.kind.to_string())
+        .bind(&key)
+        .bind(&req.request_id)
+        .bind(std::str::from_utf8(&resp.body).unwrap())
+        .bind(&req.timestamp)
+        .bind(ns)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 async fn fetch_activation_response(
-    pool: &SqlitePool, req: &CRequest,
-) -> Result<Option<CResponse>> {
-    let a_key = activation_id(req);
-    let q_str =
-        "select body, timestamp from activation_responses where activation_key = ?";
+    pool: &SqlitePool, ns: &str, req: &CRequest,
+) -> Result<Option<(CResponse, String)>> {
+    let a_key = activation_id(ns, req);
+    let q_str = "select body, timestamp, checksum, etag, last_modified from activation_responses where activation_key = ?";
     debug!("Finding activation response with key: {}", &a_key);
     let result = sqlx::query(q_str).bind(&a_key).fetch_optional(pool).await?;
     match result {
         Some(row) => {
             let body: String = row.get("body");
             let timestamp: String = row.get("timestamp");
-            Ok(Some(CResponse {
+            let checksum: String = row.get("checksum");
+            let resp = CResponse {
                 kind: req.kind.clone(),
                 request_id: req.request_id.clone(),
                 timestamp,
                 body: body.into_bytes(),
-            }))
+                etag: row.get("etag"),
+                last_modified: row.get("last_modified"),
+            };
+            Ok(Some((resp, checksum)))
         }
         None => {
             debug!("No activation response found for key: {}", &a_key);
@@ -389,22 +1412,26 @@ async fn fetch_activation_response(
 }
 
 async fn fetch_deactivation_response(
-    pool: &SqlitePool, req: &CRequest,
-) -> Result<Option<CResponse>> {
-    let a_key = activation_id(req);
-    let q_str = "select body from activation_responses where activation_key = ?";
+    pool: &SqlitePool, ns: &str, req: &CRequest,
+) -> Result<Option<(CResponse, String)>> {
+    let a_key = activation_id(ns, req);
+    let q_str = "select body, timestamp, checksum, etag, last_modified from activation_responses where activation_key = ?";
     debug!("Finding deactivation response with key: {}", &a_key);
     let result = sqlx::query(q_str).bind(&a_key).fetch_optional(pool).await?;
     match result {
         Some(row) => {
             let body: String = row.get("body");
             let timestamp: String = row.get("timestamp");
-            Ok(Some(CResponse {
+            let checksum: String = row.get("checksum");
+            let resp = CResponse {
                 kind: req.kind.clone(),
                 request_id: req.request_id.clone(),
                 timestamp,
                 body: body.into_bytes(),
-            }))
+                etag: row.get("etag"),
+                last_modified: row.get("last_modified"),
+            };
+            Ok(Some((resp, checksum)))
         }
         None => {
             debug!("No deactivation response found for key: {}", &a_key);
@@ -413,55 +1440,97 @@ async fn fetch_deactivation_response(
     }
 }
 
-async fn fetch_unanswered_requests(pool: &SqlitePool) -> Result<Vec<CRequest>> {
-    let mut activations = fetch_unanswered_activations(pool).await?;
-    let mut deactivations = fetch_unanswered_deactivations(pool).await?;
+/// Remove the cached response to a request (the one a fetch finds).
+async fn delete_response(pool: &SqlitePool, ns: &str, req: &CRequest) -> Result<()> {
+    let a_key = activation_id(ns, req);
+    debug!("Removing {} response with key: {}", req.kind, &a_key);
+    let d_str = "delete from activation_responses where activation_key = ?";
+    sqlx::query(d_str).bind(&a_key).execute(pool).await?;
+    Ok(())
+}
+
+async fn fetch_unanswered_requests(pool: &SqlitePool, ns: &str) -> Result<Vec<CRequest>> {
+    let mut activations = fetch_unanswered_activations(pool, ns).await?;
+    let mut deactivations = fetch_unanswered_deactivations(pool, ns).await?;
     activations.append(&mut deactivations);
-    activations.sort_unstable_by(|r1, r2| r1.timestamp.cmp(&r2.timestamp));
+    // requests stored in the same millisecond go in the order they were
+    // stored (and, for those without a sequence, in the order fetched)
+    activations.sort_by(|r1, r2| (&r1.timestamp, r1.seq).cmp(&(&r2.timestamp, r2.seq)));
     Ok(activations)
 }
 
-async fn fetch_unanswered_activations(pool: &SqlitePool) -> Result<Vec<CRequest>> {
+async fn count_unforwarded(pool: &SqlitePool, ns: &str) -> Result<u64> {
+    let row = sqlx::query(UNFORWARDED_COUNT).bind(ns).bind(ns).fetch_one(pool).await?;
+    let count: i64 = row.get("count");
+    Ok(count as u64)
+}
+
+/// Remove the oldest un-forwarded request, if there is one.
+async fn evict_oldest_request(pool: &SqlitePool, ns: &str) -> Result<bool> {
+    let row =
+        sqlx::query(OLDEST_UNFORWARDED).bind(ns).bind(ns).fetch_optional(pool).await?;
+    if let Some(row) = row {
+        let table: String = row.get("tbl");
+        let rowid: i64 = row.get("id");
+        let request_id: String = row.get("request_id");
+        info!("Evicting oldest un-forwarded request {} from {}", request_id, table);
+        let q_str = format!("delete from {} where rowid = ?", table);
+        sqlx::query(&q_str).bind(rowid).execute(pool).await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+async fn fetch_unanswered_activations(
+    pool: &SqlitePool, ns: &str,
+) -> Result<Vec<CRequest>> {
     let mut result: Vec<CRequest> = Vec::new();
-    let q_str = r#"select * from activation_requests req where not exists
+    let q_str = r#"select * from activation_requests req where namespace = ? and not exists
                     (select 1 from activation_responses where
                         activation_key = req.activation_key and
                         timestamp >= req.timestamp
-                    )"#;
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+                    ) order by rowid"#;
+    let rows = sqlx::query(q_str).bind(ns).fetch_all(pool).await?;
     for row in rows.iter() {
         result.push(request_from_activation_row(row))
     }
     Ok(result)
 }
 
-async fn fetch_unanswered_deactivations(pool: &SqlitePool) -> Result<Vec<CRequest>> {
+async fn fetch_unanswered_deactivations(
+    pool: &SqlitePool, ns: &str,
+) -> Result<Vec<CRequest>> {
     let mut result: Vec<CRequest> = Vec::new();
-    let q_str = r#"select * from deactivation_requests"#;
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+    let q_str =
+        r#"select * from deactivation_requests where namespace = ? order by rowid"#;
+    let rows = sqlx::query(q_str).bind(ns).fetch_all(pool).await?;
     for row in rows.iter() {
         result.push(request_from_deactivation_row(row))
     }
     Ok(result)
 }
 
-async fn fetch_forwarded_pairs(pool: &SqlitePool) -> Result<Vec<(CRequest, CResponse)>> {
-    let mut activations = fetch_forwarded_activations(pool).await?;
-    let mut deactivations = fetch_forwarded_deactivations(pool).await?;
+async fn fetch_forwarded_pairs(
+    pool: &SqlitePool, ns: &str,
+) -> Result<Vec<(CRequest, CResponse)>> {
+    let mut activations = fetch_forwarded_activations(pool, ns).await?;
+    let mut deactivations = fetch_forwarded_deactivations(pool, ns).await?;
     activations.append(&mut deactivations);
     activations.sort_unstable_by(|r1, r2| r1.0.timestamp.cmp(&r2.0.timestamp));
     Ok(activations)
 }
 
 async fn fetch_forwarded_activations(
-    pool: &SqlitePool,
+    pool: &SqlitePool, ns: &str,
 ) -> Result<Vec<(CRequest, CResponse)>> {
     let mut result: Vec<(CRequest, CResponse)> = Vec::new();
     let q_str = r#"
-        select req.*, resp.body from activation_requests req 
+        select req.*, resp.body, resp.etag, resp.last_modified from activation_requests req 
             inner join activation_responses resp
-            on req.activation_key = resp.activation_key"#;
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+            on req.activation_key = resp.activation_key
+            where req.namespace = ?"#;
+    let rows = sqlx::query(q_str).bind(ns).fetch_all(pool).await?;
     for row in rows.iter() {
         result.push((request_from_activation_row(row), response_from_activation_row(row)))
     }
@@ -469,14 +1538,15 @@ async fn fetch_forwarded_activations(
 }
 
 async fn fetch_forwarded_deactivations(
-    pool: &SqlitePool,
+    pool: &SqlitePool, ns: &str,
 ) -> Result<Vec<(CRequest, CResponse)>> {
     let mut result: Vec<(CRequest, CResponse)> = Vec::new();
     let q_str = r#"
-        select req.*, resp.body from deactivation_requests req 
+        select req.*, resp.body, resp.etag, resp.last_modified from deactivation_requests req 
             inner join deactivation_responses resp
-            on req.deactivation_key = resp.deactivation_key"#;
-    let rows = sqlx::query(q_str).fetch_all(pool).await?;
+            on req.deactivation_key = resp.deactivation_key
+            where req.namespace = ?"#;
+    let rows = sqlx::query(q_str).bind(ns).fetch_all(pool).await?;
     for row in rows.iter() {
         result.push((
             request_from_deactivation_row(row),
@@ -486,13 +1556,129 @@ async fn fetch_forwarded_deactivations(
     Ok(result)
 }
 
-fn activation_id(req: &CRequest) -> String {
-    let factors: Vec<String> =
-        vec![req.app_id.clone(), req.ngl_version.clone(), deactivation_id(req)];
-    factors.join("|")
+/// Purge responses older than the given ages (activation, then
+/// deactivation), where an age of 0 keeps them forever, except those
+/// with pinned keys.
+async fn purge_expired_responses(
+    pool: &SqlitePool, ns: &str, ttls: [u64; 2], pinned_keys: &[String],
+) -> Result<u64> {
+    let mut count = 0u64;
+    let mut tx = pool.begin().await?;
+    for (table, key, ttl) in [
+        ("activation_responses", "activation_key", ttls[0]),
+        ("deactivation_responses", "deactivation_key", ttls[1]),
+    ]
+    .iter()
+    {
+        if *ttl == 0 {
+            continue;
+        }
+        let q_str =
+            format!("select {}, timestamp from {} where namespace = ?", key, table);
+        let rows = sqlx::query(&q_str).bind(ns).fetch_all(&mut tx).await?;
+        let d_str = format!("delete from {} where {} = ?", table, key);
+        for row in rows.iter() {
+            let timestamp: String = row.get("timestamp");
+            let k: String = row.get(*key);
+            if is_expired(&timestamp, *ttl) && !pinned_keys.contains(&k) {
+                debug!("Purging expired response from {} with key: {}", table, &k);
+                sqlx::query(&d_str).bind(&k).execute(&mut tx).await?;
+                count += 1;
+            }
+        }
+    }
+    tx.commit().await?;
+    Ok(count)
+}
+
+async fn purge_over_age_requests(
+    pool: &SqlitePool, ns: &str, max_age: u64,
+) -> Result<u64> {
+    let requests = fetch_unanswered_requests(pool, ns).await?;
+    let mut count = 0u64;
+    let mut tx = pool.begin().await?;
+    for req in requests.iter() {
+        if !is_older_than(&req.timestamp, max_age) {
+            continue;
+        }
+        debug!("Purging over-age {} request {}", req.kind, req.request_id);
+        match req.kind {
+            Kind::Activation => {
+                let d_str = "delete from activation_requests where activation_key = ?";
+                sqlx::query(d_str).bind(activation_id(ns, req)).execute(&mut tx).await?;
+            }
+            Kind::Deactivation => {
+                let d_str =
+                    "delete from deactivation_requests where deactivation_key = ?";
+                sqlx::query(d_str)
+                    .bind(deactivation_id(ns, req))
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+        count += 1;
+    }
+    tx.commit().await?;
+    Ok(count)
+}
+
+/// Remove responses with no stored request (activation, then
+/// deactivation), except those with pinned keys.
+async fn remove_orphaned_responses(
+    pool: &SqlitePool, ns: &str, pinned_keys: &[String],
+) -> Result<[u64; 2]> {
+    let mut counts = [0u64; 2];
+    for (count, (table, requests, key)) in counts.iter_mut().zip(
+        [
+            ("activation_responses", "activation_requests", "activation_key"),
+            ("deactivation_responses", "deactivation_requests", "deactivation_key"),
+        ]
+        .iter(),
+    ) {
+        let mut tx = pool.begin().await?;
+        let q_str = format!(
+            "select {key} from {table} resp where namespace = ? and not exists
+                (select 1 from {requests} where {key} = resp.{key})",
+            key = key,
+            table = table,
+            requests = requests
+        );
+        let rows = sqlx::query(&q_str).bind(ns).fetch_all(&mut tx).await?;
+        let d_str = format!("delete from {} where {} = ?", table, key);
+        for row in rows.iter() {
+            let k: String = row.get(*key);
+            if !pinned_keys.contains(&k) {
+                debug!("Removing orphaned response from {} with key: {}", table, &k);
+                sqlx::query(&d_str).bind(&k).execute(&mut tx).await?;
+                *count += 1;
+            }
+        }
+        tx.commit().await?;
+    }
+    Ok(counts)
 }
 
-fn deactivation_id(req: &CRequest) -> String {
+fn response_key(ns: &str, req: &CRequest) -> String {
+    match req.kind {
+        Kind::Activation => activation_id(ns, req),
+        Kind::Deactivation => deactivation_id(ns, req),
+    }
+}
+
+fn activation_id(ns: &str, req: &CRequest) -> String {
+    let factors: Vec<String> = if req.key_extra.is_empty() {
+        vec![req.app_id.clone(), req.ngl_version.clone(), deactivation_factors(req)]
+    } else {
+        vec![req.key_extra.clone(), deactivation_factors(req)]
+    };
+    namespaced(ns, factors.join("|"))
+}
+
+fn deactivation_id(ns: &str, req: &CRequest) -> String {
+    namespaced(ns, deactivation_factors(req))
+}
+
+fn deactivation_factors(req: &CRequest) -> String {
     let factors: Vec<&str> = vec![
         req.package_id.as_str(),
         if req.is_vdi { req.os_user_id.as_str() } else { req.device_id.as_str() },
@@ -500,6 +1686,16 @@ fn deactivation_id(req: &CRequest) -> String {
     factors.join("|")
 }
 
+/// Keys in the default (empty) namespace are unprefixed, so caches
+/// created before namespaces were introduced keep working.
+fn namespaced(ns: &str, key: String) -> String {
+    if ns.is_empty() {
+        key
+    } else {
+        format!("{}/{}", ns, key)
+    }
+}
+
 fn request_from_activation_row(row: &SqliteRow) -> CRequest {
     CRequest {
         kind: Kind::Activation,
@@ -520,6 +1716,16 @@ fn request_from_activation_row(row: &SqliteRow) -> CRequest {
         app_version: row.get("app_version"),
         ngl_version: row.get("ngl_version"),
         timestamp: row.get("timestamp"),
+        attempts: row.get("attempts"),
+        next_retry: row.get("next_retry"),
+        query: row.get("query"),
+        key_extra: row.get("key_extra"),
+        client_headers: decode_headers(row.get("client_headers")),
+        seq: row.get("seq"),
+        timeout_ms: None,
+        prefer_cache: false,
+        last_error: row.get("last_error"),
+        last_attempt_at: row.get("last_attempt_at"),
     }
 }
 
@@ -535,10 +1741,29 @@ fn request_from_deactivation_row(row: &SqliteRow) -> CRequest {
         os_user_id: row.get("os_user_id"),
         is_domain_user: row.get("is_domain_user"),
         timestamp: row.get("timestamp"),
+        attempts: row.get("attempts"),
+        next_retry: row.get("next_retry"),
+        query: row.get("query"),
+        client_headers: decode_headers(row.get("client_headers")),
+        seq: row.get("seq"),
+        last_error: row.get("last_error"),
+        last_attempt_at: row.get("last_attempt_at"),
         ..Default::default()
     }
 }
 
+fn encode_headers(headers: &[(String, String)]) -> String {
+    if headers.is_empty() {
+        String::new()
+    } else {
+        serde_json::to_string(headers).unwrap_or_default()
+    }
+}
+
+fn decode_headers(encoded: String) -> Vec<(String, String)> {
+    serde_json::from_str(&encoded).unwrap_or_default()
+}
+
 fn response_from_activation_row(row: &SqliteRow) -> CResponse {
     let body: String = row.get("body");
     CResponse {
@@ -546,6 +1771,8 @@ fn response_from_activation_row(row: &SqliteRow) -> CResponse {
         request_id: row.get("request_id"),
         body: body.into_bytes(),
         timestamp: row.get("timestamp"),
+        etag: row.get("etag"),
+        last_modified: row.get("last_modified"),
     }
 }
 
@@ -556,6 +1783,8 @@ fn response_from_deactivation_row(row: &SqliteRow) -> CResponse {
         request_id: row.get("request_id"),
         body: body.into_bytes(),
         timestamp: row.get("timestamp"),
+        etag: row.get("etag"),
+        last_modified: row.get("last_modified"),
     }
 }
 
@@ -617,9 +1846,240 @@ const DEACTIVATION_RESPONSE_SCHEMA: &str = r#"
         timestamp string not null
     );"#;
 
+const RESPONSE_HISTORY_SCHEMA: &str = r#"
+    create table if not exists response_history (
+        kind text not null,
+        response_key text not null,
+        request_id text not null,
+        body text not null,
+        timestamp string not null,
+        namespace text not null default ''
+    );"#;
+
+const TABLES: [&str; 5] = [
+    "activation_requests",
+    "activation_responses",
+    "deactivation_requests",
+    "deactivation_responses",
+    "response_history",
+];
+
+const NAMESPACE_COUNTS: &str = r#"
+    select namespace, count(*) as entries from (
+        select namespace from activation_requests
+        union all select namespace from activation_responses
+        union all select namespace from deactivation_requests
+        union all select namespace from deactivation_responses
+        union all select namespace from response_history
+    ) group by namespace order by namespace"#;
+
+const CACHE_STATS: &str = r#"
+    select
+        coalesce(sum(case when tbl = 'req' then 1 else 0 end), 0) as requests,
+        coalesce(sum(case when tbl = 'resp' then 1 else 0 end), 0) as responses,
+        min(timestamp) as oldest,
+        max(timestamp) as newest
+    from (
+        select 'req' as tbl, timestamp from activation_requests where namespace = ?
+        union all select 'req', timestamp from deactivation_requests where namespace = ?
+        union all select 'resp', timestamp from activation_responses where namespace = ?
+        union all select 'resp', timestamp from deactivation_responses where namespace = ?
+    )"#;
+
+const UNFORWARDED_COUNT: &str = r#"
+    select
+        (select count(*) from activation_requests req where namespace = ? and not exists
+            (select 1 from activation_responses where
+                activation_key = req.activation_key and
+                timestamp >= req.timestamp
+            )
+        ) +
+        (select count(*) from deactivation_requests where namespace = ?)
+    as count"#;
+
+const OLDEST_UNFORWARDED: &str = r#"
+    select 'activation_requests' as tbl, rowid as id, request_id, timestamp
+        from activation_requests req where namespace = ? and not exists
+            (select 1 from activation_responses where
+                activation_key = req.activation_key and
+                timestamp >= req.timestamp
+            )
+    union all
+    select 'deactivation_requests', rowid, request_id, timestamp
+        from deactivation_requests where namespace = ?
+    order by timestamp limit 1"#;
+
+const DIFF_REQUESTS: &str = r#"
+    select 'activation' as kind, activation_key as key, request_id
+        from activation_requests
+    union all
+    select 'deactivation', deactivation_key, request_id
+        from deactivation_requests"#;
+
+const DIFF_RESPONSES: &str = r#"
+    select 'activation' as kind, activation_key as key, body
+        from activation_responses
+    union all
+    select 'deactivation', deactivation_key, body
+        from deactivation_responses"#;
+
+/// The sequence of stored requests, shared by both request tables:
+/// a single row holding the sequence number last given out.
+const SEQ_SCHEMA: &str = r#"
+    create table if not exists request_seq (
+        id integer primary key check (id = 1),
+        value integer not null
+    )"#;
+
+const SEQ_SEED: &str = r#"
+    insert or ignore into request_seq (id, value)
+        select 1, coalesce(max(seq), 0) from (
+            select seq from activation_requests
+            union all
+            select seq from deactivation_requests
+        )"#;
+
+/// Run in the transaction storing a request, before it's inserted
+/// with `NEXT_SEQ`.
+const SEQ_INCREMENT: &str = "update request_seq set value = value + 1";
+
+const NEXT_SEQ: &str = "(select value from request_seq)";
+
+/// Columns added after the original schema: (table, column, definition).
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("activation_requests", "namespace", "text not null default ''"),
+    ("activation_responses", "namespace", "text not null default ''"),
+    ("deactivation_requests", "namespace", "text not null default ''"),
+    ("deactivation_responses", "namespace", "text not null default ''"),
+    ("activation_requests", "attempts", "integer not null default 0"),
+    ("activation_requests", "next_retry", "text not null default ''"),
+    ("deactivation_requests", "attempts", "integer not null default 0"),
+    ("deactivation_requests", "next_retry", "text not null default ''"),
+    ("activation_requests", "query", "text not null default ''"),
+    ("deactivation_requests", "query", "text not null default ''"),
+    ("activation_requests", "key_extra", "text not null default ''"),
+    ("activation_requests", "client_headers", "text not null default ''"),
+    ("deactivation_requests", "client_headers", "text not null default ''"),
+    ("activation_responses", "checksum", "text not null default ''"),
+    ("deactivation_responses", "checksum", "text not null default ''"),
+    ("activation_requests", "seq", "integer not null default 0"),
+    ("deactivation_requests", "seq", "integer not null default 0"),
+    ("activation_requests", "last_error", "text not null default ''"),
+    ("activation_requests", "last_attempt_at", "text not null default ''"),
+    ("deactivation_requests", "last_error", "text not null default ''"),
+    ("deactivation_requests", "last_attempt_at", "text not null default ''"),
+    ("activation_responses", "etag", "text not null default ''"),
+    ("activation_responses", "last_modified", "text not null default ''"),
+    ("deactivation_responses", "etag", "text not null default ''"),
+    ("deactivation_responses", "last_modified", "text not null default ''"),
+];
+
+const READ_ONLY_CHECK: &str = "select count(*) from activation_requests";
+
+const ROUND_TRIP_SCHEMA: &str = "create table self_test (value text not null)";
+
+const ROUND_TRIP_WRITE: &str = "insert into self_test (value) values (?)";
+
+const ROUND_TRIP_READ: &str = "select value from self_test";
+
 const CLEAR_ALL: &str = r#"
+    delete from response_history;
     delete from deactivation_responses;
     delete from deactivation_requests;
     delete from activation_responses;
     delete from activation_requests;
     "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings for a cache db of a test's own, with no db there yet.
+    fn temp_conf(name: &str) -> Settings {
+        let file = format!("frl-proxy-{}-{}.sqlite", name, std::process::id());
+        let path = env::temp_dir().join(file).to_string_lossy().into_owned();
+        for suffix in ["", "-shm", "-wal"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.cache.db_path = path;
+        conf
+    }
+
+    fn request(kind: Kind, id: &str, timestamp: &str) -> CRequest {
+        CRequest {
+            kind,
+            request_id: id.to_string(),
+            api_key: "key".to_string(),
+            session_id: "session".to_string(),
+            package_id: "npd1".to_string(),
+            device_id: format!("device-{}", id),
+            os_user_id: "user1".to_string(),
+            app_id: "app1".to_string(),
+            ngl_version: "1.0".to_string(),
+            timestamp: timestamp.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(requests: &[CRequest]) -> Vec<&str> {
+        requests.iter().map(|r| r.request_id.as_str()).collect()
+    }
+
+    // the cache uses block_in_place, which needs the multi-threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn same_millisecond_requests_keep_their_stored_order() {
+        let cache = Cache::from(&temp_conf("forward-order"), true).await.unwrap();
+        let same = "2021-01-01T00:00:00.000+0000";
+        let earlier = "2020-12-31T23:59:59.999+0000";
+        let stored = [
+            request(Kind::Activation, "a1", same),
+            request(Kind::Deactivation, "d1", same),
+            request(Kind::Activation, "a2", same),
+            request(Kind::Activation, "early", earlier),
+            request(Kind::Deactivation, "d2", same),
+            request(Kind::Activation, "a3", same),
+        ];
+        for req in stored.iter() {
+            assert!(cache.store_request(req).await.unwrap());
+        }
+        let fetched = cache.fetch_forwarding_requests(false).await;
+        assert_eq!(ids(&fetched), ["early", "a1", "d1", "a2", "d2", "a3"]);
+        // requests stored before there was a sequence have seq 0, so go
+        // before those that have one (and stay in the order fetched)
+        let pool = cache.db_pool.as_ref().unwrap();
+        for (table, id) in
+            [("activation_requests", "a3"), ("deactivation_requests", "d2")]
+        {
+            let u_str = format!("update {} set seq = 0 where request_id = ?", table);
+            sqlx::query(&u_str).bind(id).execute(pool).await.unwrap();
+        }
+        let fetched = cache.fetch_forwarding_requests(false).await;
+        assert_eq!(ids(&fetched), ["early", "a3", "d2", "a1", "d1", "a2"]);
+        for _ in 0..3 {
+            let again = cache.fetch_forwarding_requests(false).await;
+            assert_eq!(ids(&again), ids(&fetched));
+        }
+        cache.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sequence_continues_after_the_stored_requests() {
+        let conf = temp_conf("seq-seed");
+        let same = "2021-01-01T00:00:00.000+0000";
+        let cache = Cache::from(&conf, true).await.unwrap();
+        cache.store_request(&request(Kind::Activation, "a1", same)).await.unwrap();
+        cache.store_request(&request(Kind::Deactivation, "d1", same)).await.unwrap();
+        // a db from before the counter was kept has only the requests
+        let pool = cache.db_pool.as_ref().unwrap();
+        sqlx::query("drop table request_seq").execute(pool).await.unwrap();
+        cache.close().await;
+        let cache = Cache::from(&conf, true).await.unwrap();
+        cache.store_request(&request(Kind::Activation, "a2", same)).await.unwrap();
+        let fetched = cache.fetch_forwarding_requests(false).await;
+        assert_eq!(ids(&fetched), ["a1", "d1", "a2"]);
+        let seqs: Vec<i64> = fetched.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, [1, 2, 3]);
+        cache.close().await;
+    }
+}
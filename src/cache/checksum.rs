@@ -0,0 +1,50 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Checksums of cached responses.
+//!
+//! A checksum is stored as the name of its algorithm and the hex digest,
+//! e.g. `crc32:cbf43926`, so it can be verified whatever
+//! `cache.checksum` is set to when it's read.  Responses cached before
+//! checksums were introduced (or with `cache.checksum = "none"`) have an
+//! empty checksum, and can't be verified.
+use crate::settings::Checksum;
+use sha1::{Digest, Sha1};
+
+/// The checksum to store with a response body (empty for none).
+pub fn compute(algorithm: &Checksum, body: &[u8]) -> String {
+    match algorithm {
+        Checksum::None => String::new(),
+        Checksum::Crc32 => format!("crc32:{:08x}", crc32(body)),
+        Checksum::Sha1 => format!("sha1:{:x}", Sha1::digest(body)),
+    }
+}
+
+/// Whether a response body matches its stored checksum, or `None` if the
+/// checksum can't be verified.
+pub fn verify(checksum: &str, body: &[u8]) -> Option<bool> {
+    let algorithm = match checksum.split(':').next() {
+        Some("crc32") => Checksum::Crc32,
+        Some("sha1") => Checksum::Sha1,
+        _ => return None,
+    };
+    Some(compute(&algorithm, body) == checksum)
+}
+
+/// The CRC-32 (IEEE) of some bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
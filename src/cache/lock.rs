@@ -0,0 +1,118 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use eyre::{eyre, Result, WrapErr};
+use log::{info, warn};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// A lock on a cache db, held by a proxy while it runs.
+///
+/// The lock is an OS advisory lock (`flock` or `LockFileEx`) on a file
+/// next to the db, with `.lock` appended to its name.  The OS releases
+/// it when the process exits, however it exits, so a lock is never
+/// left behind.  The file holds the id of the process with the lock,
+/// but only so it can be named when another process has to wait: the
+/// file itself stays, since removing it would let a process waiting on
+/// the removed file and one creating a new file both take the lock.
+#[derive(Debug)]
+pub struct CacheLock {
+    path: String,
+    file: File,
+}
+
+impl CacheLock {
+    /// Lock the given cache db, waiting up to `timeout` for another
+    /// process to let go of it.
+    pub async fn acquire(db_path: &str, timeout: Duration) -> Result<CacheLock> {
+        let path = format!("{}.lock", db_path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err(format!("Can't open cache lock file: {}", path))?;
+        let started = Instant::now();
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => {
+                    file.set_len(0)
+                        .and_then(|_| writeln!(file, "{}", std::process::id()))
+                        .wrap_err(format!("Can't write cache lock file: {}", path))?;
+                    return Ok(CacheLock { path, file });
+                }
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(err)) => {
+                    return Err(err)
+                        .wrap_err(format!("Can't lock cache lock file: {}", path))
+                }
+            }
+            if started.elapsed() >= timeout {
+                let holder = match fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<u32>().ok())
+                {
+                    Some(pid) => format!("process {}", pid),
+                    None => "another process".to_string(),
+                };
+                return Err(eyre!("Cache {} is already in use by {}", db_path, holder));
+            }
+            if !waiting {
+                info!(
+                    "Waiting up to {:?} for another process to release {}",
+                    timeout, path
+                );
+                waiting = true;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        // the lock itself is released when the file is closed
+        if let Err(err) = self.file.set_len(0) {
+            warn!("Can't clear cache lock file {}: {}", self.path, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> String {
+        let file = format!("frl-proxy-{}-{}.sqlite", name, std::process::id());
+        std::env::temp_dir().join(file).to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn lock_left_by_a_dead_process_is_taken() {
+        let db = temp_db("lock-stale");
+        // pid 1 is always running, and is us in a container
+        fs::write(format!("{}.lock", db), "1\n").unwrap();
+        let lock = CacheLock::acquire(&db, Duration::from_secs(0)).await.unwrap();
+        let pid = fs::read_to_string(&lock.path).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+    }
+
+    #[tokio::test]
+    async fn held_lock_is_waited_for() {
+        let db = temp_db("lock-held");
+        let lock = CacheLock::acquire(&db, Duration::from_secs(0)).await.unwrap();
+        let err = CacheLock::acquire(&db, Duration::from_millis(300)).await.unwrap_err();
+        let expected = format!("in use by process {}", std::process::id());
+        assert!(err.to_string().contains(&expected), "{}", err);
+        drop(lock);
+        CacheLock::acquire(&db, Duration::from_secs(0)).await.unwrap();
+    }
+}
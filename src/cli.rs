@@ -11,9 +11,11 @@ use structopt::StructOpt;
 #[derive(Debug, StructOpt)]
 #[structopt(about = "A caching, store/forward, reverse proxy for Adobe FRL licensing")]
 pub struct FrlProxy {
-    #[structopt(short, long, default_value = "proxy-conf.toml")]
-    /// Path to config file.
-    pub config_file: String,
+    #[structopt(short, long, default_value = "proxy-conf.toml", number_of_values = 1)]
+    /// Path to config file.  Give this more than once to layer config
+    /// files: settings in later files override those in earlier ones,
+    /// field by field, and environment variables override them all.
+    pub config_file: Vec<String>,
 
     #[structopt(short, parse(from_occurrences))]
     /// Specify once to force log level to debug.
@@ -21,8 +23,8 @@ pub struct FrlProxy {
     pub debug: u8,
 
     #[structopt(short, long)]
-    /// Override configured log destination: 'console' or 'file'.
-    /// You can use just the first letter, so '-l c' and '-l f' work.
+    /// Override configured log destination: 'console', 'file' or 'syslog'.
+    /// You can use just the first letter, so '-l c', '-l f' and '-l s' work.
     pub log_to: Option<String>,
 
     #[structopt(subcommand)]
@@ -35,24 +37,117 @@ pub enum Command {
     /// Start the proxy server
     Start {
         #[structopt(short, long)]
-        /// Mode to run the proxy in, one of passthrough, cache, store, or forward.
-        /// You can use any prefix of these names (minimally p, c, s, or f)
+        /// Mode to run the proxy in, one of passthrough, cache, store, forward, or auto.
+        /// You can use any prefix of these names (minimally p, c, s, f, or a)
         mode: Option<String>,
 
         #[structopt(long, parse(try_from_str))]
         /// Enable SSL? (true or false)
         ssl: Option<bool>,
+
+        #[structopt(long)]
+        /// In forward mode, space out requests as they were originally received
+        preserve_timing: bool,
+
+        #[structopt(long)]
+        /// Multiply preserved gaps between requests by this factor
+        /// (so 0.5 replays twice as fast)
+        timing_scale: Option<f64>,
+
+        #[structopt(long)]
+        /// Check the cache and COPS before starting, and exit with an error
+        /// if a required check fails
+        self_test: bool,
     },
     /// Interactively create the config file
     Configure,
+    /// Check a config file for problems, without starting the proxy
+    CheckConfig {
+        /// The config file to check (defaults to the --config-file list)
+        path: Option<String>,
+    },
     /// Clear the cache (requires confirmation)
     Clear {
         #[structopt(short, long)]
         /// Bypass confirmation prompt
         yes: bool,
     },
+    /// Forward all stored requests, then exit (with an error if any fail)
+    Drain,
+    /// Remove expired responses and over-age requests from the cache
+    Purge,
+    /// Remove cached responses which have no stored request (other than
+    /// pinned ones)
+    Gc,
+    /// Show a summary of the cache contents
+    Stats {
+        #[structopt(long)]
+        /// Print the summary as JSON
+        json: bool,
+    },
+    /// List or clear the namespaces in the cache
+    Namespace {
+        #[structopt(subcommand)]
+        cmd: NamespaceCommand,
+    },
+    /// List or requeue requests set aside after too many failed forwards
+    Deadletter {
+        #[structopt(subcommand)]
+        cmd: DeadletterCommand,
+    },
+    /// List the stored requests which haven't been forwarded, with why
+    /// the last call to COPS for each failed
+    List,
+    /// List the cached responses which are pinned, so never expire
+    Pinned,
+    /// Resolve and connect to COPS, reporting the address, TLS details,
+    /// latency, and any error
+    TestCops,
     /// Import stored responses from a forwarder
     Import { import_path: String },
     /// Export stored requests for a forwarder
     Export { export_path: String },
+    /// Compare two cache dbs (e.g. exports), without changing either
+    Diff {
+        /// The first cache db
+        path_a: String,
+
+        /// The second cache db
+        path_b: String,
+
+        #[structopt(long)]
+        /// List each differing entry, not just the counts
+        details: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DeadletterCommand {
+    /// List the dead-lettered requests
+    List,
+    /// Put dead-lettered requests back in line for forwarding
+    Requeue {
+        #[structopt(required_unless = "all")]
+        /// The request id of the request to requeue
+        id: Option<String>,
+
+        #[structopt(long, conflicts_with = "id")]
+        /// Requeue all dead-lettered requests
+        all: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum NamespaceCommand {
+    /// List the namespaces in the cache, with their entry counts
+    List,
+    /// Clear all entries in one namespace (requires confirmation)
+    Clear {
+        /// The namespace to clear (use "" for the default namespace)
+        name: String,
+
+        #[structopt(short, long)]
+        /// Bypass confirmation prompt
+        yes: bool,
+    },
 }
@@ -7,9 +7,16 @@ accordance with the terms of the Adobe license agreement accompanying
 it.
 */
 use chrono::{DateTime, Local};
-use hyper::{http::request::Parts, Body, Method, Response as HResponse};
+use hyper::header::{
+    HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use hyper::{http::request::Parts, Body, HeaderMap, Method, Response as HResponse};
+use log::warn;
+use once_cell::sync::Lazy;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
 use url::Url;
 
 #[derive(Default, Debug, Clone)]
@@ -40,6 +47,32 @@ pub struct Request {
     pub app_version: String,
     pub ngl_version: String,
     pub timestamp: String,
+    /// How many attempts to forward this request have failed.
+    pub attempts: i64,
+    /// When this request may next be forwarded (empty if it may be now).
+    pub next_retry: String,
+    /// The query string exactly as received, so it can be forwarded unchanged.
+    pub query: String,
+    /// Configured cache key factors which replace the app id and NGL version
+    /// in the activation key (empty for the default key).
+    pub key_extra: String,
+    /// Client headers passed on to COPS, as configured.
+    pub client_headers: Vec<(String, String)>,
+    /// The order in which this request was stored, among all stored
+    /// requests (0 if it was stored before this was recorded).
+    pub seq: i64,
+    /// The COPS timeout the client asked for, in milliseconds, if it
+    /// asked for one (this isn't stored).
+    pub timeout_ms: Option<u64>,
+    /// Whether the client asked to be answered from the cache, when it
+    /// can be, without calling COPS (this isn't stored).
+    pub prefer_cache: bool,
+    /// Why the last call to COPS for this request failed (empty if none
+    /// has): the status COPS responded with, or the network error.
+    pub last_error: String,
+    /// When COPS was last called for this request and failed (empty if
+    /// it never has).
+    pub last_attempt_at: String,
 }
 
 impl Request {
@@ -69,16 +102,29 @@ impl Request {
 
     /// Create a network request which submits this COPS request to the given server.
     pub fn to_network(&self, scheme: &str, host: &str) -> hyper::Request<Body> {
-        match self.kind {
+        let mut request = match self.kind {
             Kind::Activation => self.to_activation(scheme, host),
             Kind::Deactivation => self.to_deactivation(scheme, host),
+        };
+        // the headers we set ourselves take precedence over the client's
+        let headers = request.headers_mut();
+        for (name, value) in self.client_headers.iter() {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value))
+            {
+                if !headers.contains_key(&name) {
+                    headers.append(name, value);
+                }
+            }
         }
+        request
     }
 
     fn from_activation(parts: &Parts, body: &[u8]) -> Result<Request, BadRequest> {
         let mut req = Request {
             kind: Kind::Activation,
             timestamp: current_timestamp(),
+            query: parts.uri.query().unwrap_or_default().to_string(),
             ..Default::default()
         };
         req.update_from_headers(parts)?;
@@ -97,7 +143,7 @@ impl Request {
         } else {
             return Err(BadRequest::from("Missing asnpTemplateId field in request."));
         }
-        if map.get("appDetails").is_none() {
+        if !map.contains_key("appDetails") {
             return Err(BadRequest::from("Missing appDetails object in request."));
         }
         let app_map: HashMap<String, Value> =
@@ -117,7 +163,7 @@ impl Request {
         } else {
             return Err(BadRequest::from("Missing nglLibVersion field in request."));
         }
-        if map.get("deviceDetails").is_none() {
+        if !map.contains_key("deviceDetails") {
             return Err(BadRequest::from("Missing deviceDetails object in request."));
         }
         let device_map: HashMap<String, Value> =
@@ -169,6 +215,7 @@ impl Request {
         let mut req = Request {
             kind: Kind::Deactivation,
             timestamp: current_timestamp(),
+            query: parts.uri.query().unwrap_or_default().to_string(),
             ..Default::default()
         };
         req.update_from_headers(parts)?;
@@ -222,9 +269,14 @@ impl Request {
                 "osVersion" : &self.os_version
             }
         });
+        let uri = if self.query.is_empty() {
+            format!("{}://{}{}", scheme, host, ACTIVATION_ENDPOINT)
+        } else {
+            format!("{}://{}{}?{}", scheme, host, ACTIVATION_ENDPOINT, self.query)
+        };
         let builder = hyper::Request::builder()
             .method("POST")
-            .uri(format!("{}://{}{}", scheme, host, ACTIVATION_ENDPOINT).as_str())
+            .uri(uri.as_str())
             .header("host", host)
             .header("x-api-key", &self.api_key)
             .header("x-session-id", &self.session_id)
@@ -239,16 +291,23 @@ impl Request {
 
     /// Convert a COPS deactivation request to its network form.
     fn to_deactivation(&self, scheme: &str, host: &str) -> hyper::Request<Body> {
-        let uri = format!(
-            "{}://{}{}?npdId={}&deviceId={}&osUserId={}&enableVdiMarkerExists={}",
-            scheme,
-            host,
-            DEACTIVATION_ENDPOINT,
-            &self.package_id,
-            &self.device_id,
-            &self.os_user_id,
-            self.is_vdi,
-        );
+        // send the query we received, if we have it, so its encoding and
+        // parameter order are untouched.  (Requests stored by older
+        // versions of the proxy don't have it.)
+        let uri = if self.query.is_empty() {
+            format!(
+                "{}://{}{}?npdId={}&deviceId={}&osUserId={}&enableVdiMarkerExists={}",
+                scheme,
+                host,
+                DEACTIVATION_ENDPOINT,
+                &self.package_id,
+                &self.device_id,
+                &self.os_user_id,
+                self.is_vdi,
+            )
+        } else {
+            format!("{}://{}{}?{}", scheme, host, DEACTIVATION_ENDPOINT, self.query)
+        };
         let builder = hyper::Request::builder()
             .method("DELETE")
             .uri(uri)
@@ -296,6 +355,10 @@ pub struct Response {
     pub request_id: String,
     pub body: Vec<u8>,
     pub timestamp: String,
+    /// The validators COPS sent with the response (empty if it sent
+    /// none), which let a conditional request revalidate it.
+    pub etag: String,
+    pub last_modified: String,
 }
 
 impl Response {
@@ -305,6 +368,38 @@ impl Response {
             request_id: request.request_id.clone(),
             body: Vec::from(body),
             timestamp: current_timestamp(),
+            etag: String::new(),
+            last_modified: String::new(),
+        }
+    }
+
+    /// Keep the validators found in the headers of the COPS response.
+    pub fn with_validators(mut self, headers: &HeaderMap) -> Response {
+        let value = |name| {
+            let value = headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok());
+            value.unwrap_or_default().to_string()
+        };
+        self.etag = value(ETAG);
+        self.last_modified = value(LAST_MODIFIED);
+        self
+    }
+
+    /// Whether COPS sent validators with the response.
+    pub fn has_validators(&self) -> bool {
+        !self.etag.is_empty() || !self.last_modified.is_empty()
+    }
+
+    /// Make a request to COPS conditional on this response having changed.
+    pub fn add_conditions(&self, headers: &mut HeaderMap) {
+        let conditions =
+            [(IF_NONE_MATCH, &self.etag), (IF_MODIFIED_SINCE, &self.last_modified)];
+        for (name, value) in conditions.iter() {
+            if value.is_empty() {
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
         }
     }
 
@@ -319,11 +414,26 @@ impl Response {
     }
 }
 
+/// Whether a path is one of the COPS endpoints the proxy serves.
+pub fn is_endpoint(path: &str) -> bool {
+    endpoint_method(path).is_some()
+}
+
+/// The method COPS accepts at one of its endpoints.
+pub fn endpoint_method(path: &str) -> Option<Method> {
+    match path {
+        ACTIVATION_ENDPOINT => Some(Method::POST),
+        DEACTIVATION_ENDPOINT => Some(Method::DELETE),
+        _ => None,
+    }
+}
+
 const ACTIVATION_ENDPOINT: &str = "/asnp/frl_connected/values/v2";
 const DEACTIVATION_ENDPOINT: &str = "/asnp/frl_connected/v1";
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Kind {
+    #[default]
     Activation,
     Deactivation,
 }
@@ -337,12 +447,6 @@ impl std::fmt::Display for Kind {
     }
 }
 
-impl Default for Kind {
-    fn default() -> Self {
-        Kind::Activation
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct BadRequest {
     pub reason: String,
@@ -363,7 +467,156 @@ pub fn agent() -> String {
     )
 }
 
+/// The proxy's view of the time, which never goes backwards.
+///
+/// It's the wall clock time, except when the wall clock has been set
+/// back (by an NTP step, say, or a VM migration): then it's the last
+/// time seen, moved on by the monotonic clock, until the wall clock
+/// catches up.  So stepping the clock back doesn't make expired
+/// responses fresh again, or entries be stored out of order.
+struct Clock {
+    last: Option<(DateTime<Local>, Instant)>,
+    skew_secs: i64,
+    // whether the wall clock is behind by more than the skew
+    stepped_back: bool,
+}
+
+static CLOCK: Lazy<Mutex<Clock>> =
+    Lazy::new(|| Mutex::new(Clock { last: None, skew_secs: 60, stepped_back: false }));
+
+/// Set the `cache.clock_skew_secs` the clock tolerates.
+pub fn set_clock_skew(secs: u64) {
+    CLOCK.lock().unwrap().skew_secs = secs as i64;
+}
+
+fn now() -> DateTime<Local> {
+    let wall = Local::now();
+    let mut clock = CLOCK.lock().unwrap();
+    let now = match clock.last {
+        Some((then, at)) => {
+            let elapsed = chrono::Duration::from_std(at.elapsed())
+                .unwrap_or_else(|_| chrono::Duration::zero());
+            let expected = then + elapsed;
+            let behind = expected.signed_duration_since(wall).num_seconds();
+            if behind > clock.skew_secs && !clock.stepped_back {
+                warn!(
+                    "The system clock went back {} seconds: using the monotonic clock until it catches up",
+                    behind
+                );
+            }
+            clock.stepped_back = behind > clock.skew_secs;
+            expected.max(wall)
+        }
+        None => wall,
+    };
+    clock.last = Some((now, Instant::now()));
+    now
+}
+
 pub fn current_timestamp() -> String {
-    let now: DateTime<Local> = Local::now();
-    now.format("%Y-%m-%dT%H:%M:%S%.3f%z").to_string()
+    now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Is the given timestamp (as produced by `current_timestamp`) more than
+/// `secs` seconds in the past?  Unparseable timestamps are never too old.
+pub fn is_older_than(timestamp: &str, secs: u64) -> bool {
+    match DateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+        Ok(then) => {
+            let age = now().signed_duration_since(then);
+            age.num_seconds() > secs as i64
+        }
+        Err(_) => false,
+    }
+}
+
+/// Has a response with the given timestamp outlived a `ttl` (in
+/// seconds)?  Responses dated further in the future than the clock
+/// skew allows have, since they would otherwise never expire.
+pub fn is_expired(timestamp: &str, ttl: u64) -> bool {
+    match DateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+        Ok(then) => {
+            let age = now().signed_duration_since(then).num_seconds();
+            let skew = CLOCK.lock().unwrap().skew_secs;
+            if age < -skew {
+                warn!("Treating response dated {} (in the future) as expired", timestamp);
+                return true;
+            }
+            age > ttl as i64
+        }
+        Err(_) => false,
+    }
+}
+
+/// A timestamp (in the format of `current_timestamp`) `secs` seconds from now.
+pub fn timestamp_after(secs: u64) -> String {
+    let then = now() + chrono::Duration::seconds(secs as i64);
+    then.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Has the given timestamp been reached?  Empty or unparseable
+/// timestamps count as reached.
+pub fn has_passed(timestamp: &str) -> bool {
+    match DateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT) {
+        Ok(then) => now().signed_duration_since(then).num_milliseconds() >= 0,
+        Err(_) => true,
+    }
+}
+
+/// The milliseconds from one timestamp (as produced by `current_timestamp`)
+/// to another, if both can be parsed.
+pub fn millis_between(from: &str, to: &str) -> Option<i64> {
+    let from = DateTime::parse_from_str(from, TIMESTAMP_FORMAT).ok()?;
+    let to = DateTime::parse_from_str(to, TIMESTAMP_FORMAT).ok()?;
+    Some(to.signed_duration_since(from).num_milliseconds())
+}
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f%z";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deactivation_parts(uri: &str) -> Parts {
+        hyper::Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .header("x-api-key", "key")
+            .header("x-request-id", "req1")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn deactivation_query_is_forwarded_byte_for_byte() {
+        let query =
+            "osUserId=u%2B1%20x&npdId=n%3D1&deviceId=d+1&enableVdiMarkerExists=true";
+        let uri = format!("{}?{}", DEACTIVATION_ENDPOINT, query);
+        let req = Request::from_network(&deactivation_parts(&uri), &[]).unwrap();
+        assert_eq!(req.os_user_id, "u+1 x");
+        assert_eq!(req.package_id, "n=1");
+        let net_req = req.to_network("https", "cops.example");
+        assert_eq!(net_req.uri().to_string(), format!("https://cops.example{}", uri));
+    }
+
+    #[test]
+    fn stored_deactivation_without_raw_query_is_rebuilt() {
+        let req = Request {
+            kind: Kind::Deactivation,
+            package_id: "n1".to_string(),
+            device_id: "d1".to_string(),
+            os_user_id: "u1".to_string(),
+            is_vdi: true,
+            ..Default::default()
+        };
+        let net_req = req.to_network("https", "cops.example");
+        assert_eq!(
+            net_req.uri().to_string(),
+            format!(
+                "https://cops.example{}?npdId=n1&deviceId=d1&osUserId=u1&enableVdiMarkerExists=true",
+                DEACTIVATION_ENDPOINT
+            )
+        );
+    }
 }
@@ -6,38 +6,105 @@ NOTICE: Adobe permits you to use, modify, and distribute this file in
 accordance with the terms of the Adobe license agreement accompanying
 it.
 */
+pub mod access;
+pub mod redact;
+pub mod syslog;
+
 use crate::settings::{LogDestination, LogLevel, Settings};
 use eyre::{Result, WrapErr};
-use fern::{log_file, Dispatch};
-use log::LevelFilter;
+use fern::{log_file, Dispatch, FormatCallback};
+use log::{LevelFilter, Record};
+use once_cell::sync::OnceCell;
+use std::cell::RefCell;
+use std::fmt::Arguments;
+use std::future::Future;
 use std::io;
 
+tokio::task_local! {
+    static REQUEST_ID: RefCell<String>;
+}
+
+static INSTANCE_ID: OnceCell<String> = OnceCell::new();
+
 pub fn init(conf: &Settings) -> Result<()> {
-    let mut base_config = Dispatch::new().format(|out, message, record| {
-        out.finish(format_args!(
-            "{}[{}][{}] {}",
-            chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-            record.target(),
-            record.level(),
-            message
-        ))
-    });
+    let _ = INSTANCE_ID.set(conf.proxy.instance_id.clone());
+    redact::init(conf);
     let level = log_level(&conf.logging.level);
-    match conf.logging.destination {
+    let main = match conf.logging.destination {
         LogDestination::Console => {
-            base_config =
-                base_config.chain(Dispatch::new().level(level).chain(io::stdout()))
-        }
-        LogDestination::File => {
-            base_config = base_config.chain(
-                Dispatch::new().level(level).chain(log_file(&conf.logging.file_path)?),
-            )
+            Dispatch::new().format(format_line).level(level).chain(io::stdout())
         }
+        LogDestination::File => Dispatch::new()
+            .format(format_line)
+            .level(level)
+            .chain(log_file(&conf.logging.file_path)?),
+        LogDestination::Syslog => match syslog::Syslog::connect(conf) {
+            Ok(syslog) => {
+                let syslog: Box<dyn log::Log> = Box::new(syslog);
+                Dispatch::new().level(level).chain(syslog)
+            }
+            Err(err) => {
+                eprintln!("Logging to console because syslog is unavailable: {:?}", err);
+                Dispatch::new().format(format_line).level(level).chain(io::stdout())
+            }
+        },
+    };
+    let mut dispatch =
+        Dispatch::new().chain(main.filter(|meta| meta.target() != access::TARGET));
+    if let Some(access) = access::dispatch(conf)? {
+        dispatch = dispatch.chain(access);
     }
-    base_config.apply().wrap_err("Cannot initialize logging subsystem")?;
+    dispatch.apply().wrap_err("Cannot initialize logging subsystem")?;
     Ok(())
 }
 
+fn format_line(out: FormatCallback, message: &Arguments, record: &Record) {
+    let timestamp = chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]");
+    let instance = instance_id().map(|id| format!("[{}]", id)).unwrap_or_default();
+    match request_id() {
+        Some(id) => out.finish(format_args!(
+            "{}{}[{}][{}][{}] {}",
+            timestamp,
+            instance,
+            record.target(),
+            record.level(),
+            id,
+            message
+        )),
+        None => out.finish(format_args!(
+            "{}{}[{}][{}] {}",
+            timestamp,
+            instance,
+            record.target(),
+            record.level(),
+            message
+        )),
+    }
+}
+
+/// Run a request handler so that its log messages can carry the request id.
+pub async fn with_request_context<F: Future>(f: F) -> F::Output {
+    REQUEST_ID.scope(RefCell::new(String::new()), f).await
+}
+
+/// Tag subsequent log messages from this request handler with the request id.
+pub fn set_request_id(id: &str) {
+    let _ = REQUEST_ID.try_with(|current| *current.borrow_mut() = id.to_string());
+}
+
+/// The configured `proxy.instance_id`, once logging is set up, if it's set.
+pub fn instance_id() -> Option<&'static str> {
+    INSTANCE_ID.get().map(String::as_str).filter(|id| !id.is_empty())
+}
+
+/// The request id of the request being handled, if there is one.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID
+        .try_with(|current| current.borrow().clone())
+        .ok()
+        .filter(|id| !id.is_empty())
+}
+
 fn log_level(level: &LogLevel) -> LevelFilter {
     match level {
         LogLevel::Off => LevelFilter::Off,
@@ -0,0 +1,129 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! An access log, with a line for each request served.
+//!
+//! With `logging.access_log` set to `common` or `combined`, each request
+//! gets a line in the Common or Combined Log Format (as written by web
+//! servers) in `logging.access_log_path`, whatever the other logging
+//! settings.  With `logging.access_log_extensions` set, each line ends
+//! with two more fields: the (quoted) request id, and how long COPS took to
+//! respond in milliseconds (or where the response came from instead,
+//! as in `proxy.upstream_latency_header`), or `-` for either that
+//! isn't known.
+use crate::settings::{AccessLogFormat, Settings};
+use chrono::{DateTime, Local};
+use eyre::{Result, WrapErr};
+use fern::{log_file, Dispatch};
+use hyper::body::HttpBody;
+use hyper::{Body, Request as HRequest, Response as HResponse};
+use log::{info, LevelFilter};
+use once_cell::sync::OnceCell;
+use std::net::IpAddr;
+
+/// The log target of access log lines, which only go to the access log.
+pub const TARGET: &str = "frl_proxy::access";
+
+struct Config {
+    combined: bool,
+    extensions: bool,
+}
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Where a response came from, for the access log, as the upstream
+/// marker of `proxy.upstream_latency_header`.
+#[derive(Clone, Debug)]
+pub struct Upstream(pub String);
+
+/// The logger for the configured access log, if there is one.
+pub fn dispatch(conf: &Settings) -> Result<Option<Dispatch>> {
+    let combined = match conf.logging.access_log {
+        AccessLogFormat::Off => return Ok(None),
+        AccessLogFormat::Common => false,
+        AccessLogFormat::Combined => true,
+    };
+    let path = &conf.logging.access_log_path;
+    let file = log_file(path).wrap_err(format!("Can't open access log: {}", path))?;
+    let extensions = conf.logging.access_log_extensions;
+    let _ = CONFIG.set(Config { combined, extensions });
+    Ok(Some(
+        Dispatch::new()
+            .level(LevelFilter::Info)
+            .filter(|meta| meta.target() == TARGET)
+            .format(|out, message, _| out.finish(format_args!("{}", message)))
+            .chain(file),
+    ))
+}
+
+/// The parts of a request that go in its access log line, taken before
+/// it's served.
+pub struct Entry {
+    at: DateTime<Local>,
+    peer: IpAddr,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+}
+
+impl Entry {
+    /// Start the access log entry for a request, if there's an access log.
+    pub fn start(req: &HRequest<Body>, peer: IpAddr) -> Option<Entry> {
+        CONFIG.get()?;
+        let header = |name: &str| {
+            req.headers().get(name).and_then(|v| v.to_str().ok()).map(quoted)
+        };
+        let target = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        Some(Entry {
+            at: Local::now(),
+            peer,
+            request_line: format!("{} {} {:?}", req.method(), target, req.version()),
+            referer: header("referer").unwrap_or_else(|| "-".to_string()),
+            user_agent: header("user-agent").unwrap_or_else(|| "-".to_string()),
+        })
+    }
+
+    /// Write the access log line for a request, given how it was served
+    /// (`None` if it failed without a response).
+    pub fn finish(self, resp: Option<&HResponse<Body>>, request_id: Option<&str>) {
+        let config = match CONFIG.get() {
+            Some(config) => config,
+            None => return,
+        };
+        let status = resp.map(|r| r.status().as_u16()).unwrap_or(500);
+        let bytes = match resp.and_then(|r| r.body().size_hint().exact()) {
+            Some(bytes) => bytes.to_string(),
+            None => "-".to_string(),
+        };
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            self.peer,
+            self.at.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.request_line,
+            status,
+            bytes
+        );
+        if config.combined {
+            line.push_str(&format!(" \"{}\" \"{}\"", self.referer, self.user_agent));
+        }
+        if config.extensions {
+            let upstream = resp.and_then(|r| r.extensions().get::<Upstream>());
+            line.push_str(&format!(
+                " \"{}\" {}",
+                request_id.map(quoted).unwrap_or_else(|| "-".to_string()),
+                upstream.map(|u| u.0.as_str()).unwrap_or("-")
+            ));
+        }
+        info!(target: TARGET, "{}", line);
+    }
+}
+
+/// A value for a quoted field, with anything that would end it escaped.
+fn quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
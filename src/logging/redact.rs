@@ -0,0 +1,92 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Redaction of sensitive fields in logged bodies.
+//!
+//! With `logging.redact_paths` set, request and response bodies are
+//! parsed as JSON before they are logged, and the values at those paths
+//! are replaced with `***`.  A path is a series of object member names
+//! and array indexes, as in `$.deviceDetails.deviceId` or
+//! `$.items[0].token`, in which `*` matches every member or element.
+//! The redacted body is logged as compact JSON; one that isn't JSON is
+//! replaced entirely.  Only the logged copy is changed: what is sent,
+//! returned and cached is the body as received.
+use crate::settings::Settings;
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+
+static PATHS: OnceCell<Vec<Vec<String>>> = OnceCell::new();
+
+/// The segments of a redaction path, or `None` if it isn't valid.
+pub fn parse_path(path: &str) -> Option<Vec<String>> {
+    let path = path.trim();
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let path = path.strip_prefix('.').unwrap_or(path);
+    let segments: Vec<String> =
+        path.replace('[', ".").replace(']', "").split('.').map(String::from).collect();
+    if segments.iter().any(String::is_empty) {
+        None
+    } else {
+        Some(segments)
+    }
+}
+
+/// Remember the configured redaction paths (which have been validated).
+pub fn init(conf: &Settings) {
+    let paths = conf.logging.redact_paths.iter().filter_map(|p| parse_path(p)).collect();
+    let _ = PATHS.set(paths);
+}
+
+/// A body as it should appear in the log.
+pub fn body(bytes: &[u8]) -> String {
+    let paths = match PATHS.get() {
+        Some(paths) if !paths.is_empty() => paths,
+        _ => return String::from_utf8_lossy(bytes).into_owned(),
+    };
+    if bytes.is_empty() {
+        return String::new();
+    }
+    match serde_json::from_slice::<Value>(bytes) {
+        Ok(mut value) => {
+            for path in paths {
+                redact(&mut value, path);
+            }
+            value.to_string()
+        }
+        Err(_) => format!("*** ({} bytes, not JSON)", bytes.len()),
+    }
+}
+
+fn redact(value: &mut Value, path: &[String]) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            *value = Value::String("***".to_string());
+            return;
+        }
+    };
+    match value {
+        Value::Object(map) if first == "*" => {
+            map.values_mut().for_each(|v| redact(v, rest));
+        }
+        Value::Object(map) => {
+            if let Some(v) = map.get_mut(first) {
+                redact(v, rest);
+            }
+        }
+        Value::Array(items) if first == "*" => {
+            items.iter_mut().for_each(|v| redact(v, rest));
+        }
+        Value::Array(items) => {
+            if let Some(v) = first.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                redact(v, rest);
+            }
+        }
+        _ => (),
+    }
+}
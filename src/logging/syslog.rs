@@ -0,0 +1,118 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use super::{instance_id, request_id};
+use crate::settings::Settings;
+use eyre::{eyre, Result, WrapErr};
+use log::{Level, Log, Metadata, Record};
+use std::net::UdpSocket;
+
+/// Where syslog messages are sent.
+enum Transport {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Remote(UdpSocket),
+}
+
+/// A logger which sends RFC 5424 messages to syslog.
+pub struct Syslog {
+    transport: Transport,
+    app_name: String,
+    hostname: String,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Connect to the configured syslog server.
+    ///
+    /// An empty server address means the local syslog socket, which
+    /// only exists on Unix platforms.
+    pub fn connect(conf: &Settings) -> Result<Syslog> {
+        let address = &conf.logging.syslog_address;
+        let transport = if address.is_empty() {
+            local_transport()?
+        } else {
+            let socket =
+                UdpSocket::bind("0.0.0.0:0").wrap_err("Can't create syslog socket")?;
+            socket
+                .connect(address)
+                .wrap_err(format!("Can't reach syslog server: {}", address))?;
+            Transport::Remote(socket)
+        };
+        Ok(Syslog {
+            transport,
+            app_name: conf.logging.syslog_app_name.clone(),
+            hostname: sys_info::hostname().unwrap_or_else(|_| String::from("-")),
+            pid: std::process::id(),
+        })
+    }
+
+    fn send(&self, message: &str) {
+        // there's nowhere to report a logging failure, so ignore it
+        let _ = match &self.transport {
+            #[cfg(unix)]
+            Transport::Local(socket) => socket.send(message.as_bytes()),
+            Transport::Remote(socket) => socket.send(message.as_bytes()),
+        };
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        // facility "user" (1), with the severity that matches the level
+        let priority = 8 + severity(record.level());
+        let mut context = String::new();
+        for tag in instance_id().into_iter().chain(request_id().as_deref()) {
+            context.push_str(&format!("[{}] ", tag));
+        }
+        let message = format!(
+            "<{}>1 {} {} {} {} - - {}: {}{}",
+            priority,
+            chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            record.target(),
+            context,
+            record.args()
+        );
+        self.send(&message);
+    }
+
+    fn flush(&self) {}
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[cfg(unix)]
+fn local_transport() -> Result<Transport> {
+    use std::os::unix::net::UnixDatagram;
+    let socket = UnixDatagram::unbound().wrap_err("Can't create syslog socket")?;
+    for path in ["/dev/log", "/var/run/syslog", "/var/run/log"].iter() {
+        if socket.connect(path).is_ok() {
+            return Ok(Transport::Local(socket));
+        }
+    }
+    Err(eyre!("Can't find the local syslog socket"))
+}
+
+#[cfg(not(unix))]
+fn local_transport() -> Result<Transport> {
+    Err(eyre!("There is no local syslog on this platform; configure a syslog server"))
+}
@@ -12,16 +12,18 @@ mod cache;
 mod cli;
 mod cops;
 mod logging;
+mod metrics;
 mod proxy;
 mod settings;
+#[cfg(feature = "statsd")]
+mod statsd;
 
-use crate::cli::Command;
+use crate::cli::{Command, DeadletterCommand, NamespaceCommand};
 use crate::settings::{LogDestination, ProxyMode};
 use cache::Cache;
 use cli::FrlProxy;
-use eyre::{Result, WrapErr};
-use log::debug;
-use proxy::{plain, secure};
+use eyre::{eyre, Result, WrapErr};
+use log::info;
 use settings::Settings;
 use std::convert::TryInto;
 use std::sync::Arc;
@@ -31,39 +33,83 @@ async fn main() -> Result<()> {
     openssl_probe::init_ssl_cert_env_vars();
     let args: FrlProxy = FrlProxy::from_args();
 
+    // checking a config must not create one, so it's done before loading
+    if let Command::CheckConfig { path } = &args.cmd {
+        return match path {
+            Some(path) => check_config(std::slice::from_ref(path)),
+            None => check_config(&args.config_file),
+        };
+    }
+    // comparing dbs doesn't involve the config (or the configured cache)
+    if let Command::Diff { path_a, path_b, details } = &args.cmd {
+        return diff(path_a, path_b, *details).await;
+    }
+
     // make sure we have a config file.  if not, make one
     if let Some(mut conf) = Settings::load_config(&args)? {
         match args.cmd {
-            cli::Command::Start { mode, ssl } => {
+            cli::Command::Start {
+                mode,
+                ssl,
+                preserve_timing,
+                timing_scale,
+                self_test,
+            } => {
                 if let Some(mode) = mode {
                     conf.proxy.mode = mode.as_str().try_into()?;
                 };
                 if let Some(ssl) = ssl {
                     conf.proxy.ssl = ssl;
                 }
+                if preserve_timing {
+                    conf.forward.preserve_timing = true;
+                }
+                if let Some(scale) = timing_scale {
+                    conf.forward.timing_scale = scale;
+                }
                 conf.validate()?;
                 logging::init(&conf)?;
-                debug!("conf: {:?}", conf);
+                if conf.logging.log_settings {
+                    info!("Effective settings: {:?}", conf);
+                }
+                #[cfg(feature = "statsd")]
+                let emitter = statsd::spawn_emitter(&conf)?;
                 if let ProxyMode::Forward = conf.proxy.mode {
-                    let cache = Cache::from(&conf, false).await?;
-                    proxy::forward_stored_requests(&conf, Arc::clone(&cache)).await;
+                    let cache = Cache::exclusive(&conf, false).await?;
+                    if self_test {
+                        proxy::self_test::run(&conf, &cache).await?;
+                    }
+                    proxy::forward_stored_requests(&conf, Arc::clone(&cache), false)
+                        .await;
                     cache.close().await;
                 } else {
-                    let cache = Cache::from(&conf, true).await?;
-                    if conf.proxy.ssl {
-                        secure::run_server(&conf, Arc::clone(&cache)).await?;
-                    } else {
-                        plain::run_server(&conf, Arc::clone(&cache)).await?;
+                    let cache = Cache::exclusive(&conf, true).await?;
+                    if self_test {
+                        proxy::self_test::run(&conf, &cache).await?;
                     }
+                    let sweeper = cache::spawn_sweeper(&conf, Arc::clone(&cache));
+                    let snapshots = metrics::spawn_snapshot_logger(&conf);
+                    proxy::run_servers(&conf, Arc::clone(&cache)).await?;
+                    if let Some(sweeper) = sweeper {
+                        sweeper.abort();
+                    }
+                    if let Some(snapshots) = snapshots {
+                        snapshots.abort();
+                    }
+                    metrics::log_summary();
                     cache.close().await;
                 }
+                #[cfg(feature = "statsd")]
+                statsd::stop(emitter);
             }
+            Command::CheckConfig { .. } => unreachable!("config checked before loading"),
+            Command::Diff { .. } => unreachable!("dbs compared before loading"),
             cli::Command::Configure => {
                 conf.validate()?;
                 // do not log configuration changes, because
                 // logging might interfere with the interactions
                 // and there really isn't anything to log.
-                conf.update_config_file(&args.config_file)?;
+                conf.update_config_file(single_config_file(&args)?)?;
             }
             Command::Clear { yes } => {
                 conf.proxy.mode = ProxyMode::Cache;
@@ -74,6 +120,209 @@ async fn main() -> Result<()> {
                 let cache = Cache::from(&conf, true).await?;
                 cache.clear(yes).await.wrap_err("Failed to clear cache")?;
             }
+            Command::Purge => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                let counts = cache.purge().await.wrap_err("Failed to purge cache")?;
+                eprintln!(
+                    "Purged {} expired response(s) and {} over-age request(s).",
+                    counts.responses, counts.requests
+                );
+                cache.close().await;
+            }
+            Command::Gc => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command reports to the console
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                let counts =
+                    cache.gc().await.wrap_err("Failed to garbage collect cache")?;
+                eprintln!(
+                    "Removed {} orphaned activation response(s) and {} orphaned deactivation response(s).",
+                    counts.activation_responses, counts.deactivation_responses
+                );
+                cache.close().await;
+            }
+            Command::Drain => {
+                conf.proxy.mode = ProxyMode::Forward;
+                // log to file, because this command reports to the console
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::exclusive(&conf, false).await?;
+                let summary =
+                    proxy::forward_stored_requests(&conf, Arc::clone(&cache), true).await;
+                cache.close().await;
+                let unsent = summary.failures
+                    + summary.errors
+                    + summary.deferred
+                    + summary.skipped;
+                if unsent > 0 {
+                    return Err(eyre!("{} request(s) could not be forwarded", unsent));
+                }
+            }
+            Command::Stats { json } => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                let stats = cache.stats().await.wrap_err("Failed to read cache stats")?;
+                cache.close().await;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    let none = String::from("(none)");
+                    println!("Stored requests:  {}", stats.stored_requests);
+                    println!("Pending forwards: {}", stats.pending_forwards);
+                    if stats.dead_letters > 0 {
+                        println!("Dead letters:     {}", stats.dead_letters);
+                    }
+                    println!("Cached responses: {}", stats.cached_responses);
+                    if stats.max_stored_requests > 0 {
+                        println!(
+                            "Request cap:      {} of {}{}",
+                            stats.unforwarded_requests,
+                            stats.max_stored_requests,
+                            if stats.at_capacity { " (full)" } else { "" }
+                        );
+                    }
+                    println!(
+                        "Oldest entry:     {}",
+                        stats.oldest_entry.as_ref().unwrap_or(&none)
+                    );
+                    println!(
+                        "Newest entry:     {}",
+                        stats.newest_entry.as_ref().unwrap_or(&none)
+                    );
+                    println!("Database size:    {} bytes", stats.db_size_bytes);
+                }
+            }
+            Command::Namespace { cmd } => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                match cmd {
+                    NamespaceCommand::List => {
+                        let namespaces = cache
+                            .namespaces()
+                            .await
+                            .wrap_err("Failed to list namespaces")?;
+                        for (ns, entries) in namespaces.iter() {
+                            let name = if ns.is_empty() { "(default)" } else { ns };
+                            eprintln!("{}: {} entries", name, entries);
+                        }
+                    }
+                    NamespaceCommand::Clear { name, yes } => {
+                        settings::validate_namespace(&name)?;
+                        cache
+                            .clear_namespace(&name, yes)
+                            .await
+                            .wrap_err(format!("Failed to clear namespace '{}'", name))?;
+                    }
+                }
+                cache.close().await;
+            }
+            Command::Deadletter { cmd } => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                match cmd {
+                    DeadletterCommand::List => {
+                        let requests = cache
+                            .dead_letters()
+                            .await
+                            .wrap_err("Failed to list dead-lettered requests")?;
+                        for req in requests.iter() {
+                            eprintln!(
+                                "{} {} (received {}, {} attempt(s))",
+                                req.kind, req.request_id, req.timestamp, req.attempts
+                            );
+                        }
+                        eprintln!("{} dead-lettered request(s)", requests.len());
+                    }
+                    DeadletterCommand::Requeue { id, all } => {
+                        let id = if all { None } else { id };
+                        let count = cache
+                            .requeue(id.as_deref())
+                            .await
+                            .wrap_err("Failed to requeue dead-lettered requests")?;
+                        if let (Some(id), 0) = (&id, count) {
+                            cache.close().await;
+                            return Err(eyre!("No dead-lettered request has id {}", id));
+                        }
+                        eprintln!("Requeued {} dead-lettered request(s)", count);
+                    }
+                }
+                cache.close().await;
+            }
+            Command::List => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                let requests = cache
+                    .unforwarded_requests()
+                    .await
+                    .wrap_err("Failed to list stored requests")?;
+                for req in requests.iter() {
+                    eprintln!(
+                        "{} {} (received {}, {} attempt(s))",
+                        req.kind, req.request_id, req.timestamp, req.attempts
+                    );
+                    eprintln!("    key {}", cache.key(req));
+                    if !req.last_error.is_empty() {
+                        eprintln!(
+                            "    last failed {}: {}",
+                            req.last_attempt_at, req.last_error
+                        );
+                    }
+                }
+                eprintln!("{} stored request(s) not forwarded", requests.len());
+                cache.close().await;
+            }
+            Command::Pinned => {
+                conf.proxy.mode = ProxyMode::Cache;
+                // log to file, because this command is interactive
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                let cache = Cache::from(&conf, false).await?;
+                let (pinned, missing) = cache
+                    .pinned_responses()
+                    .await
+                    .wrap_err("Failed to list pinned responses")?;
+                for resp in pinned.iter() {
+                    eprintln!("{} {} (cached {})", resp.kind, resp.key, resp.timestamp);
+                }
+                for key in missing.iter() {
+                    eprintln!("{} (pinned, but not in the cache)", key);
+                }
+                eprintln!("{} pinned response(s)", pinned.len());
+                cache.close().await;
+            }
+            Command::TestCops => {
+                // log to file, because this command reports to the console
+                conf.logging.destination = LogDestination::File;
+                conf.validate()?;
+                logging::init(&conf)?;
+                proxy::cops_test::run(&conf).await?;
+            }
             Command::Import { import_path } => {
                 conf.proxy.mode = ProxyMode::Cache;
                 // log to file, because this command is interactive
@@ -101,8 +350,80 @@ async fn main() -> Result<()> {
         }
     } else {
         let mut conf = Settings::load_config(&args)?.unwrap();
-        conf.update_config_file(&args.config_file)
+        conf.update_config_file(single_config_file(&args)?)
             .wrap_err("Failed to update configuration file")?;
     }
     Ok(())
 }
+
+/// The config file to update, which must be the only one given.
+fn single_config_file(args: &FrlProxy) -> Result<&str> {
+    match args.config_file.as_slice() {
+        [path] => Ok(path),
+        _ => Err(eyre!("Only a single config file can be updated, not a list of them")),
+    }
+}
+
+/// Report every problem we can find in (a layered list of) config files,
+/// without binding any ports or touching the cache.
+fn check_config(paths: &[String]) -> Result<()> {
+    for path in paths.iter() {
+        std::fs::metadata(path).wrap_err(format!("Can't read config file: {}", path))?;
+    }
+    let shown = paths.join(", ");
+    let mut conf = Settings::read_config(paths)
+        .wrap_err(format!("Can't parse config file(s): {}", shown))?;
+    let mut problems = Vec::new();
+    if let Err(err) = conf.validate() {
+        problems.push(err);
+    }
+    // a missing certificate file has been reported by validation
+    if conf.proxy.ssl && std::fs::metadata(&conf.ssl.cert_path).is_ok() {
+        if let Err(err) = proxy::secure::load_acceptor(&conf) {
+            problems.push(err);
+        }
+    }
+    if conf.network.use_proxy {
+        let proxy_url =
+            format!("http://{}:{}", conf.network.proxy_host, conf.network.proxy_port);
+        if proxy_url.parse::<hyper::Uri>().is_err() {
+            problems.push(eyre!("Invalid upstream proxy address: {}", proxy_url));
+        }
+    }
+    if problems.is_empty() {
+        println!("OK");
+        Ok(())
+    } else {
+        for problem in problems.iter() {
+            eprintln!("{:#}", problem);
+        }
+        Err(eyre!("Found {} problem(s) in config file(s) {}", problems.len(), shown))
+    }
+}
+
+async fn diff(path_a: &str, path_b: &str, details: bool) -> Result<()> {
+    let diff = cache::diff(path_a, path_b)
+        .await
+        .wrap_err(format!("Failed to compare {} with {}", path_a, path_b))?;
+    let sections = [
+        ("Requests only in A", &diff.requests_only_in_a),
+        ("Requests only in B", &diff.requests_only_in_b),
+        ("Responses only in A", &diff.responses_only_in_a),
+        ("Responses only in B", &diff.responses_only_in_b),
+        ("Responses differing", &diff.responses_differing),
+    ];
+    println!("A: {}", path_a);
+    println!("B: {}", path_b);
+    for (title, entries) in sections.iter() {
+        println!("{}: {}", title, entries.len());
+        if details {
+            for entry in entries.iter() {
+                println!("    {}", entry);
+            }
+        }
+    }
+    if diff.is_empty() {
+        println!("No differences.");
+    }
+    Ok(())
+}
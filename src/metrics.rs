@@ -0,0 +1,516 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::cops::Kind;
+use crate::settings::Settings;
+use log::info;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// The things we count about each kind of request.
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    /// A valid request was received from a client.
+    Request,
+    /// COPS returned a success response.
+    CopsSuccess,
+    /// COPS returned a failure response.
+    CopsFailure,
+    /// COPS could not be reached (network error or timeout).
+    CopsError,
+    /// A cached response was served in place of a COPS response.
+    CacheHit,
+    /// The client got a response from COPS.
+    ServedLive,
+    /// The client got a cached response, which is being refreshed.
+    ServedStale,
+    /// The client got a cached response because COPS couldn't answer.
+    ServedFallback,
+    /// The client got a cached response, without calling COPS, because
+    /// it asked to.
+    ServedPreferred,
+    /// The client got the offline response (with no cached response).
+    ServedOffline,
+    /// The client got a failure (with no cached response).
+    ServedFailure,
+}
+
+struct KindCounters {
+    requests: AtomicU64,
+    cops_success: AtomicU64,
+    cops_failure: AtomicU64,
+    cops_error: AtomicU64,
+    cache_hits: AtomicU64,
+    served_live: AtomicU64,
+    served_stale: AtomicU64,
+    served_fallback: AtomicU64,
+    served_preferred: AtomicU64,
+    served_offline: AtomicU64,
+    served_failure: AtomicU64,
+    cops_latency_ms: AtomicU64,
+    cops_calls: AtomicU64,
+}
+
+impl KindCounters {
+    const fn new() -> KindCounters {
+        KindCounters {
+            requests: AtomicU64::new(0),
+            cops_success: AtomicU64::new(0),
+            cops_failure: AtomicU64::new(0),
+            cops_error: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            served_live: AtomicU64::new(0),
+            served_stale: AtomicU64::new(0),
+            served_fallback: AtomicU64::new(0),
+            served_preferred: AtomicU64::new(0),
+            served_offline: AtomicU64::new(0),
+            served_failure: AtomicU64::new(0),
+            cops_latency_ms: AtomicU64::new(0),
+            cops_calls: AtomicU64::new(0),
+        }
+    }
+
+    fn counter(&self, event: Event) -> &AtomicU64 {
+        match event {
+            Event::Request => &self.requests,
+            Event::CopsSuccess => &self.cops_success,
+            Event::CopsFailure => &self.cops_failure,
+            Event::CopsError => &self.cops_error,
+            Event::CacheHit => &self.cache_hits,
+            Event::ServedLive => &self.served_live,
+            Event::ServedStale => &self.served_stale,
+            Event::ServedFallback => &self.served_fallback,
+            Event::ServedPreferred => &self.served_preferred,
+            Event::ServedOffline => &self.served_offline,
+            Event::ServedFailure => &self.served_failure,
+        }
+    }
+}
+
+static ACTIVATION: KindCounters = KindCounters::new();
+static DEACTIVATION: KindCounters = KindCounters::new();
+static BAD_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static REJECTED_SIGNATURES: AtomicU64 = AtomicU64::new(0);
+static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static REFUSED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static SHED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static OVERSIZED_HEADERS: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static DENIED_RETRIES: AtomicU64 = AtomicU64::new(0);
+static UNSTORED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static EVICTED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static CORRUPT_RESPONSES: AtomicU64 = AtomicU64::new(0);
+// negative until there is a cap on stored requests to report on
+static UNFORWARDED_REQUESTS: AtomicI64 = AtomicI64::new(-1);
+static COPS_CONNECTIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+static COPS_CONNECTIONS_REUSED: AtomicU64 = AtomicU64::new(0);
+static TLS_HANDSHAKES: AtomicU64 = AtomicU64::new(0);
+static TLS_HANDSHAKE_MS: AtomicU64 = AtomicU64::new(0);
+static AUTO_SWITCHES: AtomicU64 = AtomicU64::new(0);
+// negative unless the proxy is in auto mode
+static AUTO_OFFLINE: AtomicI64 = AtomicI64::new(-1);
+// negative until there is a retry budget to report on
+static RETRY_BUDGET: AtomicI64 = AtomicI64::new(-1);
+
+fn counters(kind: &Kind) -> &'static KindCounters {
+    match kind {
+        Kind::Activation => &ACTIVATION,
+        Kind::Deactivation => &DEACTIVATION,
+    }
+}
+
+/// Count an event for the given kind of request.
+pub fn count(kind: &Kind, event: Event) {
+    counters(kind).counter(event).fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record how long a COPS call for the given kind of request took.
+pub fn observe_latency(kind: &Kind, elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    let counters = counters(kind);
+    counters.cops_latency_ms.fetch_add(ms, Ordering::Relaxed);
+    counters.cops_calls.fetch_add(1, Ordering::Relaxed);
+    #[cfg(feature = "statsd")]
+    crate::statsd::timing("cops_latency_ms", Some(kind), ms);
+}
+
+/// Count a request that could not be parsed (so has no kind).
+pub fn count_bad_request() {
+    BAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a request rejected for a missing or invalid signature.
+pub fn count_rejected_signature() {
+    REJECTED_SIGNATURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note that a client connection has been accepted.
+pub fn connection_opened() {
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note that an accepted client connection has closed.
+pub fn connection_closed() {
+    OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Count a client connection refused because of a connection limit.
+pub fn count_refused_connection() {
+    REFUSED_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a request shed because of the limit on bytes in flight.
+pub fn count_shed_request() {
+    SHED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a request rejected because its headers were too large.
+pub fn count_oversized_headers() {
+    OVERSIZED_HEADERS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a request not stored because the cache was full.
+pub fn count_unstored_request() {
+    UNSTORED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a request evicted from a full cache to make room for a new one.
+pub fn count_evicted_request() {
+    EVICTED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a cached response ignored because it didn't match its checksum.
+pub fn count_corrupt_response() {
+    CORRUPT_RESPONSES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the number of un-forwarded requests in the cache.
+pub fn set_unforwarded_requests(count: u64) {
+    UNFORWARDED_REQUESTS.store(count as i64, Ordering::Relaxed);
+}
+
+/// Count a retry of a COPS call.
+pub fn count_retry() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a retry which wasn't made because the retry budget was exhausted.
+pub fn count_denied_retry() {
+    DENIED_RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Record the number of retries left in the retry budget.
+pub fn set_retry_budget(remaining: u64) {
+    RETRY_BUDGET.store(remaining as i64, Ordering::Relaxed);
+}
+
+/// Count a COPS call by whether it reused a pooled connection, with
+/// the TLS handshake time of a new connection.
+pub fn count_cops_connection(reused: bool, tls_handshake: Option<Duration>) {
+    if reused {
+        COPS_CONNECTIONS_REUSED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        COPS_CONNECTIONS_OPENED.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(handshake) = tls_handshake {
+        let ms = handshake.as_millis() as u64;
+        TLS_HANDSHAKES.fetch_add(1, Ordering::Relaxed);
+        TLS_HANDSHAKE_MS.fetch_add(ms, Ordering::Relaxed);
+        #[cfg(feature = "statsd")]
+        crate::statsd::timing("cops_tls_handshake_ms", None, ms);
+    }
+}
+
+/// Record whether an auto-mode proxy is working offline.
+pub fn set_auto_offline(offline: bool) {
+    let previous = AUTO_OFFLINE.swap(offline as i64, Ordering::Relaxed);
+    if previous >= 0 && previous != offline as i64 {
+        AUTO_SWITCHES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Log the current value of all the metrics.
+pub fn log_summary() {
+    for sample in samples() {
+        match sample.kind {
+            Some(kind) => {
+                info!("{} ({}) = {} [{}]", sample.name, kind, sample.value, sample.help)
+            }
+            None => info!("{} = {} [{}]", sample.name, sample.value, sample.help),
+        }
+    }
+}
+
+/// Start logging a snapshot of the metrics every
+/// `metrics.log_interval_secs`, if that's set.
+///
+/// Each snapshot is one line of `name=value` pairs, with a `.kind`
+/// suffix on per-kind names.  Counters are given as their change since
+/// the last snapshot (or the start), and gauges as their current value.
+pub fn spawn_snapshot_logger(conf: &Settings) -> Option<JoinHandle<()>> {
+    let secs = conf.metrics.log_interval_secs;
+    if secs == 0 {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let mut last_logged: HashMap<String, u64> = HashMap::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        // the first tick is immediate, and there's nothing to report yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let mut line = String::new();
+            for sample in samples() {
+                let name = match &sample.kind {
+                    Some(kind) => {
+                        format!("{}.{}", sample.name, kind.to_string().to_lowercase())
+                    }
+                    None => sample.name.to_string(),
+                };
+                let value = if sample.gauge {
+                    sample.value
+                } else {
+                    let last = last_logged.insert(name.clone(), sample.value);
+                    sample.value - last.unwrap_or(0)
+                };
+                write!(line, " {}={}", name, value).unwrap();
+            }
+            info!("Metrics for the last {}s:{}", secs, line);
+        }
+    }))
+}
+
+/// The current value of a metric, as exported to monitoring systems.
+///
+/// All exporters work from these samples, so they always agree
+/// on the names and meanings of the metrics.
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Option<Kind>,
+    pub value: u64,
+    /// Gauges can go down as well as up; all other metrics are counters.
+    pub gauge: bool,
+}
+
+type Counter = fn(&KindCounters) -> &AtomicU64;
+
+/// The current values of all the metrics.
+pub fn samples() -> Vec<Sample> {
+    let mut result = Vec::new();
+    let per_kind: [(&'static str, &'static str, Counter); 13] = [
+        ("requests_total", "Valid requests received from clients", |c| &c.requests),
+        ("cops_success_total", "Success responses received from COPS", |c| {
+            &c.cops_success
+        }),
+        ("cops_failure_total", "Failure responses received from COPS", |c| {
+            &c.cops_failure
+        }),
+        ("cops_error_total", "COPS calls that got no response", |c| &c.cops_error),
+        ("cache_hits_total", "Cached responses served in place of COPS", |c| {
+            &c.cache_hits
+        }),
+        ("served_live_total", "Requests answered by COPS", |c| &c.served_live),
+        (
+            "served_stale_total",
+            "Requests answered from the cache while refreshing",
+            |c| &c.served_stale,
+        ),
+        (
+            "served_fallback_total",
+            "Requests answered from the cache because COPS couldn't",
+            |c| &c.served_fallback,
+        ),
+        (
+            "served_preferred_total",
+            "Requests answered from the cache because the client asked",
+            |c| &c.served_preferred,
+        ),
+        (
+            "served_offline_total",
+            "Requests answered as offline, with nothing cached",
+            |c| &c.served_offline,
+        ),
+        (
+            "served_failure_total",
+            "Requests answered with a failure, with nothing cached",
+            |c| &c.served_failure,
+        ),
+        ("cops_latency_ms_sum", "Total milliseconds spent in COPS calls", |c| {
+            &c.cops_latency_ms
+        }),
+        ("cops_latency_ms_count", "Number of timed COPS calls", |c| &c.cops_calls),
+    ];
+    for (name, help, counter) in per_kind.iter() {
+        for kind in [Kind::Activation, Kind::Deactivation].iter() {
+            result.push(Sample {
+                name,
+                help,
+                kind: Some(kind.clone()),
+                value: counter(counters(kind)).load(Ordering::Relaxed),
+                gauge: false,
+            });
+        }
+    }
+    result.push(Sample {
+        name: "bad_requests_total",
+        help: "Requests rejected as malformed",
+        kind: None,
+        value: BAD_REQUESTS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "rejected_signatures_total",
+        help: "Requests rejected for a missing or invalid signature",
+        kind: None,
+        value: REJECTED_SIGNATURES.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "open_connections",
+        help: "Client connections currently open",
+        kind: None,
+        value: OPEN_CONNECTIONS.load(Ordering::Relaxed),
+        gauge: true,
+    });
+    result.push(Sample {
+        name: "refused_connections_total",
+        help: "Client connections refused because of a connection limit",
+        kind: None,
+        value: REFUSED_CONNECTIONS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "in_flight_bytes",
+        help: "Bytes of request bodies currently held in memory",
+        kind: None,
+        value: crate::proxy::in_flight::bytes(),
+        gauge: true,
+    });
+    result.push(Sample {
+        name: "shed_requests_total",
+        help: "Requests shed because of the limit on bytes in flight",
+        kind: None,
+        value: SHED_REQUESTS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "oversized_headers_total",
+        help: "Requests rejected because of the limit on header bytes",
+        kind: None,
+        value: OVERSIZED_HEADERS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "retries_total",
+        help: "COPS calls retried",
+        kind: None,
+        value: RETRIES.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "retries_denied_total",
+        help: "COPS calls not retried because the retry budget was exhausted",
+        kind: None,
+        value: DENIED_RETRIES.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "unstored_requests_total",
+        help: "Requests not stored because the cache was full",
+        kind: None,
+        value: UNSTORED_REQUESTS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "evicted_requests_total",
+        help: "Un-forwarded requests evicted from a full cache",
+        kind: None,
+        value: EVICTED_REQUESTS.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    result.push(Sample {
+        name: "corrupt_responses_total",
+        help: "Cached responses ignored because they didn't match their checksums",
+        kind: None,
+        value: CORRUPT_RESPONSES.load(Ordering::Relaxed),
+        gauge: false,
+    });
+    let connections: [(&'static str, &'static str, &AtomicU64); 4] = [
+        (
+            "cops_connections_opened_total",
+            "COPS calls which opened a new connection",
+            &COPS_CONNECTIONS_OPENED,
+        ),
+        (
+            "cops_connections_reused_total",
+            "COPS calls which reused a pooled connection",
+            &COPS_CONNECTIONS_REUSED,
+        ),
+        (
+            "cops_tls_handshake_ms_sum",
+            "Total milliseconds spent in TLS handshakes with COPS",
+            &TLS_HANDSHAKE_MS,
+        ),
+        (
+            "cops_tls_handshake_ms_count",
+            "Number of TLS handshakes with COPS",
+            &TLS_HANDSHAKES,
+        ),
+    ];
+    for (name, help, counter) in connections.iter() {
+        result.push(Sample {
+            name,
+            help,
+            kind: None,
+            value: counter.load(Ordering::Relaxed),
+            gauge: false,
+        });
+    }
+    let offline = AUTO_OFFLINE.load(Ordering::Relaxed);
+    if offline >= 0 {
+        result.push(Sample {
+            name: "auto_offline",
+            help: "Whether the auto-mode proxy is working offline (1) or connected (0)",
+            kind: None,
+            value: offline as u64,
+            gauge: true,
+        });
+        result.push(Sample {
+            name: "auto_switches_total",
+            help: "Switches of the auto-mode proxy between connected and offline",
+            kind: None,
+            value: AUTO_SWITCHES.load(Ordering::Relaxed),
+            gauge: false,
+        });
+    }
+    let unforwarded = UNFORWARDED_REQUESTS.load(Ordering::Relaxed);
+    if unforwarded >= 0 {
+        result.push(Sample {
+            name: "unforwarded_requests",
+            help: "Requests in the cache which haven't been forwarded",
+            kind: None,
+            value: unforwarded as u64,
+            gauge: true,
+        });
+    }
+    let budget = RETRY_BUDGET.load(Ordering::Relaxed);
+    if budget >= 0 {
+        result.push(Sample {
+            name: "retry_budget_remaining",
+            help: "Retries left in the retry budget",
+            kind: None,
+            value: budget as u64,
+            gauge: true,
+        });
+    }
+    result
+}
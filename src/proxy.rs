@@ -6,23 +6,61 @@ NOTICE: Adobe permits you to use, modify, and distribute this file in
 accordance with the terms of the Adobe license agreement accompanying
 it.
 */
+pub mod admin;
+pub mod auto;
+pub mod client_headers;
+pub mod connector;
+pub mod cops_test;
+pub mod forward_trigger;
+pub mod health;
+pub mod idle;
+pub mod in_flight;
+pub mod limits;
+pub mod maintenance;
+pub mod methods;
+pub mod mode;
 pub mod plain;
+pub mod retry;
+pub mod rewrite;
 pub mod secure;
+pub mod self_test;
+pub mod shutdown;
+pub mod signature;
+pub mod static_response;
+pub mod status_remap;
+pub mod webhook;
 
 use crate::cache::Cache;
-use crate::cops::{agent, BadRequest, Request as CRequest, Response as CResponse};
-use crate::settings::ProxyMode;
-use crate::settings::Settings;
+use crate::cops::{
+    agent, current_timestamp, millis_between, BadRequest, Request as CRequest,
+    Response as CResponse,
+};
+use crate::logging::{self, access};
+use crate::metrics::{self, Event};
+use crate::settings::{
+    EmptyBodyPolicy, Http2Mode, ProbeMethod, ProxyMode, Settings, UnavailablePolicy,
+};
+use connector::{ConnectionInfo, CopsConnector, ProxyHopConnector, ProxyHopError};
+use in_flight::ReadError;
+use limits::ConnectionLimiter;
 
 use eyre::{eyre, Report, Result, WrapErr};
 use headers::Authorization;
-use hyper::client::HttpConnector;
-use hyper::{Body, Client, Request as HRequest, Response as HResponse, Uri};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, HOST};
+use hyper::{client, Body, Client, Request as HRequest, Response as HResponse, Uri};
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
-use hyper_tls::HttpsConnector;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::oneshot::{self, error::TryRecvError};
+use tokio::sync::watch;
 
 fn ctrl_c_handler<F>(f: F)
 where
@@ -41,114 +79,972 @@ where
     .unwrap();
 }
 
+/// Run the configured listener(s) until a shutdown signal is received.
+///
+/// When SSL is enabled and `also_plain` is set, both the secure and
+/// the plain listener run, sharing the cache, the connection limits,
+/// and a single shutdown signal.
+///
+/// Shutdown drains the listeners and any background forwarding run in
+/// the order set by `proxy.shutdown_order`.  It's started by a signal,
+/// or by the idle timer (see `idle`).
+pub async fn run_servers(conf: &Settings, cache: Arc<Cache>) -> Result<()> {
+    let (tx, rx) = watch::channel(false);
+    let tx = Arc::new(tx);
+    let signal = Arc::clone(&tx);
+    ctrl_c_handler(move || signal.send(true).unwrap_or(()));
+    let idle_timer = idle::spawn_timer(conf, tx);
+    let (stop_serving, serve_rx) = watch::channel(false);
+    let (stop_forwarding, forward_rx) = watch::channel(false);
+    let limiter = Arc::new(ConnectionLimiter::from(conf));
+    let prober = auto::spawn_prober(conf);
+    maintenance::init(conf);
+    let trigger = forward_trigger::spawn(conf, Arc::clone(&cache), forward_rx.clone());
+    let admin = admin::run_server(conf, Arc::clone(&cache), serve_rx.clone(), forward_rx);
+    let backlog = Arc::clone(&cache);
+    let serving = async {
+        futures::try_join!(admin, serve_all(conf, cache, limiter, serve_rx)).map(|_| ())
+    };
+    let served =
+        shutdown::run(conf, rx, serving, stop_serving, stop_forwarding, backlog).await;
+    if let Some(prober) = prober {
+        prober.abort();
+    }
+    if let Some(trigger) = trigger {
+        trigger.abort();
+    }
+    if let Some(idle_timer) = idle_timer {
+        idle_timer.abort();
+    }
+    served
+}
+
+async fn serve_all(
+    conf: &Settings, cache: Arc<Cache>, limiter: Arc<ConnectionLimiter>,
+    rx: watch::Receiver<bool>,
+) -> Result<()> {
+    if conf.proxy.ssl && conf.proxy.also_plain {
+        let plain =
+            plain::run_server(conf, Arc::clone(&cache), Arc::clone(&limiter), rx.clone());
+        let secure = secure::run_server(conf, cache, limiter, rx);
+        futures::try_join!(plain, secure)?;
+    } else if conf.proxy.ssl {
+        secure::run_server(conf, cache, limiter, rx).await?;
+    } else {
+        plain::run_server(conf, cache, limiter, rx).await?;
+    }
+    Ok(())
+}
+
+/// Listen on the given address, with the configured backlog.
+async fn bind_listener(conf: &Settings, addr: &str) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            "No address to listen on",
+        )
+    })?;
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    // as TcpListener::bind does, so restarts don't wait for old connections
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    let backlog = match conf.limits.listen_backlog {
+        0 => DEFAULT_LISTEN_BACKLOG,
+        backlog => backlog,
+    };
+    socket.listen(backlog)
+}
+
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+/// Wait until the shutdown signal has been sent (or can never be sent).
+async fn wait_for_shutdown(mut rx: watch::Receiver<bool>) {
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Serve a request from a client, noting it in the access log.
 async fn serve_req(
-    req: HRequest<Body>, conf: Settings, cache: Arc<Cache>,
+    req: HRequest<Body>, conf: Settings, cache: Arc<Cache>, peer: IpAddr,
 ) -> Result<HResponse<Body>> {
-    let (parts, body) = req.into_parts();
-    let body = hyper::body::to_bytes(body).await?;
+    let entry = access::Entry::start(&req, peer);
+    let result = answer_req(req, conf, cache, peer).await;
+    if let Some(entry) = entry {
+        entry.finish(result.as_ref().ok(), logging::request_id().as_deref());
+    }
+    result
+}
+
+async fn answer_req(
+    req: HRequest<Body>, mut conf: Settings, cache: Arc<Cache>, peer: IpAddr,
+) -> Result<HResponse<Body>> {
+    let _busy = idle::busy();
+    mode::apply(&mut conf);
+    let (mut parts, body) = req.into_parts();
+    if let Some(total) = limits::headers_too_large(&conf, &parts.headers) {
+        warn!(
+            "Rejecting request for {:?}: {} bytes of headers (limit {})",
+            parts.uri, total, conf.limits.max_header_bytes
+        );
+        metrics::count_oversized_headers();
+        let request_id = incoming_request_id(&parts.headers);
+        let msg = "Request header fields too large";
+        return Ok(with_request_id(
+            &conf,
+            request_id,
+            error_response(&conf, 431, msg, request_id),
+        ));
+    }
+    // held until the request has been served
+    let mut reservation = in_flight::Reservation::default();
+    let body = match in_flight::read_body(&conf, body, &mut reservation).await {
+        Ok(body) => body,
+        Err(ReadError::OverBudget) => {
+            warn!("Shedding request for {:?}: too many bytes in flight", parts.uri);
+            metrics::count_shed_request();
+            let request_id = incoming_request_id(&parts.headers);
+            let msg = "The proxy is too busy to accept this request; try again later";
+            return Ok(with_request_id(
+                &conf,
+                request_id,
+                error_response(&conf, 503, msg, request_id),
+            ));
+        }
+        Err(ReadError::Incomplete(err)) => {
+            warn!("Can't read body of request for {:?}: {}", parts.uri, err);
+            metrics::count_bad_request();
+            let request_id = incoming_request_id(&parts.headers);
+            let err = BadRequest::from("Incomplete request body");
+            return Ok(with_request_id(
+                &conf,
+                request_id,
+                bad_request_response(&conf, &err, request_id),
+            ));
+        }
+    };
     info!("Received request for {:?}", parts.uri);
+    // signatures cover the path the client sent
+    let sent_uri = parts.uri.clone();
+    rewrite::normalize_uri(&conf, &mut parts.uri);
+    rewrite::rewrite_uri(&conf, &mut parts.uri);
+    let request_id = incoming_request_id(&parts.headers);
+    if let Some(resp) = static_response::for_path(&conf, parts.uri.path()) {
+        return Ok(with_request_id(&conf, request_id, resp));
+    }
+    if let Some(resp) =
+        methods::answer_locally(&conf, &parts.method, parts.uri.path(), request_id)
+    {
+        return Ok(with_request_id(&conf, request_id, resp));
+    }
+    if let Err(reason) =
+        signature::check(&conf, &parts.method, &sent_uri, &parts.headers, &body)
+    {
+        metrics::count_rejected_signature();
+        let request_id = incoming_request_id(&parts.headers);
+        let resp = unauthorized_response(&conf, reason, request_id);
+        return Ok(with_request_id(&conf, request_id, resp));
+    }
+    supply_request_id(&conf, &mut parts.headers);
     debug!("Received request method: {:?}", parts.method);
     debug!("Received request headers: {:?}", parts.headers);
-    debug!("Received request body: {}", std::str::from_utf8(&body).unwrap());
+    debug!("Received request body: {}", logging::redact::body(&body));
 
     // Analyze and handle the request
     match CRequest::from_network(&parts, &body) {
-        Err(err) => Ok(bad_request_response(&err)),
-        Ok(req) => {
-            info!("Received request id: {}", &req.request_id);
-            cache.store_request(&req).await;
-            let net_resp = if let ProxyMode::Store = conf.proxy.mode {
-                debug!("Store mode - not contacting COPS");
-                proxy_offline_response()
-            } else {
-                match call_cops(&conf, &req).await {
-                    Ok(resp) => resp,
-                    Err(err) => cops_failure_response(err),
+        Err(err) => {
+            metrics::count_bad_request();
+            // a bad request may still have told us its id
+            let request_id = incoming_request_id(&parts.headers);
+            let resp = bad_request_response(&conf, &err, request_id);
+            Ok(with_request_id(&conf, request_id, resp))
+        }
+        Ok(mut req) => {
+            logging::set_request_id(&req.request_id);
+            cache.set_key_factors(&mut req, &parts.headers);
+            req.client_headers = client_headers::for_cops(&conf, &parts.headers);
+            client_headers::add_forwarded(
+                &conf,
+                &parts.headers,
+                peer,
+                &mut req.client_headers,
+            );
+            req.timeout_ms = requested_timeout(&conf, &parts.headers);
+            req.prefer_cache = prefers_cache(&conf, &parts.headers);
+            if maintenance::is_on() {
+                if conf.maintenance.store_requests
+                    && matches!(cache.store_request(&req).await, Ok(true))
+                {
+                    info!(
+                        "Stored {} request {} during maintenance",
+                        req.kind, req.request_id
+                    );
+                } else {
+                    info!(
+                        "Rejected {} request {} during maintenance",
+                        req.kind, req.request_id
+                    );
                 }
-            };
-            let (parts, body) = net_resp.into_parts();
-            let body = hyper::body::to_bytes(body).await?;
-            if parts.status.is_success() {
-                // the COPS call succeeded,
-                info!("Received success response ({:?}) from COPS", parts.status);
-                debug!("Received success response headers {:?}", parts.headers);
-                debug!(
-                    "Received success response body {}",
-                    std::str::from_utf8(&body).unwrap()
-                );
-                // cache the response
-                let resp = CResponse::from_network(&req, &body);
-                cache.store_response(&req, &resp).await;
-                // return the response
-                Ok(HResponse::from_parts(parts, Body::from(body)))
-            } else if let Some(resp) = cache.fetch_response(&req).await {
-                // COPS call failed, but we have a cached response to use
-                info!("Using previously cached response to request");
-                let net_resp = resp.to_network();
-                Ok(net_resp)
-            } else {
-                // COPS call failed, and no cache, so tell client
-                info!("Returning failure response ({:?}) from COPS", parts.status);
-                debug!("Returning failure response headers {:?}", parts.headers);
-                debug!(
-                    "Returning failure response body {}",
-                    std::str::from_utf8(&body).unwrap()
+                let resp = maintenance::response(&conf, &req.request_id);
+                return Ok(with_request_id(&conf, Some(&req.request_id), resp));
+            }
+            let resp = serve_valid_req(&req, &conf, &cache).await?;
+            Ok(with_request_id(&conf, Some(&req.request_id), resp))
+        }
+    }
+}
+
+/// Handle a request that COPS would accept, using COPS and the cache.
+async fn serve_valid_req(
+    req: &CRequest, conf: &Settings, cache: &Arc<Cache>,
+) -> Result<HResponse<Body>> {
+    let (mut resp, upstream) = if conf.proxy.complete_on_disconnect {
+        // answered in a task of its own, so a client going away doesn't
+        // cancel the COPS call, and its response is still cached
+        let (waiting, waiter) = oneshot::channel::<()>();
+        let (req, conf, cache) = (req.clone(), conf.clone(), Arc::clone(cache));
+        let answering = tokio::spawn(logging::with_request_context(async move {
+            let mut waiter = waiter;
+            let answer = answer_valid_req(&req, &conf, &cache).await;
+            if let Err(TryRecvError::Closed) = waiter.try_recv() {
+                info!(
+                    "Finished {} request {} after the client went away",
+                    req.kind, req.request_id
                 );
-                Ok(HResponse::from_parts(parts, Body::from(body)))
+            }
+            answer
+        }));
+        let answer = answering.await?;
+        drop(waiting);
+        answer?
+    } else {
+        answer_valid_req(req, conf, cache).await?
+    };
+    let name = &conf.proxy.upstream_latency_header;
+    if !name.is_empty() {
+        // the header name was checked when the configuration was validated
+        let name = HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name");
+        let value = HeaderValue::from_str(&upstream).expect("Invalid upstream marker");
+        resp.headers_mut().insert(name, value);
+    }
+    resp.extensions_mut().insert(access::Upstream(upstream));
+    Ok(resp)
+}
+
+/// Answer a request that COPS would accept, along with how long COPS
+/// took to respond (in milliseconds) or, if the answer didn't come from
+/// COPS, where it came from: `cache`, `offline`, or (when COPS couldn't
+/// be reached, or the cache couldn't store the request) `error`.
+async fn answer_valid_req(
+    req: &CRequest, conf: &Settings, cache: &Arc<Cache>,
+) -> Result<(HResponse<Body>, String)> {
+    logging::set_request_id(&req.request_id);
+    info!("Received request id: {}", &req.request_id);
+    metrics::count(&req.kind, Event::Request);
+    let stored = match cache.store_request(req).await {
+        Ok(stored) => stored,
+        Err(_) => {
+            match conf.cache.when_unavailable {
+                UnavailablePolicy::Reject => {
+                    warn!("Rejecting request with 503 response: cache is unavailable");
+                    note_served(req, Event::ServedFailure);
+                    let msg = "Proxy cache is unavailable: request not stored";
+                    let resp = error_response(conf, 503, msg, Some(&req.request_id));
+                    return Ok((resp, "error".to_string()));
+                }
+                UnavailablePolicy::Serve => {
+                    warn!("Serving request that won't be forwarded later: cache is unavailable");
+                    false
+                }
+            }
+        }
+    };
+    if req.prefer_cache {
+        if let Some(resp) = cache.fetch_response(req).await {
+            metrics::count(&resp.kind, Event::CacheHit);
+            info!("Using cached response to request, as the client prefers");
+            debug!("Cached response has timestamp {}", resp.timestamp);
+            note_served(req, Event::ServedPreferred);
+            return Ok((resp.to_network(), "cache".to_string()));
+        }
+        info!("Client prefers a cached response, but none is cached");
+    }
+    if cache.serves_stale() && !matches!(conf.proxy.mode, ProxyMode::Store) {
+        if let Some(resp) = cache.fetch_stale_response(req).await {
+            metrics::count(&resp.kind, Event::CacheHit);
+            info!("Using cached response to request while refreshing it");
+            debug!("Cached response has timestamp {}", resp.timestamp);
+            note_served(req, Event::ServedStale);
+            spawn_refresh(conf, Arc::clone(cache), req.clone());
+            return Ok((resp.to_network(), "cache".to_string()));
+        }
+    }
+    let offline = match conf.proxy.mode {
+        ProxyMode::Store => true,
+        ProxyMode::Auto => auto::is_offline(),
+        _ => false,
+    };
+    // whether the response is from COPS, rather than made by the proxy
+    let mut from_cops = false;
+    let mut upstream = "offline".to_string();
+    let net_resp = if offline {
+        debug!("{:?} mode is offline - not contacting COPS", conf.proxy.mode);
+        // like a COPS failure, this is replaced by a cached response to
+        // the request if there is one (unless the cache fails closed)
+        if stored {
+            proxy_offline_response(
+                conf,
+                req,
+                "Proxy is operating offline: request stored for later replay",
+            )
+        } else {
+            proxy_offline_response(
+                conf,
+                req,
+                if cache.is_unavailable() {
+                    "Proxy is operating offline: cache is unavailable, request not stored"
+                } else {
+                    "Proxy is operating offline: cache is full, request not stored"
+                },
+            )
+        }
+    } else {
+        // a response which won't be cached, and can't be replaced by a
+        // cached one, needn't be buffered
+        let stream = conf.proxy.stream_uncached && !cache.caches_responses(req);
+        let cached = revalidation_candidate(conf, cache, req).await;
+        let started = Instant::now();
+        let result = call_cops(conf, req, stream, cached.as_ref()).await;
+        upstream = match &result {
+            Ok(_) => started.elapsed().as_millis().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        if let (Ok(resp), Some(cached)) = (&result, cached) {
+            if resp.status() == hyper::StatusCode::NOT_MODIFIED {
+                metrics::count(&req.kind, Event::CopsSuccess);
+                info!("COPS confirmed the cached response is unchanged (304)");
+                cache.refresh_response(req).await;
+                note_served(req, Event::ServedLive);
+                return Ok((cached.to_network(), upstream));
+            }
+        }
+        match result {
+            Ok(resp) => {
+                if resp.status().is_success() {
+                    metrics::count(&req.kind, Event::CopsSuccess);
+                } else {
+                    metrics::count(&req.kind, Event::CopsFailure);
+                    let error = format!("COPS responded {}", resp.status());
+                    cache.record_call_error(req, &error).await;
+                }
+                from_cops = true;
+                if stream {
+                    info!("Streaming response ({:?}) from COPS", resp.status());
+                    if resp.status().is_success() {
+                        note_served(req, Event::ServedLive);
+                        return Ok((resp, upstream));
+                    }
+                    note_served(req, Event::ServedFailure);
+                    return Ok((status_remap::apply(conf, resp), upstream));
+                }
+                resp
+            }
+            Err(err) => {
+                metrics::count(&req.kind, Event::CopsError);
+                cache.record_call_error(req, &format!("{:#}", err)).await;
+                cops_failure_response(conf, req, err)
+            }
+        }
+    };
+    // COPS bodies were read in full by `call_cops`, and a COPS body that
+    // couldn't be read made the call an error, so this read can't fail
+    let (parts, body) = net_resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    if parts.status.is_success() {
+        // the COPS call succeeded,
+        info!("Received success response ({:?}) from COPS", parts.status);
+        debug!("Received success response headers {:?}", parts.headers);
+        debug!("Received success response body {}", logging::redact::body(&body));
+        // cache the response
+        let resp = CResponse::from_network(req, &body).with_validators(&parts.headers);
+        store_live_response(conf, cache, req, &resp).await;
+        // return the response
+        note_served(req, Event::ServedLive);
+        Ok((HResponse::from_parts(parts, Body::from(body)), upstream))
+    } else if conf.cache.fail_closed {
+        // COPS call failed, and we mustn't answer from the cache
+        info!("Returning failure response ({:?}) without using cache", parts.status);
+        note_served(
+            req,
+            if offline { Event::ServedOffline } else { Event::ServedFailure },
+        );
+        let resp = HResponse::from_parts(parts, Body::from(body));
+        Ok((remap_failure(conf, from_cops, resp), upstream))
+    } else if let Some(resp) = cache.fetch_response(req).await {
+        // COPS call failed, but we have a cached response to use
+        metrics::count(&resp.kind, Event::CacheHit);
+        info!("Using previously cached response to request");
+        debug!("Cached response has timestamp {}", resp.timestamp);
+        note_served(req, Event::ServedFallback);
+        let net_resp = resp.to_network();
+        Ok((net_resp, "cache".to_string()))
+    } else {
+        // COPS call failed, and no cache, so tell client
+        info!("Returning failure response ({:?}) from COPS", parts.status);
+        debug!("Returning failure response headers {:?}", parts.headers);
+        debug!("Returning failure response body {}", logging::redact::body(&body));
+        note_served(
+            req,
+            if offline { Event::ServedOffline } else { Event::ServedFailure },
+        );
+        let resp = HResponse::from_parts(parts, Body::from(body));
+        Ok((remap_failure(conf, from_cops, resp), upstream))
+    }
+}
+
+/// The cached response to a request which COPS can be asked to
+/// revalidate, if `cache.revalidate` is set and it has validators.
+async fn revalidation_candidate(
+    conf: &Settings, cache: &Cache, req: &CRequest,
+) -> Option<CResponse> {
+    if !conf.cache.revalidate {
+        return None;
+    }
+    cache.fetch_any_response(req).await.filter(CResponse::has_validators)
+}
+
+/// Remap the status of a failure being returned, if it came from COPS.
+fn remap_failure(
+    conf: &Settings, from_cops: bool, resp: HResponse<Body>,
+) -> HResponse<Body> {
+    if from_cops {
+        status_remap::apply(conf, resp)
+    } else {
+        resp
+    }
+}
+
+/// Cache a response from COPS which is being returned to a client,
+/// unless it's bigger than `cache.max_cacheable_response_bytes`.
+///
+/// Forwarded responses aren't capped: a stored request is only
+/// forwarded once its response is cached.
+async fn store_live_response(
+    conf: &Settings, cache: &Cache, req: &CRequest, resp: &CResponse,
+) {
+    let limit = conf.cache.max_cacheable_response_bytes;
+    if limit > 0 && resp.body.len() > limit {
+        info!(
+            "Returning {} response {} without caching it ({} bytes, more than {})",
+            req.kind,
+            req.request_id,
+            resp.body.len(),
+            limit
+        );
+        return;
+    }
+    cache.store_response(req, resp).await;
+}
+
+/// Count and log the way a request was answered.
+fn note_served(req: &CRequest, how: Event) {
+    let path = match how {
+        Event::ServedLive => "live",
+        Event::ServedStale => "stale",
+        Event::ServedFallback => "cache-fallback",
+        Event::ServedPreferred => "cache-preferred",
+        Event::ServedOffline => "offline",
+        _ => "failure",
+    };
+    info!("Served {} request {} (served={})", req.kind, req.request_id, path);
+    metrics::count(&req.kind, how);
+}
+
+/// Keys of the cached responses being refreshed in the background.
+static REFRESHING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// A key's place in `REFRESHING`, given up when the refresh ends.
+struct Refreshing(String);
+
+impl Drop for Refreshing {
+    fn drop(&mut self) {
+        REFRESHING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Refresh the cached response to a request from COPS in the background,
+/// unless a refresh of that response is already running.
+fn spawn_refresh(conf: &Settings, cache: Arc<Cache>, req: CRequest) {
+    let key = cache.response_key(&req);
+    if !REFRESHING.lock().unwrap().insert(key.clone()) {
+        debug!("Cached response to request is already being refreshed");
+        return;
+    }
+    let refreshing = Refreshing(key);
+    let conf = conf.clone();
+    tokio::spawn(logging::with_request_context(async move {
+        let _refreshing = refreshing;
+        logging::set_request_id(&req.request_id);
+        let cached = revalidation_candidate(&conf, &cache, &req).await;
+        match call_cops(&conf, &req, false, cached.as_ref()).await {
+            Ok(resp)
+                if resp.status() == hyper::StatusCode::NOT_MODIFIED
+                    && cached.is_some() =>
+            {
+                metrics::count(&req.kind, Event::CopsSuccess);
+                info!("Refreshed cached response: COPS confirmed it is unchanged (304)");
+                cache.refresh_response(&req).await;
+            }
+            Ok(resp) if resp.status().is_success() => {
+                metrics::count(&req.kind, Event::CopsSuccess);
+                let (parts, body) = resp.into_parts();
+                match hyper::body::to_bytes(body).await {
+                    Ok(body) => {
+                        info!("Refreshed cached response from COPS");
+                        let resp = CResponse::from_network(&req, &body)
+                            .with_validators(&parts.headers);
+                        store_live_response(&conf, &cache, &req, &resp).await;
+                    }
+                    Err(err) => error!("Failed to read refreshed response: {}", err),
+                }
+            }
+            Ok(resp) => {
+                metrics::count(&req.kind, Event::CopsFailure);
+                info!("Cached response not refreshed: COPS responded {}", resp.status());
+            }
+            Err(err) => {
+                metrics::count(&req.kind, Event::CopsError);
+                info!("Cached response not refreshed: {}", err);
             }
         }
+    }));
+}
+
+/// The outcome of forwarding stored requests.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ForwardSummary {
+    /// Requests which got a success response from COPS.
+    pub successes: u64,
+    /// Requests which got a failure response from COPS.
+    pub failures: u64,
+    /// Requests which got no response from COPS.
+    pub errors: u64,
+    /// Retries which weren't made because the retry budget was exhausted.
+    pub deferred: u64,
+    /// Requests which weren't forwarded because of a shutdown signal
+    /// or a failed check of the upstream proxy.
+    pub skipped: u64,
+}
+
+/// Forward the stored requests to COPS, storing their responses.
+///
+/// When `include_deferred` is set, requests which are backing off
+/// after earlier failures are forwarded as well.
+///
+/// A shutdown signal stops the run: no more requests are sent, and the
+/// one in flight is given `forward.shutdown_grace_secs` to be answered.
+///
+/// When the run is over, the configured webhook (if any) is notified.
+pub async fn forward_stored_requests(
+    conf: &Settings, cache: Arc<Cache>, include_deferred: bool,
+) -> ForwardSummary {
+    let (tx, rx) = watch::channel(false);
+    ctrl_c_handler(move || tx.send(true).unwrap_or(()));
+    forward_until_shutdown(conf, cache, include_deferred, rx).await
+}
+
+/// Forward the stored requests as `forward_stored_requests` does, but
+/// stop on the given shutdown signal rather than on Ctrl-C.
+pub async fn forward_until_shutdown(
+    conf: &Settings, cache: Arc<Cache>, include_deferred: bool, rx: watch::Receiver<bool>,
+) -> ForwardSummary {
+    let started_at = current_timestamp();
+    let started = Instant::now();
+    let summary = forward_requests(conf, &cache, include_deferred, rx).await;
+    if !conf.forward.webhook_url.expose().is_empty() {
+        let finished_at = current_timestamp();
+        let remaining = match cache.stats().await {
+            Ok(stats) => stats.pending_forwards,
+            Err(err) => {
+                error!("Can't count remaining requests: {:?}", err);
+                0
+            }
+        };
+        let completion = webhook::Completion::new(
+            conf,
+            &summary,
+            &started_at,
+            &finished_at,
+            started.elapsed(),
+            remaining,
+        );
+        webhook::notify(conf, &completion).await;
     }
+    summary
 }
 
-pub async fn forward_stored_requests(conf: &Settings, cache: Arc<Cache>) {
-    let requests = cache.fetch_forwarding_requests().await;
+async fn forward_requests(
+    conf: &Settings, cache: &Cache, include_deferred: bool, rx: watch::Receiver<bool>,
+) -> ForwardSummary {
+    let mut summary = ForwardSummary::default();
+    let mut audit = AuditCounts::default();
+    let requests = cache.fetch_forwarding_requests(include_deferred).await;
     if requests.is_empty() {
         eprintln!("No requests to forward.");
-        return;
+        return summary;
+    }
+    if let Err(err) = check_proxy(conf).await {
+        error!("Not forwarding: {:#}", err);
+        eprintln!(
+            "Proxy check failed, so {} request(s) were not forwarded: {:#}",
+            requests.len(),
+            err
+        );
+        summary.skipped = requests.len() as u64;
+        return summary;
     }
     eprintln!("Starting to forward {} request(s)...", requests.len());
-    let (mut successes, mut failures) = (0u64, 0u64);
-    for req in requests.iter() {
+    let mut previous: Option<&CRequest> = None;
+    for (index, req) in requests.iter().enumerate() {
+        if conf.forward.preserve_timing {
+            if let Some(previous) = previous {
+                tokio::select! {
+                    _ = wait_for_gap(conf, previous, req) => (),
+                    _ = wait_for_shutdown(rx.clone()) => (),
+                }
+            }
+            previous = Some(req);
+        }
+        if *rx.borrow() {
+            summary.skipped = (requests.len() - index) as u64;
+            break;
+        }
+        // a stored request that failed before is being retried
+        if req.attempts > 0 && !retry::allow(conf) {
+            info!("Deferring retry of stored {} request {}", req.kind, &req.request_id);
+            summary.deferred += 1;
+            continue;
+        }
         info!("Forwarding stored {} request {}", req.kind, &req.request_id);
-        match call_cops(conf, req).await {
+        let result = match call_cops_until_shutdown(conf, req, rx.clone()).await {
+            Some(result) => result,
+            None => {
+                // the request stays stored, just as if it hadn't been sent
+                summary.skipped = (requests.len() - index) as u64;
+                break;
+            }
+        };
+        match result {
             Ok(net_resp) => {
                 let (parts, body) = net_resp.into_parts();
                 let body = hyper::body::to_bytes(body).await.unwrap();
-                if parts.status.is_success() {
+                // an empty response that won't be cached is no use to replay
+                let empty = body.is_empty()
+                    && !matches!(conf.cache.empty_success_body, EmptyBodyPolicy::Cache);
+                if parts.status.is_success() && !empty {
                     // the COPS call succeeded,
+                    metrics::count(&req.kind, Event::CopsSuccess);
                     info!("Received success response ({:?}) from COPS", parts.status);
                     debug!("Received success response headers {:?}", parts.headers);
                     debug!(
                         "Received success response body {}",
-                        std::str::from_utf8(&body).unwrap()
+                        logging::redact::body(&body)
                     );
                     // cache the response
-                    let resp = CResponse::from_network(req, &body);
+                    let resp = CResponse::from_network(req, &body)
+                        .with_validators(&parts.headers);
+                    if conf.forward.audit_changes {
+                        let change = audit_change(cache, req, &resp).await;
+                        info!(
+                            "Audit: {} request {} got status {}, response {}",
+                            req.kind,
+                            &req.request_id,
+                            parts.status.as_u16(),
+                            change.as_str()
+                        );
+                        audit.count(change);
+                    }
                     cache.store_response(req, &resp).await;
-                    successes += 1;
+                    summary.successes += 1;
+                } else if parts.status.is_success() {
+                    metrics::count(&req.kind, Event::CopsSuccess);
+                    warn!(
+                        "Received empty response ({:?}) from COPS: will forward again",
+                        parts.status
+                    );
+                    summary.failures += 1;
+                    let error = format!("COPS responded {} with no body", parts.status);
+                    cache.record_forward_failure(req, &error).await;
                 } else {
                     // the COPS call failed
+                    metrics::count(&req.kind, Event::CopsFailure);
                     info!("Received failure response ({:?}) from COPS", parts.status);
                     debug!("Received failure response headers {:?}", parts.headers);
                     debug!(
                         "Received failure response body {}",
-                        std::str::from_utf8(&body).unwrap()
+                        logging::redact::body(&body)
                     );
-                    failures += 1;
+                    summary.failures += 1;
+                    let error = format!("COPS responded {}", parts.status);
+                    cache.record_forward_failure(req, &error).await;
                 }
             }
             Err(err) => {
-                error!("No response received from COPS: {}", err)
+                metrics::count(&req.kind, Event::CopsError);
+                error!("No response received from COPS: {}", err);
+                summary.errors += 1;
+                cache.record_forward_failure(req, &format!("{:#}", err)).await;
             }
         };
     }
     eprintln!(
         "Received {} success response(s) and {} failure response(s).",
-        successes, failures
+        summary.successes, summary.failures
     );
+    if conf.forward.audit_changes {
+        eprintln!(
+            "Of the success responses, {} were new, {} changed and {} unchanged.",
+            audit.new, audit.changed, audit.same
+        );
+    }
+    if summary.errors > 0 {
+        eprintln!("No response was received for {} request(s).", summary.errors);
+    }
+    if summary.deferred > 0 {
+        eprintln!(
+            "Retry of {} request(s) was deferred (retry budget).",
+            summary.deferred
+        );
+    }
+    if summary.skipped > 0 {
+        let forwarded = summary.successes + summary.failures + summary.errors;
+        warn!(
+            "Forwarding interrupted by shutdown after {} of {} request(s)",
+            forwarded,
+            requests.len()
+        );
+        eprintln!(
+            "Shutdown interrupted forwarding: {} request(s) were not forwarded.",
+            summary.skipped
+        );
+    }
+    summary
+}
+
+/// Check that COPS can be reached through the upstream proxy (if one is
+/// used and `network.proxy_precheck` is set), and that the proxy accepts
+/// our credentials.  Any response from COPS will do.
+async fn check_proxy(conf: &Settings) -> Result<()> {
+    if !conf.network.use_proxy || !conf.network.proxy_precheck {
+        return Ok(());
+    }
+    let proxy = format!("{}:{}", conf.network.proxy_host, conf.network.proxy_port);
+    let status = probe_cops(conf)
+        .await
+        .wrap_err_with(|| format!("Can't reach COPS via proxy {}", proxy))?;
+    if status == hyper::StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        return Err(eyre!("Proxy {} didn't accept our credentials ({})", proxy, status));
+    }
+    debug!("Proxy check got status {} from COPS", status);
+    Ok(())
+}
+
+/// How a forwarded request's response compares with the one cached before.
+#[derive(Clone, Copy)]
+enum Change {
+    New,
+    Changed,
+    Same,
+}
+
+impl Change {
+    fn as_str(self) -> &'static str {
+        match self {
+            Change::New => "new",
+            Change::Changed => "changed",
+            Change::Same => "same",
+        }
+    }
+}
+
+#[derive(Default)]
+struct AuditCounts {
+    new: u64,
+    changed: u64,
+    same: u64,
+}
+
+impl AuditCounts {
+    fn count(&mut self, change: Change) {
+        match change {
+            Change::New => self.new += 1,
+            Change::Changed => self.changed += 1,
+            Change::Same => self.same += 1,
+        }
+    }
+}
+
+async fn audit_change(cache: &Cache, req: &CRequest, resp: &CResponse) -> Change {
+    match cache.fetch_any_response(req).await {
+        None => Change::New,
+        Some(old) if old.body == resp.body => Change::Same,
+        Some(_) => Change::Changed,
+    }
+}
+
+/// Call COPS for a stored request.  If a shutdown signal arrives, the
+/// call has `forward.shutdown_grace_secs` to finish, and is abandoned
+/// (giving `None`) if it doesn't.
+async fn call_cops_until_shutdown(
+    conf: &Settings, req: &CRequest, rx: watch::Receiver<bool>,
+) -> Option<Result<HResponse<Body>>> {
+    let call = call_cops(conf, req, false, None);
+    tokio::pin!(call);
+    tokio::select! {
+        result = &mut call => return Some(result),
+        _ = wait_for_shutdown(rx) => (),
+    }
+    let grace = Duration::from_secs(conf.forward.shutdown_grace_secs);
+    info!("Waiting up to {:?} for request {} to be answered", grace, req.request_id);
+    match tokio::time::timeout(grace, call).await {
+        Ok(result) => Some(result),
+        Err(_) => {
+            warn!("Abandoned request {}: no response before shutdown", req.request_id);
+            None
+        }
+    }
 }
 
-async fn call_cops(conf: &Settings, req: &CRequest) -> Result<HResponse<Body>> {
+/// Pause for the (scaled and capped) time that passed between the
+/// arrival of two stored requests.
+async fn wait_for_gap(conf: &Settings, earlier: &CRequest, later: &CRequest) {
+    let gap = match millis_between(&earlier.timestamp, &later.timestamp) {
+        Some(ms) if ms > 0 => ms as f64 * conf.forward.timing_scale,
+        _ => return,
+    };
+    let mut gap = Duration::from_millis(gap as u64);
+    let cap = Duration::from_secs(conf.forward.max_gap_secs);
+    if conf.forward.max_gap_secs > 0 && gap > cap {
+        debug!(
+            "Capping gap of {:?} before request {} to {:?}",
+            gap, later.request_id, cap
+        );
+        gap = cap;
+    }
+    debug!("Waiting {:?} before forwarding request {}", gap, later.request_id);
+    tokio::time::sleep(gap).await;
+}
+
+/// Send a request to COPS, retrying if there's no response or a server
+/// error, as many times as configured and the retry budget allows.
+///
+/// With `stream` set, the body of the response is passed on as it
+/// arrives, rather than being read in full.  With `cached` given, the
+/// request is conditional on that response having changed, so COPS may
+/// answer 304.
+async fn call_cops(
+    conf: &Settings, req: &CRequest, stream: bool, cached: Option<&CResponse>,
+) -> Result<HResponse<Body>> {
+    let mut attempt = 0u32;
+    loop {
+        let result = call_cops_once(conf, req, stream, cached).await;
+        let error = match &result {
+            Ok(resp) if resp.status().is_server_error() => {
+                Some(format!("COPS responded with {}", resp.status()))
+            }
+            Ok(_) => None,
+            Err(err) => Some(format!("{:#}", err)),
+        };
+        let failed = error.is_some();
+        auto::record_call(conf, error.is_none());
+        health::record(conf, error);
+        if !failed || attempt >= conf.network.cops_retries {
+            return result;
+        }
+        if !conf.kinds.policy(&req.kind).retry {
+            info!(
+                "Not retrying {} request {} to COPS ({} requests aren't retried)",
+                req.kind, req.request_id, req.kind
+            );
+            return result;
+        }
+        if !retry::allow(conf) {
+            return result;
+        }
+        attempt += 1;
+        let delay = Duration::from_millis(RETRY_DELAY_MS << (attempt - 1).min(6));
+        info!(
+            "Retrying {} request {} to COPS in {:?} (retry {} of {})",
+            req.kind, req.request_id, delay, attempt, conf.network.cops_retries
+        );
+        metrics::count_retry();
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The wait before the first retry of a COPS call, doubling thereafter.
+const RETRY_DELAY_MS: u64 = 250;
+
+async fn call_cops_once(
+    conf: &Settings, req: &CRequest, stream: bool, cached: Option<&CResponse>,
+) -> Result<HResponse<Body>> {
+    let (cops_scheme, cops_host) = cops_endpoint(conf);
+    info!(
+        "Forwarding request {} to COPS at {}://{}",
+        req.request_id, cops_scheme, cops_host
+    );
+    let mut net_req = req.to_network(cops_scheme, &cops_host);
+    if let Some(cached) = cached {
+        debug!("Asking COPS whether the cached response has changed");
+        cached.add_conditions(net_req.headers_mut());
+    }
+    let timeout = cops_timeout(conf, req);
+    let started = Instant::now();
+    let response = send_to_cops(conf, net_req, Duration::from_millis(timeout)).await?;
+    metrics::observe_latency(&req.kind, started.elapsed());
+    let (parts, body) = response.into_parts();
+    if parts.status.is_success() {
+        check_content_type(conf, &parts.headers)?;
+    }
+    if stream {
+        let body = limited_stream(body, conf.proxy.max_response_bytes);
+        return Ok(HResponse::from_parts(parts, body));
+    }
+    // buffer the body here, so an oversize response is never held in memory
+    let body = read_limited_body(body, conf.proxy.max_response_bytes).await?;
+    if parts.status.is_success() && body.is_empty() {
+        if let EmptyBodyPolicy::Fail = conf.cache.empty_success_body {
+            return Err(eyre!("COPS responded with {} and an empty body", parts.status));
+        }
+    }
+    Ok(HResponse::from_parts(parts, Body::from(body)))
+}
+
+/// Make sure a successful COPS response has one of the configured
+/// content types, so that (say) a gateway's HTML page isn't cached.
+fn check_content_type(conf: &Settings, headers: &hyper::HeaderMap) -> Result<()> {
+    let allowed = &conf.cache.content_types;
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let content_type = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if allowed.iter().any(|t| t.eq_ignore_ascii_case(media_type)) {
+        Ok(())
+    } else {
+        let shown = if content_type.is_empty() { "(none)" } else { content_type };
+        Err(eyre!("COPS responded with unexpected content type: {}", shown))
+    }
+}
+
+/// The scheme and host (with any port) of the configured COPS server.
+fn cops_endpoint(conf: &Settings) -> (&'static str, String) {
     let cops_uri =
         conf.proxy.remote_host.parse::<Uri>().unwrap_or_else(|_| {
             panic!("failed to parse uri: {}", conf.proxy.remote_host)
@@ -167,95 +1063,595 @@ async fn call_cops(conf: &Settings, req: &CRequest) -> Result<HResponse<Body>> {
         }
         None => String::from(cops_uri.host().unwrap()),
     };
+    (cops_scheme, cops_host)
+}
 
-    info!(
-        "Forwarding request {} to COPS at {}://{}",
-        req.request_id, cops_scheme, cops_host
-    );
-    let mut net_req = req.to_network(cops_scheme, &cops_host);
-    let request = if conf.network.use_proxy {
-        // proxy
-        let proxy_url = format!(
-            "{}://{}:{}",
-            "http", conf.network.proxy_host, conf.network.proxy_port
-        );
-        info!("Connecting via proxy: {}", proxy_url);
-        let proxy = {
+/// Check whether COPS can be reached.  Any response counts, whatever
+/// its status, since it means the connection to COPS works.
+///
+/// Probes are sent to `network.probe_path` and give up after
+/// `network.probe_timeout_ms`, whatever the timeout of licensing calls.
+/// The outcome of the last one is kept for `/status`.
+pub async fn probe_cops(conf: &Settings) -> Result<hyper::StatusCode> {
+    let started = Instant::now();
+    let result = send_probe(conf).await;
+    let probe = ProbeResult {
+        at: current_timestamp(),
+        ok: result.is_ok(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        status: result.as_ref().ok().map(|status| status.as_u16()),
+        error: result.as_ref().err().map(|err| format!("{:#}", err)),
+    };
+    health::record(conf, probe.error.clone());
+    *LAST_PROBE.lock().unwrap() = Some(probe);
+    result
+}
+
+async fn send_probe(conf: &Settings) -> Result<hyper::StatusCode> {
+    let (cops_scheme, cops_host) = cops_endpoint(conf);
+    let uri = format!("{}://{}{}", cops_scheme, cops_host, conf.network.probe_path);
+    let builder = match conf.network.probe_method {
+        ProbeMethod::Get => HRequest::get(uri),
+        ProbeMethod::Head => HRequest::head(uri),
+    };
+    let net_req = builder
+        .header("user-agent", agent())
+        .body(Body::empty())
+        .wrap_err("Invalid COPS probe request")?;
+    let timeout = Duration::from_millis(conf.network.probe_timeout_ms);
+    let resp = send_to_cops(conf, net_req, timeout).await?;
+    Ok(resp.status())
+}
+
+/// The outcome of a probe of COPS.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeResult {
+    pub at: String,
+    pub ok: bool,
+    pub latency_ms: u64,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+static LAST_PROBE: Lazy<Mutex<Option<ProbeResult>>> = Lazy::new(Default::default);
+
+/// The outcome of the last probe of COPS, if there has been one.
+pub fn last_probe() -> Option<ProbeResult> {
+    LAST_PROBE.lock().unwrap().clone()
+}
+
+/// The clients used to call COPS.  They are made on first use and
+/// then shared by all calls, so that connections to COPS are pooled.
+///
+/// With `network.pool_max_connection_age_secs` set, they are replaced
+/// by new ones (with empty pools) once they are that old, so no pooled
+/// connection is used for longer than that.  The old clients, and their
+/// connections, go away when the calls still using them finish.
+struct CopsClients {
+    direct: Client<CopsConnector>,
+    proxied: Option<(ProxyConnector<ProxyHopConnector>, ProxiedClient)>,
+}
+
+type ProxiedClient = Client<ProxyConnector<ProxyHopConnector>>;
+
+/// The current clients, with when they were made.
+type CurrentClients = Option<(Instant, Arc<CopsClients>)>;
+
+static CLIENTS: Lazy<Mutex<CurrentClients>> = Lazy::new(Default::default);
+
+fn cops_clients(conf: &Settings) -> Result<Arc<CopsClients>> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some((made, current)) = clients.as_ref() {
+        match conf.network.pool_max_connection_age_secs {
+            0 => return Ok(Arc::clone(current)),
+            secs if made.elapsed() < Duration::from_secs(secs) => {
+                return Ok(Arc::clone(current))
+            }
+            secs => debug!("Retiring pooled COPS connections after {} seconds", secs),
+        }
+    }
+    let fresh = Arc::new(CopsClients::new(conf)?);
+    *clients = Some((Instant::now(), Arc::clone(&fresh)));
+    Ok(fresh)
+}
+
+impl CopsClients {
+    fn new(conf: &Settings) -> Result<CopsClients> {
+        let direct =
+            client_builder(conf).build::<_, hyper::Body>(CopsConnector::new(conf)?);
+        let proxied = if conf.network.use_proxy {
+            let proxy_url = format!(
+                "{}://{}:{}",
+                "http", conf.network.proxy_host, conf.network.proxy_port
+            );
+            info!("Connecting via proxy: {}", proxy_url);
             let proxy_uri =
                 proxy_url.parse().wrap_err("Cannot parse upstream proxy URL")?;
             let mut proxy = Proxy::new(Intercept::All, proxy_uri);
             if conf.network.use_basic_auth {
                 proxy.set_authorization(Authorization::basic(
                     &conf.network.proxy_username,
-                    &conf.network.proxy_password,
+                    conf.network.proxy_password.expose(),
                 ));
             }
-            let connector = HttpConnector::new();
-            ProxyConnector::from_proxy(connector, proxy)
-                .wrap_err("Failed to create proxy connector")?
+            let connector =
+                ProxyConnector::from_proxy(ProxyHopConnector::new(conf), proxy)
+                    .wrap_err("Failed to create proxy connector")?;
+            let client = client_builder(conf).build(connector.clone());
+            Some((connector, client))
+        } else {
+            None
         };
+        Ok(CopsClients { direct, proxied })
+    }
+}
+
+/// Send a request to COPS, over the configured connection, giving up
+/// if there's no response within `timeout`.
+async fn send_to_cops(
+    conf: &Settings, mut net_req: HRequest<Body>, timeout: Duration,
+) -> Result<HResponse<Body>> {
+    if let Some(host) = &conf.proxy.cops_host_header {
+        debug!("Sending host header {} to COPS", host);
+        let host = HeaderValue::from_str(host).wrap_err("Invalid COPS host header")?;
+        net_req.headers_mut().insert(HOST, host);
+    }
+    let clients = cops_clients(conf)?;
+    let request = if let Some((proxy, client)) = &clients.proxied {
         // add any needed proxy headers (authorization, typically) to the request
         if let Some(headers) = proxy.http_headers(net_req.uri()) {
-            net_req.headers_mut().extend(headers.clone().into_iter());
+            net_req.headers_mut().extend(headers.clone());
         }
-        let client = Client::builder().build(proxy);
         client.request(net_req)
     } else {
-        // no proxy
-        if cops_scheme == "https" {
-            let https = HttpsConnector::new();
-            let client = Client::builder().build::<_, hyper::Body>(https);
-            client.request(net_req)
-        } else {
-            let client = Client::new();
-            client.request(net_req)
+        clients.direct.request(net_req)
+    };
+    let via = if clients.proxied.is_some() { " via the proxy" } else { "" };
+    let response = match tokio::time::timeout(timeout, request).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => match ProxyHopError::cause_of(&err) {
+            Some(hop) => return Err(eyre!("{}", hop)),
+            None => {
+                return Err(Report::new(err).wrap_err(format!("Network error{}", via)))
+            }
+        },
+        Err(_) => {
+            return Err(eyre!(
+                "Timeout - no response received{} in {} milliseconds",
+                via,
+                timeout.as_millis()
+            ))
         }
     };
-    let timeout = 59000u64; // just under 60 seconds, which is typical client timeout
+    if let Some(info) = response.extensions().get::<ConnectionInfo>() {
+        note_connection(info);
+    }
+    Ok(response)
+}
+
+/// Log and count whether a COPS call used a new or a pooled connection.
+fn note_connection(info: &ConnectionInfo) {
+    if info.mark_used() {
+        debug!("COPS call reused pooled connection #{}", info.id);
+        metrics::count_cops_connection(true, None);
+    } else {
+        match info.tls_handshake {
+            Some(handshake) => debug!(
+                "COPS call opened new connection #{} (TLS handshake took {:?})",
+                info.id, handshake
+            ),
+            None => debug!("COPS call opened new connection #{}", info.id),
+        }
+        metrics::count_cops_connection(false, info.tls_handshake);
+    }
+}
+
+/// A builder for clients which talk to COPS with the configured HTTP
+/// version and connection pooling.
+fn client_builder(conf: &Settings) -> client::Builder {
+    let mut builder = Client::builder();
+    if let Http2Mode::PriorKnowledge = conf.network.cops_http2 {
+        builder.http2_only(true);
+    }
+    if let Some(max) = conf.network.pool_max_idle_per_host {
+        builder.pool_max_idle_per_host(max);
+    }
+    builder.pool_idle_timeout(match conf.network.pool_idle_timeout_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    });
+    // a request whose pooled connection was closed before it could be
+    // sent is sent again on a new one
+    builder.retry_canceled_requests(true);
+    builder
+}
+
+/// Read an entire body, giving up as soon as it exceeds `limit` bytes.
+///
+/// A `limit` of 0 means there is no limit.
+async fn read_limited_body(mut body: Body, limit: usize) -> Result<Vec<u8>> {
+    if limit > 0 && body.size_hint().lower() > limit as u64 {
+        return Err(eyre!(
+            "Response size ({} bytes) exceeds the limit of {} bytes",
+            body.size_hint().lower(),
+            limit
+        ));
+    }
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.wrap_err(format!(
+            "Network error reading response body (after {} bytes)",
+            buf.len()
+        ))?;
+        if limit > 0 && buf.len() + chunk.len() > limit {
+            return Err(eyre!("Response body exceeds the limit of {} bytes", limit));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+/// The COPS timeout a client asked for in the configured header, if any.
+fn requested_timeout(conf: &Settings, headers: &hyper::HeaderMap) -> Option<u64> {
+    let name = conf.network.timeout_header.as_str();
+    if name.is_empty() {
+        return None;
+    }
+    let value = headers.get(name)?;
+    match value.to_str().ok().and_then(|s| s.trim().parse::<u64>().ok()) {
+        Some(ms) if ms > 0 => Some(ms),
+        _ => {
+            warn!("Ignoring invalid {} header: {:?}", name, value);
+            None
+        }
+    }
+}
+
+/// Whether a client asked, in the configured header, to be answered from
+/// the cache if it can be.  The header only counts if the cache may be
+/// used for answers at all (it doesn't fail closed).
+fn prefers_cache(conf: &Settings, headers: &hyper::HeaderMap) -> bool {
+    let name = conf.cache.prefer_cache_header.as_str();
+    !name.is_empty() && !conf.cache.fail_closed && headers.contains_key(name)
+}
+
+/// The milliseconds to wait for COPS to answer a request: as configured
+/// (by default, just under the typical client timeout of 60 seconds),
+/// or as the client asked, up to the configured maximum.
+fn cops_timeout(conf: &Settings, req: &CRequest) -> u64 {
+    let timeout = conf.network.cops_timeout_ms;
     #[cfg(debug_assertions)]
     let timeout = match std::env::var("FRL_PROXY_TIMEOUT") {
         Ok(s) => s.parse::<u64>().unwrap(),
         Err(_) => timeout,
     };
-    match tokio::time::timeout(Duration::from_millis(timeout), request).await {
-        Ok(response) => response.wrap_err("Network error"),
-        Err(_) => {
-            Err(eyre!("Timeout - no response received in {} milliseconds", timeout))
+    match req.timeout_ms {
+        Some(asked) => {
+            let timeout = asked.min(conf.network.max_timeout_ms);
+            info!(
+                "Using a COPS timeout of {} ms for request {} (it asked for {} ms)",
+                timeout, req.request_id, asked
+            );
+            timeout
         }
+        None => timeout,
     }
 }
 
-fn bad_request_response(err: &BadRequest) -> HResponse<Body> {
-    info!("Rejecting request with 400 response: {}", err.reason);
-    let body = serde_json::json!({"statusCode": 400, "message": err.reason});
+/// Give a request with no `X-Request-Id` the id in its correlation
+/// header or, failing that, a generated id, if so configured.
+fn supply_request_id(conf: &Settings, headers: &mut hyper::HeaderMap) {
+    if headers.contains_key("x-request-id") {
+        return;
+    }
+    let correlated = match conf.proxy.correlation_header.as_str() {
+        "" => None,
+        name => headers.get(name).filter(|v| !v.is_empty()).cloned(),
+    };
+    let id = match correlated {
+        Some(id) => {
+            debug!("Using {} header as the request id", conf.proxy.correlation_header);
+            id
+        }
+        None if conf.proxy.generate_request_ids => {
+            let id = generate_request_id();
+            info!("Request has no id, so it was given id {}", id);
+            HeaderValue::from_str(&id).expect("Invalid generated request id")
+        }
+        None => return,
+    };
+    headers.insert("x-request-id", id);
+}
+
+/// A random (version 4) UUID.
+fn generate_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Pass on a body as it arrives, cutting it off as soon as it exceeds
+/// `limit` bytes (0 means there is no limit).
+///
+/// The client sees a cut off body as a broken connection.
+fn limited_stream(mut body: Body, limit: usize) -> Body {
+    if limit == 0 {
+        return body;
+    }
+    let chunks = async_stream::try_stream! {
+        let mut total = 0usize;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            total += chunk.len();
+            if total > limit {
+                error!("Streamed response body exceeds the limit of {} bytes", limit);
+                Err(eyre!("Response body exceeds the limit of {} bytes", limit))?;
+            }
+            yield chunk;
+        }
+    };
+    let chunks: Pin<Box<dyn futures::Stream<Item = Result<Bytes>> + Send>> =
+        Box::pin(chunks);
+    Body::wrap_stream(chunks)
+}
+
+/// The request id a client sent, if it sent one.
+fn incoming_request_id(headers: &hyper::HeaderMap) -> Option<&str> {
+    headers.get("x-request-id").and_then(|v| v.to_str().ok())
+}
+
+/// Add the header carrying the request id to a response, if configured.
+fn with_request_id(
+    conf: &Settings, request_id: Option<&str>, mut resp: HResponse<Body>,
+) -> HResponse<Body> {
+    if !conf.proxy.send_request_id {
+        return resp;
+    }
+    if let Some(Ok(val)) = request_id.map(HeaderValue::from_str) {
+        // the header name was checked when the configuration was validated
+        let name = HeaderName::from_bytes(conf.proxy.request_id_header.as_bytes())
+            .expect("Invalid request id header name");
+        resp.headers_mut().insert(name, val);
+    }
+    resp
+}
+
+/// The content type and body of an error response made by the proxy.
+///
+/// The body is `{"statusCode": ..., "message": ...}`, or, when
+/// `proxy.problem_details` is set, an RFC 7807 problem whose `instance`
+/// is the request id (when it's known).
+fn error_body<'a>(
+    conf: &'a Settings, status: u16, message: &str, request_id: Option<&str>,
+) -> (&'a str, String) {
+    if !conf.proxy.problem_details {
+        let body = serde_json::json!({"statusCode": status, "message": message});
+        return (&conf.proxy.error_content_type, body.to_string());
+    }
+    let title = hyper::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    let mut body = serde_json::json!({
+        "type": "about:blank",
+        "title": title,
+        "status": status,
+        "detail": message,
+    });
+    if let Some(id) = request_id {
+        body["instance"] = serde_json::Value::from(id);
+    }
+    ("application/problem+json", body.to_string())
+}
+
+/// An error response made by the proxy, with a body from `error_body`.
+fn error_response(
+    conf: &Settings, status: u16, message: &str, request_id: Option<&str>,
+) -> HResponse<Body> {
+    let (content_type, body) = error_body(conf, status, message, request_id);
     HResponse::builder()
-        .status(400)
-        .header("content-type", "application/json;charset=UTF-8")
+        .status(status)
+        .header("content-type", content_type)
         .header("server", agent())
-        .body(Body::from(body.to_string()))
+        .body(Body::from(body))
         .unwrap()
 }
 
-fn cops_failure_response(err: Report) -> HResponse<Body> {
+fn bad_request_response(
+    conf: &Settings, err: &BadRequest, request_id: Option<&str>,
+) -> HResponse<Body> {
+    info!("Rejecting request with 400 response: {}", err.reason);
+    error_response(conf, 400, &err.reason, request_id)
+}
+
+fn unauthorized_response(
+    conf: &Settings, reason: &str, request_id: Option<&str>,
+) -> HResponse<Body> {
+    warn!("Rejecting request with 401 response: {}", reason);
+    let msg = format!("Invalid request signature: {}", reason);
+    error_response(conf, 401, &msg, request_id)
+}
+
+fn cops_failure_response(
+    conf: &Settings, req: &CRequest, err: Report,
+) -> HResponse<Body> {
     let msg = format!("Failed to get a response from COPS: {}", err);
     error!("{}", msg);
-    let body = serde_json::json!({"statusCode": 502, "message": msg});
-    HResponse::builder()
-        .status(502)
-        .header("content-type", "application/json;charset=UTF-8")
-        .header("server", agent())
-        .body(Body::from(body.to_string()))
-        .unwrap()
+    error_response(conf, 502, &msg, Some(&req.request_id))
 }
 
-fn proxy_offline_response() -> HResponse<Body> {
-    let msg = "Proxy is operating offline: request stored for later replay";
+fn proxy_offline_response(conf: &Settings, req: &CRequest, msg: &str) -> HResponse<Body> {
     debug!("{}", msg);
-    let body = serde_json::json!({"statusCode": 502, "message": msg});
-    HResponse::builder()
-        .status(502)
-        .header("content-type", "application/json;charset=UTF-8")
-        .header("server", agent())
-        .body(Body::from(body.to_string()))
-        .unwrap()
+    error_response(conf, 502, msg, Some(&req.request_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cops::Kind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A path for a cache db of a test's own, with no db there yet.
+    fn temp_db(name: &str) -> String {
+        let file = format!("frl-proxy-{}-{}.sqlite", name, std::process::id());
+        let path = std::env::temp_dir().join(file).to_string_lossy().into_owned();
+        for suffix in ["", "-shm", "-wal"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+        path
+    }
+
+    /// A stub COPS which reads each request in full and answers it with
+    /// `response`, closing the connection after it.  Returns its URL.
+    async fn stub_cops(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                loop {
+                    let n = stream.read(&mut chunk).await.unwrap_or(0);
+                    buf.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&buf).to_ascii_lowercase();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text
+                            .lines()
+                            .find_map(|l| l.strip_prefix("content-length:"))
+                            .and_then(|v| v.trim().parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if n == 0 || buf.len() >= end + 4 + length {
+                            break;
+                        }
+                    } else if n == 0 {
+                        break;
+                    }
+                }
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    fn activation() -> CRequest {
+        CRequest {
+            kind: Kind::Activation,
+            request_id: "req1".to_string(),
+            api_key: "key".to_string(),
+            session_id: "session".to_string(),
+            package_id: "npd1".to_string(),
+            device_id: "device1".to_string(),
+            os_user_id: "user1".to_string(),
+            app_id: "app1".to_string(),
+            ngl_version: "1.0".to_string(),
+            timestamp: current_timestamp(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_client_body_gets_400() {
+        let conf = Settings::read_config(&[]).unwrap();
+        let (mut sender, body) = Body::channel();
+        sender.try_send_data(Bytes::from("{\"npdId\":")).unwrap();
+        sender.abort();
+        let req = HRequest::post("/asnp/frl_connected/values/v2")
+            .header("x-request-id", "req1")
+            .body(body)
+            .unwrap();
+        let cache = Arc::new(Cache::default());
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let resp = answer_req(req, conf, cache, peer).await.unwrap();
+        assert_eq!(resp.status(), 400);
+    }
+
+    // the cache uses block_in_place, which needs the multi-threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn truncated_cops_response_falls_back_to_cache() {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.proxy.remote_host = stub_cops(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+             content-length: 100\r\n\r\n{\"truncated\":",
+        )
+        .await;
+        conf.cache.db_path = temp_db("truncated-cops");
+        let cache = Cache::from(&conf, true).await.unwrap();
+        let req = activation();
+        let cached = CResponse::from_network(&req, b"{\"cached\":true}");
+        cache.store_response(&req, &cached).await;
+        let (resp, upstream) = answer_valid_req(&req, &conf, &cache).await.unwrap();
+        assert_eq!(upstream, "cache");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"{\"cached\":true}");
+        cache.close().await;
+    }
+
+    const EMPTY_SUCCESS: &str = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                                 content-length: 0\r\nconnection: close\r\n\r\n";
+
+    /// Settings for a stub COPS which answers with an empty success,
+    /// under the given policy for those.
+    async fn empty_body_conf(name: &str, policy: EmptyBodyPolicy) -> Settings {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.proxy.remote_host = stub_cops(EMPTY_SUCCESS).await;
+        conf.cache.db_path = temp_db(name);
+        conf.cache.empty_success_body = policy;
+        conf
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_body_passed_is_not_cached() {
+        let conf = empty_body_conf("empty-pass", EmptyBodyPolicy::Pass).await;
+        let cache = Arc::new(Cache::from(&conf, true).await.unwrap());
+        let req = activation();
+        let (resp, upstream) = answer_valid_req(&req, &conf, &cache).await.unwrap();
+        // the upstream of a response from COPS is how long it took
+        assert!(upstream.parse::<u128>().is_ok());
+        assert_eq!(resp.status(), 200);
+        assert!(hyper::body::to_bytes(resp.into_body()).await.unwrap().is_empty());
+        assert!(cache.fetch_any_response(&req).await.is_none());
+        cache.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_body_is_cached_when_configured() {
+        let conf = empty_body_conf("empty-cache", EmptyBodyPolicy::Cache).await;
+        let cache = Arc::new(Cache::from(&conf, true).await.unwrap());
+        let req = activation();
+        let (resp, upstream) = answer_valid_req(&req, &conf, &cache).await.unwrap();
+        assert!(upstream.parse::<u128>().is_ok());
+        assert_eq!(resp.status(), 200);
+        let cached = cache.fetch_any_response(&req).await.unwrap();
+        assert!(cached.body.is_empty());
+        cache.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_body_failure_is_forwarded_again() {
+        let conf = empty_body_conf("empty-fail", EmptyBodyPolicy::Fail).await;
+        let cache = Arc::new(Cache::from(&conf, true).await.unwrap());
+        let req = activation();
+        assert!(cache.store_request(&req).await.unwrap());
+        let (_tx, rx) = watch::channel(false);
+        let summary = forward_until_shutdown(&conf, Arc::clone(&cache), true, rx).await;
+        // the call itself failed, so no response was received
+        assert_eq!(summary.successes, 0);
+        assert_eq!(summary.errors, 1);
+        assert!(cache.fetch_any_response(&req).await.is_none());
+        let pending = cache.fetch_forwarding_requests(true).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].request_id, req.request_id);
+        assert_eq!(pending[0].attempts, 1);
+        cache.close().await;
+    }
 }
@@ -0,0 +1,576 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! The admin endpoints, served on a listener of their own.
+//!
+//! These are for operators, not licensing clients, so they are never
+//! served by the proxy listeners.  The admin listener binds to
+//! `admin.host` (localhost by default) and serves:
+//!
+//! - `/status`: the proxy mode, cache summary, auto-mode state, the
+//!   outcome of the last probe of COPS, and how long the oldest request
+//!   waiting to be forwarded has waited, as JSON;
+//! - `/metrics`: the metrics, in the Prometheus text format;
+//! - `/version`: the proxy version, as JSON;
+//! - `/readyz`: whether the proxy is healthy, as JSON with a status of
+//!   200 or (when it isn't) 503;
+//! - `/maintenance`: whether the proxy is in maintenance mode, as JSON;
+//!   a `POST` turns maintenance mode on, and a `DELETE` turns it off;
+//! - `/admin/mode`: the mode the proxy is running in, as JSON; a `POST` of
+//!   `{"mode": "store"}` (say) changes it, until a `DELETE` or a restart
+//!   (see the `mode` module);
+//! - `/admin/forward`: a `POST` starts forwarding the stored requests,
+//!   and responds (with status 202) with the id of the run;
+//! - `/admin/forward/<run-id>`: the progress or outcome of a forwarding run,
+//!   as JSON;
+//! - `/requests`: the stored requests which haven't been forwarded, as
+//!   JSON, each with its cache key, its failed attempts, and why the
+//!   last call to COPS for it failed;
+//! - `/admin/cache/<key>`: a `DELETE` removes the cached responses with that
+//!   (percent-encoded) key, as listed by `/requests` and the `list` and
+//!   `pinned` commands, and with `?requests=true` the stored requests
+//!   with it too.  It responds with the numbers removed, and with a
+//!   404 if there were none.
+//!
+//! Every endpoint is served both with and without the `/admin` prefix,
+//! so `/admin/status` is `/status` and `/forward` is `/admin/forward`.
+//!
+//! Only one forwarding run happens at a time: a `POST` to
+//! `/admin/forward` while a run is in progress gets a 409 with the id
+//! of that run.  A run's report looks like this, and `summary` is null
+//! until it's done:
+//!
+//! ```json
+//! {
+//!   "run_id": 3,
+//!   "status": "complete",
+//!   "started_at": "2021-07-01T10:15:00.123+0000",
+//!   "finished_at": "2021-07-01T10:15:02.456+0000",
+//!   "summary": {"successes": 12, "failures": 1, "errors": 0, "deferred": 0, "skipped": 0}
+//! }
+//! ```
+//!
+//! When `admin.token` is set, every request other than a `GET` must
+//! carry it as a bearer token, or gets a 401.
+//!
+//! The proxy is unhealthy when `admin.max_unforwarded_requests` is set
+//! and the cache holds more un-forwarded requests than that: COPS has
+//! been unreachable too long, or forwarding is stuck.  With
+//! `admin.readyz_probe` set, it's also unhealthy when a probe of COPS
+//! (made for each `/readyz` request, and limited to
+//! `network.probe_timeout_ms`) fails.  It's always unhealthy while the
+//! cache is unavailable: the last attempt to store a request failed.
+//! The backlog is in the `/readyz` body either way, with the probe
+//! outcome if there was one:
+//!
+//! ```json
+//! {"ready": true, "unforwarded_requests": 12, "max_unforwarded_requests": 100, "cops_reachable": null, "cache_available": true}
+//! ```
+//!
+//! Each endpoint can be turned off in the `[admin]` config.
+use super::{
+    auto, bind_listener, forward_until_shutdown, maintenance, mode, wait_for_shutdown,
+};
+use super::{probe_cops, ForwardSummary, ProbeResult};
+use crate::cache::{Cache, CacheStats};
+use crate::cops::{agent, current_timestamp, Request as CRequest};
+use crate::metrics;
+use crate::settings::{ProxyMode, Settings};
+use eyre::{eyre, Report, Result, WrapErr};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{
+    Body, Method, Request as HRequest, Response as HResponse, Server, StatusCode,
+};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// The body of a `/status` response.
+#[derive(Debug, Serialize)]
+struct Status {
+    version: &'static str,
+    mode: ProxyMode,
+    cache: Option<CacheStats>,
+    auto: Option<auto::AutoState>,
+    last_probe: Option<ProbeResult>,
+    oldest_request_age_secs: Option<u64>,
+}
+
+/// A forwarding run in the background.
+#[derive(Clone, Debug, Serialize)]
+struct ForwardRun {
+    run_id: u64,
+    status: &'static str,
+    started_at: String,
+    finished_at: Option<String>,
+    summary: Option<ForwardSummary>,
+}
+
+/// A stored request in a `/requests` response.
+#[derive(Debug, Serialize)]
+struct StoredRequest<'a> {
+    kind: String,
+    key: String,
+    request_id: &'a str,
+    timestamp: &'a str,
+    attempts: i64,
+    next_retry: &'a str,
+    last_error: &'a str,
+    last_attempt_at: &'a str,
+}
+
+impl<'a> StoredRequest<'a> {
+    fn new(cache: &Cache, req: &'a CRequest) -> StoredRequest<'a> {
+        StoredRequest {
+            kind: req.kind.to_string(),
+            key: cache.key(req),
+            request_id: &req.request_id,
+            timestamp: &req.timestamp,
+            attempts: req.attempts,
+            next_retry: &req.next_retry,
+            last_error: &req.last_error,
+            last_attempt_at: &req.last_attempt_at,
+        }
+    }
+}
+
+/// The forwarding runs started in the background, latest last.
+#[derive(Default)]
+struct ForwardRuns {
+    last_id: u64,
+    runs: VecDeque<ForwardRun>,
+    // the task of the latest run
+    task: Option<JoinHandle<()>>,
+}
+
+// how many finished runs can still be asked about
+const KEPT_FORWARD_RUNS: usize = 16;
+
+static FORWARD_RUNS: Lazy<Mutex<ForwardRuns>> = Lazy::new(Default::default);
+
+/// Serve the admin endpoints until shutdown, if they are enabled.
+///
+/// Forwarding runs started from the listener stop when `forward_shutdown`
+/// is sent, which may be before or after the listener's own `shutdown`.
+pub async fn run_server(
+    conf: &Settings, cache: Arc<Cache>, shutdown: watch::Receiver<bool>,
+    forward_shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    if !conf.admin.enabled {
+        return Ok(());
+    }
+    let full_host = format!("{}:{}", conf.admin.host, conf.admin.port);
+    let tcp = bind_listener(conf, &full_host)
+        .await
+        .wrap_err(format!("Can't listen on {} - is the admin port free?", full_host))?;
+    let rx = forward_shutdown;
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let conf = conf.clone();
+        let cache = Arc::clone(&cache);
+        let rx = rx.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Report>(service_fn(move |req| {
+                let conf = conf.clone();
+                let cache = Arc::clone(&cache);
+                let rx = rx.clone();
+                async move { serve_admin_req(req, &conf, cache, rx, peer).await }
+            }))
+        }
+    });
+    let server = Server::builder(AddrIncoming::from_listener(tcp)?).serve(make_svc);
+    let graceful = server.with_graceful_shutdown(wait_for_shutdown(shutdown));
+    info!("Serving admin endpoints on http://{}", full_host);
+    graceful.await.wrap_err("Unexpected admin server shutdown")?;
+    Ok(())
+}
+
+async fn serve_admin_req(
+    req: HRequest<Body>, conf: &Settings, cache: Arc<Cache>,
+    shutdown: watch::Receiver<bool>, peer: SocketAddr,
+) -> Result<HResponse<Body>> {
+    debug!("Received admin request: {} {}", req.method(), req.uri());
+    let path = req.uri().path().to_string();
+    let path = admin_path(&path);
+    if req.method() != Method::GET && !is_authorized(conf, &req) {
+        warn!("Rejected unauthorized admin request: {} {}", req.method(), req.uri());
+        return Ok(text_response(StatusCode::UNAUTHORIZED, "Unauthorized\n"));
+    }
+    if path == "/maintenance" && conf.admin.maintenance {
+        match *req.method() {
+            Method::POST => maintenance::set(true),
+            Method::DELETE => maintenance::set(false),
+            Method::GET => false,
+            _ => {
+                let msg = "Method not allowed\n";
+                return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, msg));
+            }
+        };
+        return json_response(&serde_json::json!({"maintenance": maintenance::is_on()}));
+    }
+    if path == "/mode" && conf.admin.mode {
+        let method = req.method().clone();
+        let result = match method {
+            Method::POST => match requested_mode(req).await {
+                Ok(requested) => mode::set(conf, Some(requested)),
+                Err(err) => Err(err),
+            },
+            Method::DELETE => mode::set(conf, None),
+            Method::GET => Ok(()),
+            _ => {
+                let msg = "Method not allowed\n";
+                return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, msg));
+            }
+        };
+        if let Err(err) = result {
+            let msg = format!("{:#}\n", err);
+            return Ok(text_response(StatusCode::BAD_REQUEST, &msg));
+        }
+        return json_response(&serde_json::json!({
+            "mode": mode::current(conf),
+            "configured_mode": conf.proxy.mode,
+            "overridden": mode::is_overridden(),
+        }));
+    }
+    if path == "/forward" && conf.admin.forward {
+        if req.method() != Method::POST {
+            return Ok(text_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Method not allowed\n",
+            ));
+        }
+        return start_forward_run(conf, cache, shutdown);
+    }
+    if let Some(key) = path.strip_prefix("/cache/") {
+        if conf.admin.cache {
+            if req.method() != Method::DELETE {
+                let msg = "Method not allowed\n";
+                return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, msg));
+            }
+            let key = percent_decode_str(key).decode_utf8_lossy().to_string();
+            let with_requests = req.uri().query() == Some("requests=true");
+            return delete_cache_key(&cache, &key, with_requests, peer).await;
+        }
+    }
+    if req.method() != Method::GET {
+        return Ok(text_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n"));
+    }
+    match path {
+        "/status" if conf.admin.status => {
+            let mode = mode::current(conf);
+            let oldest_request_age_secs = match mode {
+                ProxyMode::Passthrough => None,
+                _ => cache.oldest_pending_age_secs().await,
+            };
+            let cache = match mode {
+                ProxyMode::Passthrough => None,
+                _ => match cache.stats().await {
+                    Ok(stats) => Some(stats),
+                    Err(err) => {
+                        let msg = format!("Can't read cache stats: {:#}\n", err);
+                        return Ok(text_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            &msg,
+                        ));
+                    }
+                },
+            };
+            let auto = match mode {
+                ProxyMode::Auto => Some(auto::state()),
+                _ => None,
+            };
+            let status = Status {
+                version: env!("CARGO_PKG_VERSION"),
+                mode,
+                cache,
+                auto,
+                last_probe: super::last_probe(),
+                oldest_request_age_secs,
+            };
+            json_response(&status)
+        }
+        "/metrics" if conf.admin.metrics => {
+            let resp = HResponse::builder()
+                .header("content-type", "text/plain; version=0.0.4")
+                .body(Body::from(prometheus_text(conf, &metrics::samples())))?;
+            Ok(resp)
+        }
+        "/version" if conf.admin.version => json_response(&serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "agent": agent(),
+        })),
+        "/readyz" if conf.admin.readyz => {
+            let backlog = match conf.proxy.mode {
+                ProxyMode::Passthrough => 0,
+                _ => match cache.stats().await {
+                    Ok(stats) => stats.unforwarded_requests,
+                    Err(err) => {
+                        let msg = format!("Can't read cache stats: {:#}\n", err);
+                        return Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, &msg));
+                    }
+                },
+            };
+            let threshold = conf.admin.max_unforwarded_requests;
+            let cops_reachable = if conf.admin.readyz_probe {
+                Some(probe_cops(conf).await.is_ok())
+            } else {
+                None
+            };
+            let cache_available = !cache.is_unavailable();
+            let ready = (threshold == 0 || backlog <= threshold)
+                && cops_reachable.unwrap_or(true)
+                && cache_available;
+            let mut resp = json_response(&serde_json::json!({
+                "ready": ready,
+                "unforwarded_requests": backlog,
+                "max_unforwarded_requests": threshold,
+                "cops_reachable": cops_reachable,
+                "cache_available": cache_available,
+            }))?;
+            if !ready {
+                debug!(
+                    "Not ready: {} un-forwarded requests in the cache, COPS reachable: {:?}, cache available: {}",
+                    backlog, cops_reachable, cache_available
+                );
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            Ok(resp)
+        }
+        "/requests" if conf.admin.requests => match cache.unforwarded_requests().await {
+            Ok(requests) => {
+                let requests: Vec<StoredRequest> =
+                    requests.iter().map(|req| StoredRequest::new(&cache, req)).collect();
+                json_response(&requests)
+            }
+            Err(err) => {
+                let msg = format!("Can't read stored requests: {:#}\n", err);
+                Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, &msg))
+            }
+        },
+        path if path.starts_with("/forward/") && conf.admin.forward => {
+            let run = path["/forward/".len()..].parse::<u64>().ok().and_then(|id| {
+                let runs = FORWARD_RUNS.lock().unwrap();
+                runs.runs.iter().find(|run| run.run_id == id).cloned()
+            });
+            match run {
+                Some(run) => json_response(&run),
+                None => {
+                    Ok(text_response(StatusCode::NOT_FOUND, "No such forwarding run\n"))
+                }
+            }
+        }
+        _ => Ok(text_response(StatusCode::NOT_FOUND, "Not found\n")),
+    }
+}
+
+/// The mode in the body of a `POST` to `/admin/mode`.
+async fn requested_mode(req: HRequest<Body>) -> Result<ProxyMode> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let body: serde_json::Value =
+        serde_json::from_slice(&body).wrap_err("The body must be JSON")?;
+    match body.get("mode").and_then(|mode| mode.as_str()) {
+        Some(mode) => mode.try_into(),
+        None => Err(eyre!("The body must give the mode as a string")),
+    }
+}
+
+/// Remove the cached responses (and perhaps stored requests) with a key,
+/// noting who asked.
+async fn delete_cache_key(
+    cache: &Cache, key: &str, with_requests: bool, peer: SocketAddr,
+) -> Result<HResponse<Body>> {
+    let counts = match cache.delete_key(key, with_requests).await {
+        Ok(counts) => counts,
+        Err(err) => {
+            let msg = format!("Can't delete cache key: {:#}\n", err);
+            return Ok(text_response(StatusCode::INTERNAL_SERVER_ERROR, &msg));
+        }
+    };
+    info!(
+        "Admin client {} deleted {} cached response(s) and {} stored request(s) with key {}",
+        peer, counts.responses, counts.requests, key
+    );
+    let mut resp = json_response(&serde_json::json!({
+        "key": key,
+        "responses_deleted": counts.responses,
+        "requests_deleted": counts.requests,
+    }))?;
+    if counts.responses + counts.requests == 0 {
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+    }
+    Ok(resp)
+}
+
+/// The path of an admin request without its `/admin` prefix, if it
+/// has one.
+fn admin_path(path: &str) -> &str {
+    match path.strip_prefix("/admin") {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// Whether an admin request carries the configured token, if there is one.
+fn is_authorized(conf: &Settings, req: &HRequest<Body>) -> bool {
+    if conf.admin.token.expose().is_empty() {
+        return true;
+    }
+    let expected = format!("Bearer {}", conf.admin.token.expose());
+    match req.headers().get("authorization") {
+        Some(value) => constant_time_eq(value.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compare two byte strings in a time that depends only on their
+/// lengths, so a token can't be guessed a byte at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Whether a forwarding run is in progress in the background.
+pub fn is_forwarding() -> bool {
+    let runs = FORWARD_RUNS.lock().unwrap();
+    runs.runs.back().is_some_and(|run| run.summary.is_none())
+}
+
+/// Wait for the forwarding run in progress (if there is one) to finish.
+pub async fn finish_forward_run() {
+    let task = FORWARD_RUNS.lock().unwrap().task.take();
+    if let Some(task) = task {
+        if let Err(err) = task.await {
+            warn!("Forwarding run failed: {}", err);
+        }
+    }
+}
+
+/// Start a forwarding run from the admin listener, unless one is in
+/// progress.
+fn start_forward_run(
+    conf: &Settings, cache: Arc<Cache>, shutdown: watch::Receiver<bool>,
+) -> Result<HResponse<Body>> {
+    let run = match spawn_forward_run(conf, cache, shutdown, "the admin listener") {
+        Ok(run) => run,
+        Err(running) => {
+            let mut resp = json_response(&running)?;
+            *resp.status_mut() = StatusCode::CONFLICT;
+            return Ok(resp);
+        }
+    };
+    let mut resp = json_response(&run)?;
+    *resp.status_mut() = StatusCode::ACCEPTED;
+    resp.headers_mut()
+        .insert("location", format!("/admin/forward/{}", run.run_id).parse()?);
+    Ok(resp)
+}
+
+/// Start a forwarding run in the background, giving its id, unless one
+/// is in progress (giving the id of that).  `starter` says what started
+/// it, for the log.
+pub fn start_background_run(
+    conf: &Settings, cache: Arc<Cache>, shutdown: watch::Receiver<bool>, starter: &str,
+) -> Result<u64, u64> {
+    spawn_forward_run(conf, cache, shutdown, starter)
+        .map(|run| run.run_id)
+        .map_err(|running| running.run_id)
+}
+
+fn spawn_forward_run(
+    conf: &Settings, cache: Arc<Cache>, shutdown: watch::Receiver<bool>, starter: &str,
+) -> Result<ForwardRun, ForwardRun> {
+    let run = {
+        let mut runs = FORWARD_RUNS.lock().unwrap();
+        if let Some(run) = runs.runs.back().filter(|run| run.summary.is_none()) {
+            return Err(run.clone());
+        }
+        runs.last_id += 1;
+        let run = ForwardRun {
+            run_id: runs.last_id,
+            status: "running",
+            started_at: current_timestamp(),
+            finished_at: None,
+            summary: None,
+        };
+        if runs.runs.len() >= KEPT_FORWARD_RUNS {
+            runs.runs.pop_front();
+        }
+        runs.runs.push_back(run.clone());
+        run
+    };
+    info!("Starting forwarding run {} from {}", run.run_id, starter);
+    let (conf, id) = (conf.clone(), run.run_id);
+    let task = tokio::spawn(async move {
+        let summary = forward_until_shutdown(&conf, cache, false, shutdown).await;
+        info!("Finished forwarding run {}: {:?}", id, summary);
+        let mut runs = FORWARD_RUNS.lock().unwrap();
+        if let Some(run) = runs.runs.iter_mut().find(|run| run.run_id == id) {
+            run.status = "complete";
+            run.finished_at = Some(current_timestamp());
+            run.summary = Some(summary);
+        }
+    });
+    FORWARD_RUNS.lock().unwrap().task = Some(task);
+    Ok(run)
+}
+
+/// Render metric samples in the Prometheus text exposition format.
+fn prometheus_text(conf: &Settings, samples: &[metrics::Sample]) -> String {
+    let mut result = String::new();
+    let mut last_name = "";
+    for sample in samples.iter() {
+        let name = format!("frl_proxy_{}", sample.name);
+        // per-kind samples of a metric come together, and share a header
+        if sample.name != last_name {
+            let kind = if sample.gauge { "gauge" } else { "counter" };
+            writeln!(result, "# HELP {} {}", name, sample.help).unwrap();
+            writeln!(result, "# TYPE {} {}", name, kind).unwrap();
+            last_name = sample.name;
+        }
+        let mut labels = Vec::new();
+        if !conf.proxy.instance_id.is_empty() {
+            labels.push(format!("instance_id=\"{}\"", conf.proxy.instance_id));
+        }
+        if let Some(kind) = &sample.kind {
+            labels.push(format!("kind=\"{}\"", kind.to_string().to_lowercase()));
+        }
+        if labels.is_empty() {
+            writeln!(result, "{} {}", name, sample.value).unwrap();
+        } else {
+            writeln!(result, "{}{{{}}} {}", name, labels.join(","), sample.value)
+                .unwrap();
+        }
+    }
+    result
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<HResponse<Body>> {
+    let resp = HResponse::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_string_pretty(body)?))?;
+    Ok(resp)
+}
+
+fn text_response(status: StatusCode, body: &str) -> HResponse<Body> {
+    HResponse::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,121 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Switching between connected and offline operation in auto mode.
+//!
+//! An auto-mode proxy works like a cache-mode proxy until
+//! `auto.offline_after_failures` consecutive COPS calls fail.  It then
+//! works like a store-mode proxy (answering from the cache when it can)
+//! and probes COPS every `auto.probe_interval_secs`.  It only goes back
+//! to calling COPS once `auto.online_after_probes` consecutive probes
+//! succeed, so a marginal link doesn't make it flap between the two.
+use super::probe_cops;
+use crate::metrics;
+use crate::settings::{ProxyMode, Settings};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Whether an auto-mode proxy is offline, and its progress towards
+/// switching.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AutoState {
+    pub offline: bool,
+    pub consecutive_failures: u32,
+    pub consecutive_probes: u32,
+}
+
+static STATE: Lazy<Mutex<AutoState>> = Lazy::new(Default::default);
+
+/// The current auto-mode state.
+pub fn state() -> AutoState {
+    STATE.lock().unwrap().clone()
+}
+
+/// Whether an auto-mode proxy is working offline.
+pub fn is_offline() -> bool {
+    STATE.lock().unwrap().offline
+}
+
+/// Note the outcome of a COPS call, going offline after too many failures.
+pub fn record_call(conf: &Settings, ok: bool) {
+    if !matches!(conf.proxy.mode, ProxyMode::Auto) {
+        return;
+    }
+    let mut state = STATE.lock().unwrap();
+    if state.offline {
+        return;
+    }
+    if ok {
+        state.consecutive_failures = 0;
+        return;
+    }
+    state.consecutive_failures += 1;
+    if state.consecutive_failures >= conf.auto.offline_after_failures {
+        warn!(
+            "Working offline after {} consecutive failed COPS calls",
+            state.consecutive_failures
+        );
+        state.offline = true;
+        state.consecutive_probes = 0;
+        metrics::set_auto_offline(true);
+    }
+}
+
+fn record_probe(conf: &Settings, ok: bool) {
+    let mut state = STATE.lock().unwrap();
+    if !state.offline {
+        return;
+    }
+    if !ok {
+        state.consecutive_probes = 0;
+        return;
+    }
+    state.consecutive_probes += 1;
+    if state.consecutive_probes >= conf.auto.online_after_probes {
+        info!(
+            "Working connected again after {} consecutive successful probes of COPS",
+            state.consecutive_probes
+        );
+        state.offline = false;
+        state.consecutive_failures = 0;
+        metrics::set_auto_offline(false);
+    }
+}
+
+/// Start probing COPS while an auto-mode proxy is offline.
+pub fn spawn_prober(conf: &Settings) -> Option<JoinHandle<()>> {
+    if !matches!(conf.proxy.mode, ProxyMode::Auto) {
+        return None;
+    }
+    metrics::set_auto_offline(false);
+    let conf = conf.clone();
+    let interval = Duration::from_secs(conf.auto.probe_interval_secs);
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if !is_offline() {
+                continue;
+            }
+            let ok = match probe_cops(&conf).await {
+                Ok(status) => {
+                    debug!("Probe of COPS got status {}", status);
+                    true
+                }
+                Err(err) => {
+                    debug!("Probe of COPS failed: {:#}", err);
+                    false
+                }
+            };
+            record_probe(&conf, ok);
+        }
+    }))
+}
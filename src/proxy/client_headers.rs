@@ -0,0 +1,176 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::settings::{ForwardedHeader, ForwardedMode, Settings};
+use hyper::HeaderMap;
+use std::net::IpAddr;
+
+/// Headers which only describe a single connection, so are never
+/// passed on.  (Host and content-length are set for the COPS request.)
+const HOP_BY_HOP: [&str; 11] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+/// The headers of a client request which should be passed on to COPS.
+pub fn for_cops(conf: &Settings, headers: &HeaderMap) -> Vec<(String, String)> {
+    let allow = &conf.network.client_headers_allow;
+    if allow.is_empty() {
+        return Vec::new();
+    }
+    let all = allow.iter().any(|name| name == "*");
+    let listed =
+        |list: &[String], name: &str| list.iter().any(|n| n.eq_ignore_ascii_case(name));
+    // headers named in the connection header are hop-by-hop, too
+    let connection: Vec<String> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    let mut result = Vec::new();
+    for (name, value) in headers.iter() {
+        let name = name.as_str();
+        if HOP_BY_HOP.contains(&name) || connection.iter().any(|n| n == name) {
+            continue;
+        }
+        if !(all || listed(allow, name))
+            || listed(&conf.network.client_headers_deny, name)
+        {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            result.push((name.to_string(), value.to_string()));
+        }
+    }
+    result
+}
+
+/// Add the client's address to the forwarding headers passed on to COPS,
+/// per `network.forwarded_header` and `network.forwarded_mode`.
+pub fn add_forwarded(
+    conf: &Settings, headers: &HeaderMap, peer: IpAddr,
+    result: &mut Vec<(String, String)>,
+) {
+    let (x_forwarded_for, forwarded) = match conf.network.forwarded_header {
+        ForwardedHeader::Off => return,
+        ForwardedHeader::XForwardedFor => (true, false),
+        ForwardedHeader::Forwarded => (false, true),
+        ForwardedHeader::Both => (true, true),
+    };
+    if x_forwarded_for {
+        extend_chain(conf, headers, "x-forwarded-for", &peer.to_string(), result);
+    }
+    if forwarded {
+        // RFC 7239 quotes IPv6 addresses, which contain colons
+        let node = match peer {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        extend_chain(conf, headers, "forwarded", &format!("for={}", node), result);
+    }
+}
+
+/// Set a forwarding header to the chain the client sent (if it's kept)
+/// followed by our element, in place of any copy already passed on.
+fn extend_chain(
+    conf: &Settings, headers: &HeaderMap, name: &str, element: &str,
+    result: &mut Vec<(String, String)>,
+) {
+    result.retain(|(n, _)| n != name);
+    let mut chain: Vec<&str> = match conf.network.forwarded_mode {
+        ForwardedMode::Append => headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect(),
+        ForwardedMode::Replace => Vec::new(),
+    };
+    chain.push(element);
+    result.push((name.to_string(), chain.join(", ")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(allow: &[&str], deny: &[&str]) -> Settings {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.network.client_headers_allow = allow.iter().map(|s| s.to_string()).collect();
+        conf.network.client_headers_deny = deny.iter().map(|s| s.to_string()).collect();
+        conf
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn names(passed: Vec<(String, String)>) -> Vec<String> {
+        passed.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn allow_list_only() {
+        let headers = headers(&[("x-tenant", "t1"), ("x-other", "o"), ("cookie", "c")]);
+        let passed = for_cops(&conf(&["X-Tenant"], &[]), &headers);
+        assert_eq!(passed, vec![("x-tenant".to_string(), "t1".to_string())]);
+    }
+
+    #[test]
+    fn deny_list_only() {
+        // nothing is allowed by default, so a deny list alone passes nothing
+        let headers = headers(&[("x-tenant", "t1"), ("cookie", "c")]);
+        assert!(for_cops(&conf(&[], &["cookie"]), &headers).is_empty());
+    }
+
+    #[test]
+    fn wildcard_allows_all() {
+        let headers = headers(&[("x-tenant", "t1"), ("x-other", "o")]);
+        assert_eq!(
+            names(for_cops(&conf(&["*"], &[]), &headers)),
+            ["x-tenant", "x-other"]
+        );
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let headers = headers(&[("x-tenant", "t1"), ("cookie", "c")]);
+        assert_eq!(names(for_cops(&conf(&["*"], &["Cookie"]), &headers)), ["x-tenant"]);
+        let passed = for_cops(&conf(&["cookie"], &["cookie"]), &headers);
+        assert!(passed.is_empty());
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_always_dropped() {
+        let headers = headers(&[
+            ("connection", "close, x-hop"),
+            ("x-hop", "h"),
+            ("keep-alive", "timeout=5"),
+            ("transfer-encoding", "chunked"),
+            ("x-tenant", "t1"),
+        ]);
+        let allow = ["*", "connection", "x-hop", "keep-alive", "transfer-encoding"];
+        assert_eq!(names(for_cops(&conf(&allow, &[]), &headers)), ["x-tenant"]);
+    }
+}
@@ -0,0 +1,320 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::settings::{Http2Mode, Settings};
+use core::task::{Context, Poll};
+use eyre::{Result, WrapErr};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::connect::{Connected, Connection};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_tls::MaybeHttpsStream;
+use log::debug;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type Resolutions = HashMap<String, (Instant, Vec<SocketAddr>)>;
+
+/// Addresses resolved by `CachingResolver`, with the time they were resolved.
+///
+/// This is shared by all connectors, so that each call benefits from
+/// the resolutions of earlier ones.
+static RESOLVED: Lazy<Mutex<Resolutions>> = Lazy::new(Default::default);
+
+/// An HTTP connector which resolves host names with the `CachingResolver`.
+pub fn http_connector(conf: &Settings) -> HttpConnector<CachingResolver> {
+    let ttl = Duration::from_secs(conf.network.dns_cache_ttl_secs);
+    HttpConnector::new_with_resolver(CachingResolver { inner: GaiResolver::new(), ttl })
+}
+
+/// Resolves host names using the system resolver, remembering the
+/// results for the configured TTL.  A TTL of zero disables caching.
+#[derive(Clone)]
+pub struct CachingResolver {
+    inner: GaiResolver,
+    ttl: Duration,
+}
+
+impl Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = std::io::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, std::io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_string();
+        let ttl = self.ttl;
+        if ttl > Duration::from_secs(0) {
+            if let Some((at, addrs)) = RESOLVED.lock().unwrap().get(&host) {
+                if at.elapsed() < ttl {
+                    debug!("Using cached addresses for {}: {:?}", host, addrs);
+                    let addrs = addrs.clone();
+                    return Box::pin(async move { Ok(addrs.into_iter()) });
+                }
+            }
+        }
+        let resolving = self.inner.call(name);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving.await?.collect();
+            if ttl > Duration::from_secs(0) {
+                debug!("Caching addresses for {}: {:?}", host, addrs);
+                RESOLVED.lock().unwrap().insert(host, (Instant::now(), addrs.clone()));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+/// Connects to the upstream proxy, giving up after the configured
+/// `network.proxy_connect_timeout_ms`.
+///
+/// Its errors are `ProxyHopError`s, so that failures to reach the proxy
+/// can be told apart from failures of COPS behind it.
+#[derive(Clone)]
+pub struct ProxyHopConnector {
+    http: HttpConnector<CachingResolver>,
+    timeout: Option<Duration>,
+}
+
+impl ProxyHopConnector {
+    pub fn new(conf: &Settings) -> ProxyHopConnector {
+        let timeout = match conf.network.proxy_connect_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        ProxyHopConnector { http: http_connector(conf), timeout }
+    }
+}
+
+impl Service<Uri> for ProxyHopConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let proxy = dst.authority().map(|a| a.to_string()).unwrap_or_default();
+        let connecting = self.http.call(dst);
+        let timeout = self.timeout;
+        Box::pin(async move {
+            let result = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, connecting).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let msg = format!(
+                            "Timeout - no connection to proxy {} in {} milliseconds",
+                            proxy,
+                            timeout.as_millis()
+                        );
+                        return Err(ProxyHopError(msg).into());
+                    }
+                },
+                None => connecting.await,
+            };
+            result.map_err(|err| {
+                ProxyHopError(format!("Can't connect to proxy {}: {}", proxy, err)).into()
+            })
+        })
+    }
+}
+
+/// A failure to connect to the upstream proxy.
+#[derive(Debug)]
+pub struct ProxyHopError(String);
+
+impl std::fmt::Display for ProxyHopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ProxyHopError {}
+
+impl ProxyHopError {
+    /// The failure to connect to the proxy behind a failed request, if
+    /// that's why it failed.
+    pub fn cause_of(err: &hyper::Error) -> Option<&ProxyHopError> {
+        use std::error::Error;
+        // the proxy connector wraps connection errors in an io::Error
+        let io = err.source()?.downcast_ref::<std::io::Error>()?;
+        io.get_ref()?.downcast_ref::<ProxyHopError>()
+    }
+}
+
+/// Connects to COPS, over TLS when the URI is https.
+///
+/// This differs from `hyper_tls::HttpsConnector` in that the TLS server
+/// name (used for SNI and for validating the server's certificate) can
+/// be configured, rather than always being the host we connect to.
+/// That allows connecting to COPS by IP address.
+///
+/// It can also offer HTTP/2 via ALPN, falling back to HTTP/1.1 when
+/// the server doesn't choose HTTP/2.
+#[derive(Clone)]
+pub struct CopsConnector {
+    http: HttpConnector<CachingResolver>,
+    tls: TlsConnector,
+    server_name: Option<String>,
+    alpn: bool,
+}
+
+impl CopsConnector {
+    pub fn new(conf: &Settings) -> Result<CopsConnector> {
+        let mut http = http_connector(conf);
+        http.enforce_http(false);
+        let alpn = matches!(conf.network.cops_http2, Http2Mode::Alpn);
+        let mut tls = native_tls::TlsConnector::builder();
+        if alpn {
+            tls.request_alpns(&["h2", "http/1.1"]);
+        }
+        let tls = tls.build().wrap_err("Can't initialize TLS for COPS connections")?;
+        Ok(CopsConnector {
+            http,
+            tls: tls.into(),
+            server_name: conf.proxy.cops_sni_hostname.clone(),
+            alpn,
+        })
+    }
+}
+
+impl Service<Uri> for CopsConnector {
+    type Response = CopsStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let is_https = dst.scheme_str() == Some("https");
+        let server_name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => dst.host().unwrap_or_default().to_string(),
+        };
+        let connecting = self.http.call(dst);
+        let tls = self.tls.clone();
+        let alpn = self.alpn;
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if is_https {
+                let started = Instant::now();
+                let tls = tls.connect(&server_name, tcp).await?;
+                let handshake = started.elapsed();
+                let certificate = match tls.get_ref().peer_certificate()? {
+                    Some(cert) => Some(Arc::new(cert.to_der()?)),
+                    None => None,
+                };
+                let info = ConnectionInfo::new(Some(handshake), certificate);
+                let protocol = tls.get_ref().negotiated_alpn()?;
+                let h2 = alpn && protocol.as_deref() == Some(b"h2");
+                if alpn {
+                    debug!("COPS chose HTTP/{}", if h2 { "2" } else { "1.1" });
+                }
+                Ok(CopsStream { inner: MaybeHttpsStream::Https(tls), h2, info })
+            } else {
+                let info = ConnectionInfo::new(None, None);
+                Ok(CopsStream { inner: MaybeHttpsStream::Http(tcp), h2: false, info })
+            }
+        })
+    }
+}
+
+/// Facts about a connection to COPS, which hyper attaches to the
+/// extensions of every response received over it.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// How long the TLS handshake took, for https connections.
+    pub tls_handshake: Option<Duration>,
+    /// The (DER) certificate COPS presented, for https connections.
+    pub peer_certificate: Option<Arc<Vec<u8>>>,
+    used: Arc<AtomicBool>,
+}
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+impl ConnectionInfo {
+    fn new(
+        tls_handshake: Option<Duration>, peer_certificate: Option<Arc<Vec<u8>>>,
+    ) -> ConnectionInfo {
+        let id = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        let used = Arc::new(AtomicBool::new(false));
+        ConnectionInfo { id, tls_handshake, peer_certificate, used }
+    }
+
+    /// Note a response received over this connection, returning
+    /// whether an earlier response had been received over it.
+    pub fn mark_used(&self) -> bool {
+        self.used.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// A connection to COPS, which tells hyper whether HTTP/2 was negotiated.
+pub struct CopsStream {
+    inner: MaybeHttpsStream<TcpStream>,
+    h2: bool,
+    info: ConnectionInfo,
+}
+
+impl Connection for CopsStream {
+    fn connected(&self) -> Connected {
+        let connected = self.inner.connected().extra(self.info.clone());
+        if self.h2 {
+            connected.negotiated_h2()
+        } else {
+            connected
+        }
+    }
+}
+
+impl AsyncRead for CopsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CopsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,110 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! The `test-cops` command, which troubleshoots the connection to COPS.
+//!
+//! The COPS host is resolved, and then a request is sent to
+//! `network.probe_path` just as licensing calls are sent: through the
+//! configured proxy, with the configured TLS server name, and giving
+//! up after `network.cops_timeout_ms`.  What happens at each step is
+//! reported on the console.
+use super::connector::ConnectionInfo;
+use super::{cops_endpoint, send_to_cops};
+use crate::cops::agent;
+use crate::settings::{ProbeMethod, Settings};
+use eyre::{eyre, Result, WrapErr};
+use hyper::client::connect::HttpInfo;
+use hyper::{Body, Request as HRequest};
+use std::time::{Duration, Instant};
+
+/// Test the connection to COPS, failing if no response is received.
+pub async fn run(conf: &Settings) -> Result<()> {
+    let (scheme, host) = cops_endpoint(conf);
+    eprintln!("COPS endpoint: {}://{}", scheme, host);
+    if conf.network.use_proxy {
+        eprintln!(
+            "Connecting via proxy: http://{}:{}",
+            conf.network.proxy_host, conf.network.proxy_port
+        );
+    }
+    let target = if host.contains(':') {
+        host.clone()
+    } else {
+        format!("{}:{}", host, if scheme == "https" { 443 } else { 80 })
+    };
+    match tokio::net::lookup_host(&target).await {
+        Ok(addrs) => {
+            let addrs: Vec<String> = addrs.map(|addr| addr.ip().to_string()).collect();
+            eprintln!("Resolved {} to {}", host, addrs.join(", "));
+        }
+        // the proxy resolves COPS for itself
+        Err(err) if conf.network.use_proxy => {
+            eprintln!("Can't resolve {} here (the proxy may): {}", host, err)
+        }
+        Err(err) => return Err(eyre!("Can't resolve {}: {}", host, err)),
+    }
+    if scheme == "https" {
+        if let Some(name) = &conf.proxy.cops_sni_hostname {
+            eprintln!("TLS server name: {}", name);
+        }
+    }
+    let uri = format!("{}://{}{}", scheme, host, conf.network.probe_path);
+    let builder = match conf.network.probe_method {
+        ProbeMethod::Get => HRequest::get(&uri),
+        ProbeMethod::Head => HRequest::head(&uri),
+    };
+    let net_req = builder
+        .header("user-agent", agent())
+        .body(Body::empty())
+        .wrap_err("Invalid COPS test request")?;
+    let timeout = Duration::from_millis(conf.network.cops_timeout_ms);
+    eprintln!("Sending {} {}", net_req.method(), uri);
+    let started = Instant::now();
+    let resp = match send_to_cops(conf, net_req, timeout).await {
+        Ok(resp) => resp,
+        Err(err) => {
+            eprintln!("No response after {:?}", started.elapsed());
+            return Err(err);
+        }
+    };
+    eprintln!(
+        "Received {} ({:?}) from COPS in {:?}",
+        resp.status(),
+        resp.version(),
+        started.elapsed()
+    );
+    if let Some(http) = resp.extensions().get::<HttpInfo>() {
+        eprintln!("Connected to {}", http.remote_addr());
+    }
+    match resp.extensions().get::<ConnectionInfo>() {
+        Some(info) => {
+            if let Some(handshake) = info.tls_handshake {
+                eprintln!("TLS handshake took {:?}", handshake);
+            }
+            if let Some(der) = &info.peer_certificate {
+                describe_certificate(der);
+            }
+        }
+        None if scheme == "https" => {
+            eprintln!("TLS details aren't available for connections through a proxy")
+        }
+        None => (),
+    }
+    Ok(())
+}
+
+fn describe_certificate(der: &[u8]) {
+    match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => {
+            eprintln!("Certificate subject: {}", cert.subject());
+            eprintln!("Certificate issuer: {}", cert.issuer());
+            eprintln!("Certificate expires: {}", cert.validity().not_after);
+        }
+        Err(err) => eprintln!("Can't read the certificate COPS presented: {}", err),
+    }
+}
@@ -0,0 +1,69 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Forwarding runs started when the backlog gets old.
+//!
+//! When `forward.trigger_age_secs` is set, a serving proxy checks every
+//! `forward.trigger_check_secs` how long the oldest stored request which
+//! may be forwarded has waited.  Once that's longer than the trigger
+//! age, it probes COPS and, if COPS is reachable, starts a forwarding
+//! run in the background, just as a `POST` to the `/admin/forward`
+//! endpoint does.  So no request waits much longer than the trigger age
+//! for an attempt to forward it, while COPS can be reached.
+use super::{admin, maintenance, mode, probe_cops};
+use crate::cache::Cache;
+use crate::settings::{ProxyMode, Settings};
+use log::{debug, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Start checking the age of the backlog, if that's configured.
+pub fn spawn(
+    conf: &Settings, cache: Arc<Cache>, shutdown: watch::Receiver<bool>,
+) -> Option<JoinHandle<()>> {
+    let age = conf.forward.trigger_age_secs;
+    if age == 0 || matches!(conf.proxy.mode, ProxyMode::Passthrough) {
+        return None;
+    }
+    info!("Forwarding when the oldest stored request is over {} seconds old", age);
+    let conf = conf.clone();
+    let interval = Duration::from_secs(conf.forward.trigger_check_secs);
+    Some(tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if matches!(mode::current(&conf), ProxyMode::Passthrough)
+                || maintenance::is_on()
+            {
+                continue;
+            }
+            let oldest = match cache.oldest_pending_age_secs().await {
+                Some(oldest) if oldest > age => oldest,
+                _ => continue,
+            };
+            if admin::is_forwarding() {
+                continue;
+            }
+            if let Err(err) = probe_cops(&conf).await {
+                debug!(
+                    "Oldest stored request is {} seconds old, but COPS is unreachable: {:#}",
+                    oldest, err
+                );
+                continue;
+            }
+            let starter = format!("the backlog age trigger ({} seconds)", oldest);
+            let _ = admin::start_background_run(
+                &conf,
+                Arc::clone(&cache),
+                shutdown.clone(),
+                &starter,
+            );
+        }
+    }))
+}
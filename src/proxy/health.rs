@@ -0,0 +1,164 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Tracking of whether COPS is reachable.
+//!
+//! COPS is considered down after `network.cops_down_after_failures`
+//! consecutive calls fail (get no response or a server error), and up
+//! again after a call succeeds.  Probes of COPS (by auto mode, the
+//! forward trigger and `/readyz`, say) count as calls, failing only
+//! when they get no response.  Each change is logged (with a warning
+//! when COPS goes down) and, when `network.health_webhook_url` is set,
+//! POSTed to that URL as JSON:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "event": "cops-down",
+//!   "at": "2021-07-01T10:15:00.123+0000",
+//!   "consecutive_failures": 3,
+//!   "last_error": "Can't reach COPS: connection refused"
+//! }
+//! ```
+//!
+//! The `event` is `cops-down` or `cops-up`, and `last_error` is null in
+//! `cops-up` events.  Changes are announced at most once every
+//! `network.health_debounce_secs`: while a link flaps, only the state
+//! it settles in is announced, when the debounce window ends.
+use super::webhook;
+use crate::cops::current_timestamp;
+use crate::settings::Settings;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    // the state last announced, and when
+    announced_down: bool,
+    announced_at: Option<Instant>,
+    // whether a check is due when the debounce window ends
+    recheck_pending: bool,
+}
+
+static HEALTH: Lazy<Mutex<Health>> = Lazy::new(Default::default);
+
+/// A change in the health of COPS.
+#[derive(Debug, Serialize)]
+pub struct HealthEvent {
+    pub version: u32,
+    pub event: &'static str,
+    pub at: String,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Note the outcome of a COPS call or probe: `None` for success, or
+/// the error.
+pub fn record(conf: &Settings, error: Option<String>) {
+    let threshold = conf.network.cops_down_after_failures;
+    if threshold == 0 {
+        return;
+    }
+    {
+        let mut health = HEALTH.lock().unwrap();
+        match error {
+            None => health.consecutive_failures = 0,
+            Some(err) => {
+                health.consecutive_failures += 1;
+                health.last_error = Some(err);
+            }
+        }
+    }
+    check(conf);
+}
+
+/// Announce the current state, if it's changed since the last
+/// announcement.  Within the debounce window of that, the check is
+/// made again when the window ends, so the state COPS settles in is
+/// announced even if no more calls are made.
+fn check(conf: &Settings) {
+    let event = {
+        let mut health = HEALTH.lock().unwrap();
+        let down = health.consecutive_failures >= conf.network.cops_down_after_failures;
+        if down == health.announced_down {
+            return;
+        }
+        let debounce = Duration::from_secs(conf.network.health_debounce_secs);
+        let left = match health.announced_at {
+            Some(at) => debounce.saturating_sub(at.elapsed()),
+            None => Duration::from_secs(0),
+        };
+        if left > Duration::from_secs(0) {
+            if !health.recheck_pending {
+                health.recheck_pending = true;
+                let conf = conf.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(left).await;
+                    HEALTH.lock().unwrap().recheck_pending = false;
+                    check(&conf);
+                });
+            }
+            return;
+        }
+        health.announced_down = down;
+        health.announced_at = Some(Instant::now());
+        HealthEvent {
+            version: 1,
+            event: if down { "cops-down" } else { "cops-up" },
+            at: current_timestamp(),
+            consecutive_failures: health.consecutive_failures,
+            last_error: if down { health.last_error.clone() } else { None },
+        }
+    };
+    if event.last_error.is_some() {
+        warn!(
+            "COPS is down: {} consecutive failures, the last: {}",
+            event.consecutive_failures,
+            event.last_error.as_deref().unwrap_or_default()
+        );
+    } else {
+        info!("COPS is up again");
+    }
+    let url = conf.network.health_webhook_url.expose();
+    if !url.is_empty() {
+        // don't hold up the call that caused the change
+        let (conf, url) = (conf.clone(), url.to_string());
+        tokio::spawn(async move { webhook::deliver(&conf, &url, &event).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announced_down() -> bool {
+        HEALTH.lock().unwrap().announced_down
+    }
+
+    // the health is process-wide, so this is the only test that changes it
+    #[tokio::test]
+    async fn settled_state_is_announced_when_the_window_ends() {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.network.cops_down_after_failures = 2;
+        conf.network.health_debounce_secs = 1;
+        record(&conf, Some("refused".to_string()));
+        assert!(!announced_down());
+        record(&conf, Some("refused".to_string()));
+        assert!(announced_down());
+        // COPS comes back within the window, and then isn't called again
+        record(&conf, None);
+        assert!(announced_down());
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(!announced_down());
+    }
+}
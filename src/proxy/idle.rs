@@ -0,0 +1,84 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Shutting down a proxy that has had nothing to do for a while.
+//!
+//! With `proxy.idle_shutdown_ms` set, a serving proxy starts a graceful
+//! shutdown once that long has passed with no licensing requests (admin
+//! requests don't count, so health checks don't keep it up).  It never
+//! goes idle while a request is being served or a forwarding run is in
+//! progress: it only counts the time since they finished.
+use super::admin;
+use crate::settings::Settings;
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+struct Activity {
+    in_flight: u64,
+    last: Instant,
+}
+
+static ACTIVITY: Lazy<Mutex<Activity>> =
+    Lazy::new(|| Mutex::new(Activity { in_flight: 0, last: Instant::now() }));
+
+/// A request being served, which keeps the proxy from going idle.
+pub struct Busy;
+
+/// Note that a request is being served, until the result is dropped.
+pub fn busy() -> Busy {
+    let mut activity = ACTIVITY.lock().unwrap();
+    activity.in_flight += 1;
+    activity.last = Instant::now();
+    Busy
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut activity = ACTIVITY.lock().unwrap();
+        activity.in_flight -= 1;
+        activity.last = Instant::now();
+    }
+}
+
+/// Start the idle timer, if it's configured, which sends the shutdown
+/// signal when the proxy has been idle for long enough.
+pub fn spawn_timer(
+    conf: &Settings, shutdown: Arc<watch::Sender<bool>>,
+) -> Option<JoinHandle<()>> {
+    let idle = match conf.proxy.idle_shutdown_ms {
+        0 => return None,
+        ms => Duration::from_millis(ms),
+    };
+    ACTIVITY.lock().unwrap().last = Instant::now();
+    Some(tokio::spawn(async move {
+        loop {
+            let wait = {
+                let activity = ACTIVITY.lock().unwrap();
+                if activity.in_flight > 0 {
+                    idle
+                } else {
+                    idle.saturating_sub(activity.last.elapsed())
+                }
+            };
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            } else if admin::is_forwarding() {
+                // forwarding counts as activity for as long as it lasts
+                ACTIVITY.lock().unwrap().last = Instant::now();
+            } else {
+                break;
+            }
+        }
+        info!("Starting graceful shutdown after {:?} with no requests", idle);
+        shutdown.send(true).unwrap_or(());
+    }))
+}
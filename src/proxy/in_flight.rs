@@ -0,0 +1,104 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! A ceiling on the memory used by buffered request bodies.
+//!
+//! Request bodies are read into memory before they are served, so a
+//! burst of large concurrent requests could use a lot of it.  With
+//! `limits.max_in_flight_bytes` set, the bodies of the requests being
+//! served never add up to more than that: a request that would take the
+//! total over it is shed (answered with a 503) rather than read.  A body
+//! with a known length is reserved in full before any of it is read; one
+//! without is reserved chunk by chunk as it arrives.
+use crate::settings::Settings;
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static IN_FLIGHT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The bytes of request bodies currently held in memory.
+pub fn bytes() -> u64 {
+    IN_FLIGHT_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes counted against the budget, until this is dropped.
+#[derive(Default)]
+pub struct Reservation {
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Reserve `bytes` more, unless that would go over the budget.
+    fn grow(&mut self, conf: &Settings, bytes: u64) -> bool {
+        let limit = conf.limits.max_in_flight_bytes;
+        let reserved =
+            IN_FLIGHT_BYTES.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| match n
+                .checked_add(bytes)
+            {
+                Some(total) if limit == 0 || total <= limit => Some(total),
+                _ => None,
+            });
+        if reserved.is_ok() {
+            self.bytes += bytes;
+        }
+        reserved.is_ok()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        IN_FLIGHT_BYTES.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+/// Why a request body couldn't be read.
+pub enum ReadError {
+    /// Reading it would have gone over the budget.
+    OverBudget,
+    /// The client went away, or sent less than it said it would.
+    Incomplete(hyper::Error),
+}
+
+/// Read a request body, reserving its bytes in the budget for as long
+/// as `reservation` is kept.
+pub async fn read_body(
+    conf: &Settings, mut body: Body, reservation: &mut Reservation,
+) -> Result<Bytes, ReadError> {
+    let declared = body.size_hint().lower();
+    if !reservation.grow(conf, declared) {
+        return Err(ReadError::OverBudget);
+    }
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(ReadError::Incomplete)?;
+        let total = (buf.len() + chunk.len()) as u64;
+        if total > reservation.bytes && !reservation.grow(conf, total - reservation.bytes)
+        {
+            return Err(ReadError::OverBudget);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn truncated_body_is_incomplete() {
+        let conf = Settings::read_config(&[]).unwrap();
+        let (mut sender, body) = Body::channel();
+        sender.try_send_data(Bytes::from("{\"npdId\":")).unwrap();
+        sender.abort();
+        let mut reservation = Reservation::default();
+        let result = read_body(&conf, body, &mut reservation).await;
+        assert!(matches!(result, Err(ReadError::Incomplete(_))));
+    }
+}
@@ -0,0 +1,213 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::metrics;
+use crate::settings::Settings;
+use core::task::{Context, Poll};
+use hyper::HeaderMap;
+use log::info;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Limits on the number of connections held open by clients, and on
+/// the rate at which they are accepted.
+///
+/// The limiter is shared by all the listeners, so the global limits
+/// apply to plain and secure connections together.
+pub struct ConnectionLimiter {
+    max_total: usize,
+    max_per_ip: usize,
+    allowlist: Vec<IpAddr>,
+    open: Mutex<OpenConnections>,
+    accept_interval: Duration,
+    next_accept: Mutex<Instant>,
+}
+
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimiter {
+    pub fn from(conf: &Settings) -> ConnectionLimiter {
+        let allowlist = conf
+            .limits
+            .connection_allowlist
+            .iter()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .collect();
+        ConnectionLimiter {
+            max_total: conf.limits.max_connections,
+            max_per_ip: conf.limits.max_connections_per_ip,
+            allowlist,
+            open: Mutex::new(OpenConnections::default()),
+            accept_interval: match conf.limits.max_accepts_per_sec {
+                0 => Duration::from_secs(0),
+                rate => Duration::from_secs(1) / rate,
+            },
+            next_accept: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the accept rate allows another connection to be accepted.
+    ///
+    /// Accepts are spaced evenly, so a burst of connections is spread
+    /// out while the OS holds the waiting ones in the listen backlog.
+    pub async fn pace(&self) {
+        if self.accept_interval == Duration::from_secs(0) {
+            return;
+        }
+        let wait = {
+            let mut next = self.next_accept.lock().unwrap();
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.accept_interval;
+            slot - now
+        };
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Admit a new connection from the given address, if the limits allow it.
+    ///
+    /// The connection counts against the limits until the returned
+    /// permit is dropped.  Allowlisted addresses are always admitted,
+    /// and don't count against the limits.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionPermit> {
+        if self.allowlist.contains(&ip) {
+            metrics::connection_opened();
+            return Some(ConnectionPermit { limiter: None, ip });
+        }
+        let mut open = self.open.lock().unwrap();
+        if self.max_total > 0 && open.total >= self.max_total {
+            info!("Refusing connection from {}: {} connections are open", ip, open.total);
+            metrics::count_refused_connection();
+            return None;
+        }
+        let from_ip = open.per_ip.entry(ip).or_insert(0);
+        if self.max_per_ip > 0 && *from_ip >= self.max_per_ip {
+            info!("Refusing connection from {}: it has {} open already", ip, from_ip);
+            metrics::count_refused_connection();
+            return None;
+        }
+        *from_ip += 1;
+        open.total += 1;
+        metrics::connection_opened();
+        Some(ConnectionPermit { limiter: Some(Arc::clone(self)), ip })
+    }
+
+    fn release(&self, ip: &IpAddr) {
+        let mut open = self.open.lock().unwrap();
+        open.total = open.total.saturating_sub(1);
+        if let Some(from_ip) = open.per_ip.get_mut(ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                open.per_ip.remove(ip);
+            }
+        }
+    }
+}
+
+/// An admitted connection's place in the limits.
+pub struct ConnectionPermit {
+    limiter: Option<Arc<ConnectionLimiter>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.release(&self.ip);
+        }
+        metrics::connection_closed();
+    }
+}
+
+/// A client connection which holds its permit for as long as it's open.
+pub struct Tracked<S> {
+    stream: S,
+    permit: ConnectionPermit,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(stream: S, permit: ConnectionPermit) -> Tracked<S> {
+        Tracked { stream, permit }
+    }
+
+    /// The IP address of the client.
+    pub fn peer_ip(&self) -> IpAddr {
+        self.permit.ip
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// The read buffer size for client connections, if there's a limit on
+/// header bytes.
+///
+/// Hyper buffers the request head while parsing it, and answers with a
+/// 431 itself when the head won't fit in the buffer, so this bounds the
+/// memory a client can use on headers before the request is served.  It
+/// leaves room for the request line and the header syntax (which don't
+/// count against the limit), so heads near the limit get to the explicit
+/// check in [`headers_too_large`] and are logged.
+pub fn read_buffer_size(conf: &Settings) -> Option<usize> {
+    match conf.limits.max_header_bytes {
+        0 => None,
+        // hyper won't take a buffer smaller than 8KB
+        max => Some(max.saturating_mul(2).max(8192)),
+    }
+}
+
+/// The bytes of header names and values in a request, if they add up to
+/// more than the limit on header bytes.
+pub fn headers_too_large(conf: &Settings, headers: &HeaderMap) -> Option<usize> {
+    let max = conf.limits.max_header_bytes;
+    if max == 0 {
+        return None;
+    }
+    let total =
+        headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+    if total > max {
+        Some(total)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,60 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Maintenance mode, in which licensing requests are answered with a
+//! fixed response and COPS is never called.
+//!
+//! A proxy starts in maintenance mode if `maintenance.enabled` is set,
+//! and the admin `/maintenance` endpoint turns it on and off while the
+//! proxy runs.  With `maintenance.store_requests` set, requests received
+//! in maintenance mode are stored so they can be forwarded later.
+use super::error_body;
+use crate::cops::agent;
+use crate::settings::Settings;
+use hyper::{Body, Response as HResponse};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ON: AtomicBool = AtomicBool::new(false);
+
+/// Put the proxy in maintenance mode at startup, if so configured.
+pub fn init(conf: &Settings) {
+    if conf.maintenance.enabled {
+        set(true);
+    }
+}
+
+/// Whether the proxy is in maintenance mode.
+pub fn is_on() -> bool {
+    ON.load(Ordering::Relaxed)
+}
+
+/// Turn maintenance mode on or off, returning whether that changed it.
+pub fn set(on: bool) -> bool {
+    let changed = ON.swap(on, Ordering::Relaxed) != on;
+    match (changed, on) {
+        (true, true) => warn!("Entering maintenance mode: COPS will not be called"),
+        (true, false) => info!("Leaving maintenance mode"),
+        _ => (),
+    }
+    changed
+}
+
+/// The response to a licensing request in maintenance mode.
+pub fn response(conf: &Settings, request_id: &str) -> HResponse<Body> {
+    let status = conf.maintenance.status;
+    let (content_type, body) =
+        error_body(conf, status, &conf.maintenance.message, Some(request_id));
+    HResponse::builder()
+        .status(status)
+        .header("content-type", content_type)
+        .header("server", agent())
+        .body(Body::from(body))
+        // the status was checked when the configuration was validated
+        .expect("Invalid maintenance response")
+}
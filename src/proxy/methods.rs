@@ -0,0 +1,129 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Requests to the COPS endpoints with methods COPS doesn't accept.
+//!
+//! These are answered by the proxy, without calling COPS: an `OPTIONS`
+//! gets a 204 listing the allowed methods (if `proxy.answer_options` is
+//! set), a `HEAD` gets an empty 200 (if `proxy.answer_head` is set), and
+//! any other method gets a 405 listing the allowed methods.
+use super::error_body;
+use crate::cops::{self, agent};
+use crate::settings::Settings;
+use hyper::{Body, Method, Response as HResponse};
+use log::info;
+
+/// The proxy's own answer to a request to a COPS endpoint that uses a
+/// method COPS doesn't accept, or `None` for requests COPS can handle
+/// (or that aren't to a COPS endpoint).  The request's id, if it has
+/// one, goes in the body of a 405.
+pub fn answer_locally(
+    conf: &Settings, method: &Method, path: &str, request_id: Option<&str>,
+) -> Option<HResponse<Body>> {
+    let accepted = cops::endpoint_method(path)?;
+    if *method == accepted {
+        return None;
+    }
+    let mut allowed = vec![accepted.as_str()];
+    if conf.proxy.answer_options {
+        allowed.push("OPTIONS");
+    }
+    if conf.proxy.answer_head {
+        allowed.push("HEAD");
+    }
+    let allowed = allowed.join(", ");
+    let builder =
+        HResponse::builder().header("allow", &allowed).header("server", agent());
+    let resp = match *method {
+        Method::OPTIONS if conf.proxy.answer_options => {
+            info!("Answering OPTIONS request for {}", path);
+            builder
+                .status(204)
+                .header("access-control-allow-methods", &allowed)
+                .body(Body::empty())
+        }
+        Method::HEAD if conf.proxy.answer_head => {
+            info!("Answering HEAD request for {}", path);
+            builder.status(200).body(Body::empty())
+        }
+        _ => {
+            info!("Rejecting {} request for {} with 405 response", method, path);
+            let msg = format!("Method {} is not allowed (only {})", method, allowed);
+            let (content_type, body) = error_body(conf, 405, &msg, request_id);
+            builder
+                .status(405)
+                .header("content-type", content_type)
+                .body(Body::from(body))
+        }
+    };
+    // the error content type was checked when the configuration was validated
+    Some(resp.expect("Invalid method response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACTIVATION: &str = "/asnp/frl_connected/values/v2";
+
+    fn conf(answer_options: bool, answer_head: bool) -> Settings {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.proxy.answer_options = answer_options;
+        conf.proxy.answer_head = answer_head;
+        conf
+    }
+
+    fn allow(resp: &HResponse<Body>) -> &str {
+        resp.headers()["allow"].to_str().unwrap()
+    }
+
+    #[test]
+    fn accepted_method_goes_to_cops() {
+        let conf = conf(true, true);
+        assert!(answer_locally(&conf, &Method::POST, ACTIVATION, None).is_none());
+        let deactivation = "/asnp/frl_connected/v1";
+        assert!(answer_locally(&conf, &Method::DELETE, deactivation, None).is_none());
+        assert!(answer_locally(&conf, &Method::PUT, "/elsewhere", None).is_none());
+    }
+
+    #[test]
+    fn options_gets_204_with_allow() {
+        let resp = answer_locally(&conf(true, false), &Method::OPTIONS, ACTIVATION, None);
+        let resp = resp.unwrap();
+        assert_eq!(resp.status(), 204);
+        assert_eq!(allow(&resp), "POST, OPTIONS");
+        let resp =
+            answer_locally(&conf(false, false), &Method::OPTIONS, ACTIVATION, None);
+        assert_eq!(resp.unwrap().status(), 405);
+    }
+
+    #[test]
+    fn head_gets_200_only_when_answered() {
+        let resp = answer_locally(&conf(false, true), &Method::HEAD, ACTIVATION, None);
+        let resp = resp.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(allow(&resp), "POST, HEAD");
+        let resp = answer_locally(&conf(false, false), &Method::HEAD, ACTIVATION, None);
+        let resp = resp.unwrap();
+        assert_eq!(resp.status(), 405);
+        assert_eq!(allow(&resp), "POST");
+    }
+
+    #[tokio::test]
+    async fn other_method_gets_405_with_allow() {
+        let mut conf = conf(true, true);
+        conf.proxy.problem_details = true;
+        let resp = answer_locally(&conf, &Method::PUT, ACTIVATION, Some("req1")).unwrap();
+        assert_eq!(resp.status(), 405);
+        assert_eq!(allow(&resp), "POST, OPTIONS, HEAD");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 405);
+        assert_eq!(body["instance"], "req1");
+    }
+}
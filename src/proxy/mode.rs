@@ -0,0 +1,72 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Changing the mode of a running proxy.
+//!
+//! The `/admin/mode` endpoint can switch a running proxy between cache
+//! and store mode (and, if it was started in auto mode, back to auto),
+//! for example to stop calling COPS during an incident.  The change
+//! applies to requests received after it, and is in memory only: a
+//! restarted proxy runs in its configured mode again.
+//!
+//! Passthrough and forward mode can't be switched to or from, since
+//! they use the cache so differently.
+use crate::settings::{ProxyMode, Settings};
+use eyre::{eyre, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+static OVERRIDE: Lazy<Mutex<Option<ProxyMode>>> = Lazy::new(Default::default);
+
+/// The mode the proxy is running in right now.
+pub fn current(conf: &Settings) -> ProxyMode {
+    match &*OVERRIDE.lock().unwrap() {
+        Some(mode) => mode.clone(),
+        None => conf.proxy.mode.clone(),
+    }
+}
+
+/// Whether the configured mode has been changed at runtime.
+pub fn is_overridden() -> bool {
+    OVERRIDE.lock().unwrap().is_some()
+}
+
+/// Make settings reflect the mode the proxy is running in right now.
+pub fn apply(conf: &mut Settings) {
+    if let Some(mode) = &*OVERRIDE.lock().unwrap() {
+        conf.proxy.mode = mode.clone();
+    }
+}
+
+/// Run in the given mode from now on, or (with `None`) in the
+/// configured mode again.
+pub fn set(conf: &Settings, mode: Option<ProxyMode>) -> Result<()> {
+    let configured = &conf.proxy.mode;
+    if matches!(configured, ProxyMode::Passthrough | ProxyMode::Forward) {
+        return Err(eyre!("The mode of a {:?}-mode proxy can't be changed", configured));
+    }
+    let mode = match mode {
+        Some(ProxyMode::Auto) if !matches!(configured, ProxyMode::Auto) => {
+            return Err(eyre!("Only a proxy started in auto mode can switch to it"));
+        }
+        Some(ProxyMode::Passthrough) | Some(ProxyMode::Forward) => {
+            return Err(eyre!("Only cache, store and auto mode can be switched to"));
+        }
+        mode => mode,
+    };
+    let mut current = OVERRIDE.lock().unwrap();
+    match &mode {
+        Some(mode) => {
+            info!("Switching to {:?} mode (configured: {:?})", mode, configured)
+        }
+        None => info!("Switching back to the configured {:?} mode", configured),
+    }
+    *current = mode;
+    Ok(())
+}
@@ -6,42 +6,78 @@ NOTICE: Adobe permits you to use, modify, and distribute this file in
 accordance with the terms of the Adobe license agreement accompanying
 it.
 */
+use async_stream::stream;
 use eyre::{Report, Result, WrapErr};
+use futures_util::stream::Stream;
+use hyper::server::accept;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
-use log::info;
+use log::{error, info};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
 
-use super::{ctrl_c_handler, serve_req};
+use super::limits::{read_buffer_size, ConnectionLimiter, Tracked};
+use super::{bind_listener, serve_req, wait_for_shutdown};
 use crate::cache::Cache;
+use crate::logging::with_request_context;
 use crate::settings::Settings;
 
-pub async fn run_server(conf: &Settings, cache: Arc<Cache>) -> Result<()> {
+pub async fn run_server(
+    conf: &Settings, cache: Arc<Cache>, limiter: Arc<ConnectionLimiter>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
     let full_host = format!("{}:{}", conf.proxy.host, conf.proxy.port);
     let addr: SocketAddr = full_host.parse()?;
+    let tcp = bind_listener(conf, &full_host)
+        .await
+        .wrap_err(format!("Can't listen on {} - is the port free?", addr))?;
     info!("Listening on http://{}", addr);
-    let make_svc = make_service_fn(move |_| {
+    let make_svc = make_service_fn(move |conn: &Tracked<TcpStream>| {
+        let peer = conn.peer_ip();
         let conf = conf.clone();
         let cache = Arc::clone(&cache);
         async move {
             Ok::<_, Report>(service_fn(move |_req| {
                 let conf = conf.clone();
                 let cache = Arc::clone(&cache);
-                async move { serve_req(_req, conf, cache).await }
+                async move { with_request_context(serve_req(_req, conf, cache, peer)).await }
             }))
         }
     });
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    ctrl_c_handler(move || tx.send(()).unwrap_or(()));
-    let server = Server::bind(&addr).serve(make_svc);
+    let mut builder = Server::builder(accept::from_stream(incoming(tcp, limiter)));
+    if let Some(size) = read_buffer_size(conf) {
+        // HTTP/2 header lists can't be limited as they're read
+        builder = builder.http1_only(true).http1_max_buf_size(size);
+    }
+    let server = builder.serve(make_svc);
 
-    let graceful = server.with_graceful_shutdown(async {
-        rx.await.ok();
-    });
+    let graceful = server.with_graceful_shutdown(wait_for_shutdown(shutdown));
 
     // Run the server, keep going until an error occurs.
     info!("Starting to serve on http://{}", full_host);
     graceful.await.wrap_err("Unexpected server shutdown")?;
     Ok(())
 }
+
+fn incoming(
+    listener: TcpListener, limiter: Arc<ConnectionLimiter>,
+) -> impl Stream<Item = io::Result<Tracked<TcpStream>>> {
+    stream! {
+        loop {
+            limiter.pace().await;
+            // just swallow errors and wait again if necessary
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    // refused connections are closed by dropping them
+                    if let Some(permit) = limiter.admit(peer.ip()) {
+                        yield Ok(Tracked::new(stream, permit));
+                    }
+                }
+                Err(e) => { error!("Connection failure with client: {}", e); }
+            }
+        };
+    }
+}
@@ -0,0 +1,58 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::metrics;
+use crate::settings::Settings;
+use log::info;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The retry budget: a token bucket shared by everything that retries
+/// COPS calls, so that retries can't multiply the load an outage puts
+/// on COPS.  Each retry takes a token, and tokens are replaced at the
+/// configured rate, up to that many.
+struct Budget {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    refilled_at: Instant,
+}
+
+static BUDGET: Lazy<Mutex<Option<Budget>>> = Lazy::new(Default::default);
+
+/// Take a token from the retry budget, if there's one left.
+///
+/// A retry should only be made if this returns true.  There is no
+/// budget (every retry is allowed) if `retry_budget_per_minute` is 0.
+pub fn allow(conf: &Settings) -> bool {
+    let per_minute = conf.network.retry_budget_per_minute;
+    if per_minute == 0 {
+        return true;
+    }
+    let mut budget = BUDGET.lock().unwrap();
+    let budget = budget.get_or_insert_with(|| Budget {
+        tokens: per_minute as f64,
+        capacity: per_minute as f64,
+        per_sec: per_minute as f64 / 60.0,
+        refilled_at: Instant::now(),
+    });
+    let now = Instant::now();
+    let elapsed = now.duration_since(budget.refilled_at).as_secs_f64();
+    budget.tokens = (budget.tokens + elapsed * budget.per_sec).min(budget.capacity);
+    budget.refilled_at = now;
+    let allowed = budget.tokens >= 1.0;
+    if allowed {
+        budget.tokens -= 1.0;
+    } else {
+        info!("Retry budget is exhausted, not retrying");
+        metrics::count_denied_retry();
+    }
+    metrics::set_retry_budget(budget.tokens as u64);
+    allowed
+}
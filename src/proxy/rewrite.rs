@@ -0,0 +1,152 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::settings::{PathRewrite, Settings};
+use hyper::Uri;
+use log::{debug, error};
+
+/// Apply the configured normalization of paths to an incoming request's
+/// URI, keeping its query string.
+pub fn normalize_uri(conf: &Settings, uri: &mut Uri) {
+    let mut path = uri.path().to_string();
+    if conf.proxy.strip_trailing_slash && path.len() > 1 {
+        path = match path.trim_end_matches('/') {
+            "" => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+    }
+    if conf.proxy.fold_path_case {
+        path = path.to_lowercase();
+    }
+    if path != uri.path() {
+        set_path(uri, path);
+    }
+}
+
+/// Apply the configured path rewrites to an incoming request's URI,
+/// keeping its query string.
+pub fn rewrite_uri(conf: &Settings, uri: &mut Uri) {
+    let rules = &conf.proxy.path_rewrites;
+    if rules.is_empty() {
+        return;
+    }
+    let mut path = uri.path().to_string();
+    for rule in rules.iter() {
+        path = rewrite_path(rule, path);
+    }
+    if path != uri.path() {
+        set_path(uri, path);
+    }
+}
+
+/// Replace the path of a URI, keeping its query string.
+fn set_path(uri: &mut Uri, path: String) {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let mut parts = uri.clone().into_parts();
+    match path_and_query.parse() {
+        Ok(pq) => {
+            debug!("Rewrote request path {} to {}", uri.path(), path_and_query);
+            parts.path_and_query = Some(pq);
+            match Uri::from_parts(parts) {
+                Ok(rewritten) => *uri = rewritten,
+                Err(err) => {
+                    error!("Can't use rewritten path {}: {}", path_and_query, err)
+                }
+            }
+        }
+        Err(err) => error!("Can't use rewritten path {}: {}", path_and_query, err),
+    }
+}
+
+fn rewrite_path(rule: &PathRewrite, path: String) -> String {
+    if !rule.strip_prefix.is_empty() {
+        // only whole path segments are stripped
+        match path.strip_prefix(&rule.strip_prefix) {
+            Some("") => "/".to_string(),
+            Some(rest) if rest.starts_with('/') => rest.to_string(),
+            _ => path,
+        }
+    } else if !rule.add_prefix.is_empty() {
+        format!("{}{}", rule.add_prefix, path)
+    } else if let Some(pattern) = &rule.compiled {
+        pattern.replace_all(&path, rule.replacement.as_str()).into_owned()
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(prefix: &str) -> PathRewrite {
+        PathRewrite { strip_prefix: prefix.to_string(), ..Default::default() }
+    }
+
+    fn conf(rules: Vec<PathRewrite>) -> Settings {
+        let mut conf = Settings::read_config(&[]).unwrap();
+        conf.proxy.path_rewrites = rules;
+        // validation compiles the patterns, and checks the (default) cert
+        conf.proxy.ssl = false;
+        conf.validate().unwrap();
+        conf
+    }
+
+    fn rewritten(conf: &Settings, uri: &str) -> String {
+        let mut uri: Uri = uri.parse().unwrap();
+        rewrite_uri(conf, &mut uri);
+        uri.to_string()
+    }
+
+    #[test]
+    fn strips_whole_segments_only() {
+        let rule = strip("/frl");
+        assert_eq!(rewrite_path(&rule, "/frl/x".to_string()), "/x");
+        assert_eq!(rewrite_path(&rule, "/frl".to_string()), "/");
+        assert_eq!(rewrite_path(&rule, "/frlx".to_string()), "/frlx");
+        assert_eq!(rewrite_path(&rule, "/other/x".to_string()), "/other/x");
+    }
+
+    #[test]
+    fn adds_prefix() {
+        let rule = PathRewrite { add_prefix: "/frl".to_string(), ..Default::default() };
+        assert_eq!(rewrite_path(&rule, "/x".to_string()), "/frl/x");
+    }
+
+    #[test]
+    fn replaces_pattern_with_capture_groups() {
+        let conf = conf(vec![PathRewrite {
+            pattern: "^/v(\\d+)/(.*)$".to_string(),
+            replacement: "/$2/v$1".to_string(),
+            ..Default::default()
+        }]);
+        assert_eq!(rewritten(&conf, "/v2/asnp"), "/asnp/v2");
+        assert_eq!(rewritten(&conf, "/asnp"), "/asnp");
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let pattern = PathRewrite {
+            pattern: "^/old/".to_string(),
+            replacement: "/new/".to_string(),
+            ..Default::default()
+        };
+        let conf = conf(vec![strip("/frl"), pattern]);
+        assert_eq!(rewritten(&conf, "/frl/old/x"), "/new/x");
+    }
+
+    #[test]
+    fn rewrite_keeps_query_byte_for_byte() {
+        let conf = conf(vec![strip("/frl")]);
+        assert_eq!(rewritten(&conf, "/frl/x?q=1&a=%2B%20b"), "/x?q=1&a=%2B%20b");
+        assert_eq!(rewritten(&conf, "/frlx?q=1"), "/frlx?q=1");
+    }
+}
@@ -6,9 +6,11 @@ NOTICE: Adobe permits you to use, modify, and distribute this file in
 accordance with the terms of the Adobe license agreement accompanying
 it.
 */
-use super::{ctrl_c_handler, serve_req};
+use super::limits::{read_buffer_size, ConnectionLimiter, Tracked};
+use super::{bind_listener, serve_req, wait_for_shutdown};
 use crate::cache::Cache;
-use crate::settings::Settings;
+use crate::logging::with_request_context;
+use crate::settings::{Settings, TlsVersion};
 use async_stream::stream;
 use core::task::{Context, Poll};
 use eyre::{Report, Result, WrapErr};
@@ -19,66 +21,149 @@ use log::{error, info};
 use std::fs::File;
 use std::io::Read;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tokio::io;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tokio_native_tls::{native_tls, TlsAcceptor, TlsStream};
 
-pub async fn run_server(conf: &Settings, cache: Arc<Cache>) -> Result<()> {
-    let acceptor = {
-        let path = &conf.ssl.cert_path;
-        let password = &conf.ssl.cert_password;
-        let mut file = File::open(path)
-            .wrap_err(format!("Can't open SSL certificate file: {}", path))?;
-        let mut identity = vec![];
-        file.read_to_end(&mut identity)
-            .wrap_err(format!("Can't read SSL cert data from file: {}", path))?;
-        let identity = native_tls::Identity::from_pkcs12(&identity, password)
-            .wrap_err("Can't decrypt SSL cert data - incorrect password?")?;
-        let sync_acceptor = native_tls::TlsAcceptor::new(identity)
-            .wrap_err("Can't create TLS socket listener - is the port free?")?;
-        let async_acceptor: TlsAcceptor = sync_acceptor.into();
-        async_acceptor
-    };
+pub async fn run_server(
+    conf: &Settings, cache: Arc<Cache>, limiter: Arc<ConnectionLimiter>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let acceptor = Arc::new(RwLock::new(Arc::new(load_acceptor(conf)?)));
+    let reloader = spawn_reloader(conf, Arc::clone(&acceptor));
     let full_host = format!("{}:{}", conf.proxy.host, conf.proxy.ssl_port);
-    let tcp = TcpListener::bind(&full_host).await?;
-    let incoming_tls_stream = incoming(tcp, acceptor).boxed();
+    let tcp = bind_listener(conf, &full_host)
+        .await
+        .wrap_err(format!("Can't listen on {} - is the port free?", full_host))?;
+    let incoming_tls_stream = incoming(tcp, acceptor, limiter).boxed();
     let hyper_acceptor = HyperAcceptor { acceptor: incoming_tls_stream };
-    let service = make_service_fn(move |_| {
+    let service = make_service_fn(move |conn: &Tracked<TlsStream<TcpStream>>| {
+        let peer = conn.peer_ip();
         let conf = conf.clone();
         let cache = Arc::clone(&cache);
         async move {
             Ok::<_, Report>(service_fn(move |_req| {
                 let conf = conf.clone();
                 let cache = Arc::clone(&cache);
-                async move { serve_req(_req, conf, cache).await }
+                async move { with_request_context(serve_req(_req, conf, cache, peer)).await }
             }))
         }
     });
-    let server = Server::builder(hyper_acceptor).serve(service);
+    let mut builder = Server::builder(hyper_acceptor).http1_only(!conf.ssl.http2);
+    if let Some(size) = read_buffer_size(conf) {
+        // validation makes sure this is HTTP/1.1 only
+        builder = builder.http1_max_buf_size(size);
+    }
+    let server = builder.serve(service);
 
-    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-    ctrl_c_handler(move || tx.send(()).unwrap_or(()));
-    let graceful = server.with_graceful_shutdown(async {
-        rx.await.ok();
-    });
+    let graceful = server.with_graceful_shutdown(wait_for_shutdown(shutdown));
 
     // Run the server, keep going until an error occurs.
+    if conf.ssl.http2 {
+        info!("HTTP/2 is enabled on https://{}", full_host);
+    }
     info!("Starting to serve on https://{}", full_host);
-    graceful.await.wrap_err("Unexpected server shutdown")?;
-    Ok(())
+    let result = graceful.await.wrap_err("Unexpected server shutdown");
+    if let Some(reloader) = reloader {
+        reloader.abort();
+    }
+    result
+}
+
+/// Create a TLS acceptor from the configured certificate file.
+pub fn load_acceptor(conf: &Settings) -> Result<TlsAcceptor> {
+    let path = &conf.ssl.cert_path;
+    let password = conf.ssl.cert_password.expose();
+    let mut file = File::open(path)
+        .wrap_err(format!("Can't open SSL certificate file: {}", path))?;
+    let mut identity = vec![];
+    file.read_to_end(&mut identity)
+        .wrap_err(format!("Can't read SSL cert data from file: {}", path))?;
+    let identity = native_tls::Identity::from_pkcs12(&identity, password)
+        .wrap_err("Can't decrypt SSL cert data - incorrect password?")?;
+    let sync_acceptor = native_tls::TlsAcceptor::builder(identity)
+        .min_protocol_version(Some(tls_protocol(&conf.ssl.min_tls_version)))
+        .max_protocol_version(match &conf.ssl.max_tls_version {
+            // native-tls has no TLS 1.3 constant: no maximum allows it
+            None | Some(TlsVersion::Tls13) => None,
+            Some(max) => Some(tls_protocol(max)),
+        })
+        .build()
+        .wrap_err("Can't create TLS socket listener - is the port free?")?;
+    Ok(sync_acceptor.into())
+}
+
+fn tls_protocol(version: &TlsVersion) -> native_tls::Protocol {
+    match version {
+        TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+        TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+        // there is no TLS 1.3 constant, and validation rejects it as a minimum
+        TlsVersion::Tls12 | TlsVersion::Tls13 => native_tls::Protocol::Tlsv12,
+    }
+}
+
+/// Reload the certificate whenever we receive a SIGHUP.
+///
+/// Connections already established keep the certificate they were
+/// accepted with; new connections get the reloaded one.  If the reload
+/// fails, we keep using the certificate we already have.
+#[cfg(unix)]
+fn spawn_reloader(
+    conf: &Settings, current: Arc<RwLock<Arc<TlsAcceptor>>>,
+) -> Option<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Can't listen for SIGHUP, certificate reload is disabled: {}", e);
+            return None;
+        }
+    };
+    let conf = conf.clone();
+    Some(tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            info!("Received SIGHUP, reloading SSL certificate: {}", conf.ssl.cert_path);
+            match load_acceptor(&conf) {
+                Ok(acceptor) => {
+                    *current.write().unwrap() = Arc::new(acceptor);
+                    info!("Reloaded SSL certificate");
+                }
+                Err(e) => {
+                    error!("Keeping current SSL certificate, reload failed: {:?}", e)
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn spawn_reloader(
+    _conf: &Settings, _current: Arc<RwLock<Arc<TlsAcceptor>>>,
+) -> Option<JoinHandle<()>> {
+    None
 }
 
 fn incoming(
-    listener: TcpListener, acceptor: TlsAcceptor,
-) -> impl Stream<Item = TlsStream<TcpStream>> {
+    listener: TcpListener, acceptor: Arc<RwLock<Arc<TlsAcceptor>>>,
+    limiter: Arc<ConnectionLimiter>,
+) -> impl Stream<Item = Tracked<TlsStream<TcpStream>>> {
     stream! {
         loop {
+            limiter.pace().await;
             // just swallow errors and wait again if necessary
             match listener.accept().await {
-                Ok((stream, _)) => {
+                Ok((stream, peer)) => {
+                    // refused connections are closed by dropping them
+                    let permit = match limiter.admit(peer.ip()) {
+                        Some(permit) => permit,
+                        None => continue,
+                    };
+                    let acceptor = Arc::clone(&acceptor.read().unwrap());
                     match acceptor.accept(stream).await {
-                        Ok(x) => { yield x; }
+                        Ok(x) => { yield Tracked::new(x, permit); }
                         Err(e) => { error!("SSL Failure with client: {}", e); }
                     }
                 }
@@ -89,11 +174,11 @@ fn incoming(
 }
 
 struct HyperAcceptor<'a> {
-    acceptor: Pin<Box<dyn Stream<Item = TlsStream<TcpStream>> + 'a>>,
+    acceptor: Pin<Box<dyn Stream<Item = Tracked<TlsStream<TcpStream>>> + 'a>>,
 }
 
 impl hyper::server::accept::Accept for HyperAcceptor<'_> {
-    type Conn = TlsStream<TcpStream>;
+    type Conn = Tracked<TlsStream<TcpStream>>;
     type Error = io::Error;
 
     fn poll_accept(
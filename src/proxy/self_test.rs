@@ -0,0 +1,64 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Checks made before the proxy starts, with `start --self-test`.
+//!
+//! The cache is read and written (inside a transaction that's rolled
+//! back, so nothing is left behind), and COPS is probed.  Each result is
+//! logged.  A cache that fails always fails the self-test; whether COPS
+//! failing does is decided by `proxy.self_test_cops`.
+use super::probe_cops;
+use crate::cache::Cache;
+use crate::settings::{ProxyMode, SelfTestCops, Settings};
+use eyre::{eyre, Result};
+use log::{error, info, warn};
+
+/// Run the self-test, failing if a required check fails.
+pub async fn run(conf: &Settings, cache: &Cache) -> Result<()> {
+    let mut failures = Vec::new();
+    if let ProxyMode::Passthrough = conf.proxy.mode {
+        info!("Self-test: cache not used in passthrough mode");
+    } else {
+        match cache.round_trip().await {
+            Ok(()) => info!("Self-test: cache db {} is usable", conf.cache.db_path),
+            Err(err) => {
+                error!("Self-test: cache db {} failed: {:#}", conf.cache.db_path, err);
+                failures.push("cache");
+            }
+        }
+    }
+    let required = match conf.proxy.self_test_cops {
+        SelfTestCops::Auto => {
+            !matches!(conf.proxy.mode, ProxyMode::Store | ProxyMode::Auto)
+        }
+        SelfTestCops::Require => true,
+        SelfTestCops::Warn => false,
+        SelfTestCops::Skip => {
+            info!("Self-test: not probing COPS");
+            return finish(failures);
+        }
+    };
+    match probe_cops(conf).await {
+        Ok(status) => info!("Self-test: COPS is reachable (status {})", status),
+        Err(err) if required => {
+            error!("Self-test: COPS is unreachable: {:#}", err);
+            failures.push("COPS");
+        }
+        Err(err) => warn!("Self-test: COPS is unreachable (not required): {:#}", err),
+    }
+    finish(failures)
+}
+
+fn finish(failures: Vec<&str>) -> Result<()> {
+    if failures.is_empty() {
+        info!("Self-test passed");
+        Ok(())
+    } else {
+        Err(eyre!("Self-test failed: {}", failures.join(", ")))
+    }
+}
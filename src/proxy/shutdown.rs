@@ -0,0 +1,202 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! The order in which a serving proxy drains when it shuts down.
+//!
+//! Two things may need draining: the listeners, which finish the client
+//! requests in flight, and a forwarding run in the background (started
+//! from the admin listener or by the backlog age trigger), which
+//! finishes the request it's forwarding.  With
+//! `proxy.shutdown_order` set to `concurrent` both are told to stop at
+//! once.  With `serving-first` or `forwarding-first`, one is told to
+//! stop, and the other keeps going until the first has drained (or has
+//! had `proxy.shutdown_first_share` of the deadline).
+//!
+//! When `proxy.shutdown_timeout_secs` is set, whatever hasn't drained by
+//! then is abandoned.
+//!
+//! With `forward.forward_on_shutdown` set, once everything has drained
+//! (and if COPS can be reached) there is one last forwarding run, to
+//! shrink the backlog left behind.  It's told to stop at the deadline,
+//! if there is one, and then gets `forward.shutdown_grace_secs` to
+//! finish the request it's forwarding, as any interrupted run does.
+use super::{admin, forward_until_shutdown, probe_cops, wait_for_shutdown};
+use crate::cache::Cache;
+use crate::settings::{ProxyMode, Settings, ShutdownOrder};
+use eyre::Result;
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Run the listeners until the shutdown signal, then drain them and any
+/// forwarding run in the configured order, and then (if configured)
+/// forward what's left of the backlog in `cache`.
+///
+/// `stop_serving` and `stop_forwarding` tell the listeners and the
+/// forwarding run to stop.
+pub async fn run<F>(
+    conf: &Settings, signal: watch::Receiver<bool>, serving: F,
+    stop_serving: watch::Sender<bool>, stop_forwarding: watch::Sender<bool>,
+    cache: Arc<Cache>,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    tokio::pin!(serving);
+    tokio::select! {
+        result = &mut serving => {
+            // the listeners failed, so there's nothing to drain but forwarding
+            stop_forwarding.send(true).unwrap_or(());
+            admin::finish_forward_run().await;
+            return result;
+        }
+        _ = wait_for_shutdown(signal) => (),
+    }
+    let order = &conf.proxy.shutdown_order;
+    let deadline = match conf.proxy.shutdown_timeout_secs {
+        0 => None,
+        secs => Some(Instant::now() + Duration::from_secs(secs)),
+    };
+    let forwarding = admin::is_forwarding();
+    info!(
+        "Draining on shutdown ({}, {}): {} forwarding run",
+        order.as_str(),
+        match conf.proxy.shutdown_timeout_secs {
+            0 => "no deadline".to_string(),
+            secs => format!("{}s deadline", secs),
+        },
+        if forwarding { "with a" } else { "no" }
+    );
+    let finishing = admin::finish_forward_run();
+    tokio::pin!(finishing);
+    let mut served = None;
+    let mut forwarded = false;
+    let first = deadline.map(|deadline| {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .mul_f64(conf.proxy.shutdown_first_share)
+    });
+    match order {
+        ShutdownOrder::Concurrent => {
+            stop_serving.send(true).unwrap_or(());
+            stop_forwarding.send(true).unwrap_or(());
+        }
+        ShutdownOrder::ServingFirst => {
+            stop_serving.send(true).unwrap_or(());
+            served = within(first, &mut serving).await;
+            if served.is_none() {
+                warn!(
+                    "Listeners didn't drain within {:?}: stopping forwarding",
+                    first.unwrap_or_default()
+                );
+            }
+            stop_forwarding.send(true).unwrap_or(());
+        }
+        ShutdownOrder::ForwardingFirst => {
+            stop_forwarding.send(true).unwrap_or(());
+            // the listeners keep serving meanwhile
+            tokio::select! {
+                drained = within(first, &mut finishing) => {
+                    forwarded = drained.is_some();
+                    if !forwarded {
+                        warn!("Forwarding didn't drain within {:?}: stopping listeners", first.unwrap_or_default());
+                    }
+                }
+                result = &mut serving => served = Some(result),
+            }
+            stop_serving.send(true).unwrap_or(());
+        }
+    }
+    let rest =
+        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let remaining = async {
+        let served = match served {
+            Some(result) => result,
+            None => serving.await,
+        };
+        if !forwarded {
+            finishing.await;
+        }
+        served
+    };
+    match within(rest, remaining).await {
+        Some(result) => {
+            info!("Drained listeners and forwarding");
+            forward_backlog(conf, cache, deadline).await;
+            result
+        }
+        None => {
+            warn!(
+                "Abandoned draining after {}s: shutting down anyway",
+                conf.proxy.shutdown_timeout_secs
+            );
+            Ok(())
+        }
+    }
+}
+
+/// The last forwarding run before exiting, if `forward.forward_on_shutdown`
+/// is set and COPS can be reached, which stops at the deadline.
+async fn forward_backlog(conf: &Settings, cache: Arc<Cache>, deadline: Option<Instant>) {
+    if !conf.forward.forward_on_shutdown
+        || matches!(conf.proxy.mode, ProxyMode::Passthrough)
+    {
+        return;
+    }
+    let left =
+        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    match within(left, probe_cops(conf)).await {
+        Some(Ok(_)) => (),
+        Some(Err(err)) => {
+            warn!(
+                "Not forwarding stored requests on shutdown: COPS is unreachable: {:#}",
+                err
+            );
+            return;
+        }
+        None => {
+            warn!("Not forwarding stored requests on shutdown: no time left before the deadline");
+            return;
+        }
+    }
+    info!("Forwarding stored requests before shutting down");
+    let (stop, rx) = watch::channel(false);
+    let run = forward_until_shutdown(conf, cache, false, rx);
+    tokio::pin!(run);
+    // without a deadline, the run goes on until it's done
+    let summary = match deadline {
+        Some(deadline) => tokio::select! {
+            summary = &mut run => summary,
+            _ = tokio::time::sleep_until(deadline.into()) => {
+                info!("Shutdown deadline reached: stopping the final forwarding run");
+                stop.send(true).unwrap_or(());
+                run.await
+            }
+        },
+        None => run.await,
+    };
+    let forwarded = summary.successes + summary.failures + summary.errors;
+    let total = forwarded + summary.deferred + summary.skipped;
+    info!(
+        "Final forwarding run sent {} of {} stored request(s): {} succeeded, {} left",
+        forwarded,
+        total,
+        summary.successes,
+        total - summary.successes
+    );
+}
+
+/// Wait for a future, giving up after `limit` (if there is one).
+async fn within<F: Future>(limit: Option<Duration>, fut: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
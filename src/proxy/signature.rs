@@ -0,0 +1,70 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Verification of signed licensing requests.
+//!
+//! With `signing.enabled`, every licensing request must carry, in the
+//! `signing.header` header, the hex-encoded HMAC (keyed by
+//! `signing.secret`) of its method, path and query, and body, each
+//! followed by a newline except the body:
+//!
+//! ```text
+//! POST\n/asnp/frl_connected/values/v2\n{"npdId": ...}
+//! ```
+//!
+//! The path is the one the client sent, before any rewriting.  Requests
+//! whose signature is missing or wrong are rejected with a 401.
+use crate::settings::{Settings, SignatureAlgorithm};
+use hmac::{Hmac, Mac, NewMac};
+use hyper::{HeaderMap, Method, Uri};
+use sha1::Sha1;
+use sha2::Sha256;
+
+/// Check the signature of a request, if signatures are required,
+/// returning why it's not acceptable if it isn't.
+pub fn check(
+    conf: &Settings, method: &Method, uri: &Uri, headers: &HeaderMap, body: &[u8],
+) -> Result<(), &'static str> {
+    if !conf.signing.enabled {
+        return Ok(());
+    }
+    let signature = match headers.get(conf.signing.header.as_str()) {
+        Some(value) => value.to_str().unwrap_or_default().trim(),
+        None => return Err("request is not signed"),
+    };
+    let signature = hex::decode(signature).map_err(|_| "signature is not hex")?;
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let key = conf.signing.secret.expose().as_bytes();
+    let ok = match conf.signing.algorithm {
+        SignatureAlgorithm::HmacSha1 => {
+            verify::<Hmac<Sha1>>(key, method, path, body, &signature)
+        }
+        SignatureAlgorithm::HmacSha256 => {
+            verify::<Hmac<Sha256>>(key, method, path, body, &signature)
+        }
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err("signature doesn't match")
+    }
+}
+
+fn verify<M: Mac + NewMac>(
+    key: &[u8], method: &Method, path: &str, body: &[u8], signature: &[u8],
+) -> bool {
+    // HMACs take keys of any length
+    let mut mac = M::new_from_slice(key).expect("HMAC refused key");
+    mac.update(method.as_str().as_bytes());
+    mac.update(b"\n");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    // this comparison takes the same time wherever the signatures differ
+    mac.verify(signature).is_ok()
+}
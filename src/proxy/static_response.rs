@@ -0,0 +1,35 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Fixed responses for configured paths, such as a maintenance notice
+//! shown during a planned COPS outage.
+//!
+//! A static response is served for any request to its path, without
+//! calling COPS or using the cache.  Paths are matched after rewriting,
+//! and a path that's a COPS endpoint is only served statically if the
+//! response says so with `shadow_cops`.  (The admin endpoints are served
+//! on their own listener, so static responses never shadow them.)
+use crate::cops::agent;
+use crate::settings::Settings;
+use hyper::{Body, Response as HResponse};
+use log::info;
+
+/// The static response configured for a path, if there is one.
+pub fn for_path(conf: &Settings, path: &str) -> Option<HResponse<Body>> {
+    let route = conf.proxy.static_responses.iter().find(|r| r.path == path)?;
+    info!("Serving static response ({}) for {}", route.status, path);
+    let resp = HResponse::builder()
+        .status(route.status)
+        .header("content-type", &route.content_type)
+        .header("server", agent())
+        .body(Body::from(route.body.clone()))
+        // the status and content type were checked when the
+        // configuration was validated
+        .expect("Invalid static response");
+    Some(resp)
+}
@@ -0,0 +1,44 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Remapping of the statuses of COPS failures passed on to clients,
+//! for client stacks that handle some statuses badly.
+//!
+//! Each remap in `proxy.status_remaps` replaces one COPS status with
+//! another, e.g. a 403 with a 401.  The body COPS sent is kept unless
+//! the remap gives one (and, optionally, its content type).  Only COPS
+//! failures returned as they are get remapped: not cached responses
+//! served in their place, nor the proxy's own error responses.
+use crate::settings::Settings;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response as HResponse, StatusCode};
+use log::info;
+
+/// Apply the configured remap, if any, to a COPS failure response.
+pub fn apply(conf: &Settings, mut resp: HResponse<Body>) -> HResponse<Body> {
+    let status = resp.status().as_u16();
+    let remap = match conf.proxy.status_remaps.iter().find(|r| r.from == status) {
+        Some(remap) => remap,
+        None => return resp,
+    };
+    info!("Remapping COPS status {} to {}", status, remap.to);
+    // the statuses and content type were checked when the configuration
+    // was validated
+    *resp.status_mut() = StatusCode::from_u16(remap.to).expect("Invalid remapped status");
+    if let Some(body) = &remap.body {
+        let headers = resp.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        if let Some(content_type) = &remap.content_type {
+            let value =
+                HeaderValue::from_str(content_type).expect("Invalid content type");
+            headers.insert(CONTENT_TYPE, value);
+        }
+        *resp.body_mut() = Body::from(body.clone());
+    }
+    resp
+}
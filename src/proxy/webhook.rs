@@ -0,0 +1,160 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+//! Notification of the end of a forwarding run.
+//!
+//! When `forward.webhook_url` is set, a `POST` with a JSON body is sent to
+//! it after each forwarding run.  The body looks like this:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "event": "forwarding-complete",
+//!   "namespace": "",
+//!   "started_at": "2021-07-01T10:15:00.123+0000",
+//!   "finished_at": "2021-07-01T10:15:02.456+0000",
+//!   "duration_ms": 2333,
+//!   "successes": 12,
+//!   "failures": 1,
+//!   "errors": 0,
+//!   "deferred": 0,
+//!   "skipped": 0,
+//!   "remaining": 1
+//! }
+//! ```
+//!
+//! `remaining` is the number of stored requests that still need to be
+//! forwarded after the run.  The other counts are those of
+//! `ForwardSummary`.  Fields won't be removed or change meaning while
+//! `version` is 1, but new fields may be added, so consumers should
+//! ignore fields they don't know.
+use super::ForwardSummary;
+use crate::cops::agent;
+use crate::settings::Settings;
+use eyre::{eyre, Result, WrapErr};
+use hyper::{Body, Client, Request as HRequest};
+use hyper_tls::HttpsConnector;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::time::Duration;
+
+/// The body of a webhook notification.
+#[derive(Debug, Serialize)]
+pub struct Completion<'a> {
+    pub version: u32,
+    pub event: &'static str,
+    pub namespace: &'a str,
+    pub started_at: &'a str,
+    pub finished_at: &'a str,
+    pub duration_ms: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub errors: u64,
+    pub deferred: u64,
+    pub skipped: u64,
+    pub remaining: u64,
+}
+
+impl<'a> Completion<'a> {
+    pub fn new(
+        conf: &'a Settings, summary: &ForwardSummary, started_at: &'a str,
+        finished_at: &'a str, duration: Duration, remaining: u64,
+    ) -> Completion<'a> {
+        Completion {
+            version: 1,
+            event: "forwarding-complete",
+            namespace: &conf.cache.namespace,
+            started_at,
+            finished_at,
+            duration_ms: duration.as_millis() as u64,
+            successes: summary.successes,
+            failures: summary.failures,
+            errors: summary.errors,
+            deferred: summary.deferred,
+            skipped: summary.skipped,
+            remaining,
+        }
+    }
+}
+
+/// Send a notification to the configured webhook, if there is one.
+///
+/// Failures are retried a limited number of times and then logged:
+/// a broken webhook never fails the forwarding run.
+pub async fn notify(conf: &Settings, completion: &Completion<'_>) {
+    let url = conf.forward.webhook_url.expose();
+    if !url.is_empty() {
+        deliver(conf, url, completion).await;
+    }
+}
+
+/// POST a JSON payload to a webhook, retrying (a limited number of
+/// times) if that fails.
+pub async fn deliver<T: Serialize>(conf: &Settings, url: &str, payload: &T) {
+    let body = match serde_json::to_string(payload) {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Can't encode webhook notification: {}", err);
+            return;
+        }
+    };
+    let shown = shown_url(url);
+    let attempts = conf.forward.webhook_retries + 1;
+    for attempt in 1..=attempts {
+        match post(conf, url, &body).await {
+            Ok(()) => {
+                info!("Sent notification to webhook {}", shown);
+                return;
+            }
+            Err(err) => {
+                warn!(
+                    "Webhook {} failed (attempt {} of {}): {:#}",
+                    shown, attempt, attempts, err
+                )
+            }
+        }
+        if attempt < attempts {
+            tokio::time::sleep(WEBHOOK_RETRY_DELAY).await;
+        }
+    }
+}
+
+/// A webhook URL as it's logged: just its scheme and host, since the
+/// rest of it (or credentials in it) may be a token.
+fn shown_url(url: &str) -> String {
+    match url.parse::<hyper::Uri>() {
+        Ok(uri) => format!(
+            "{}://{}",
+            uri.scheme_str().unwrap_or("http"),
+            uri.host().unwrap_or_default()
+        ),
+        Err(_) => "(invalid URL)".to_string(),
+    }
+}
+
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+async fn post(conf: &Settings, url: &str, body: &str) -> Result<()> {
+    let req = HRequest::post(url)
+        .header("content-type", "application/json")
+        .header("user-agent", agent())
+        .body(Body::from(body.to_string()))
+        .wrap_err("Invalid webhook request")?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let timeout = Duration::from_secs(conf.forward.webhook_timeout_secs);
+    let resp = tokio::time::timeout(timeout, client.request(req))
+        .await
+        .map_err(|_| eyre!("No response within {:?}", timeout))?
+        .wrap_err("Can't reach webhook")?;
+    debug!("Webhook responded with {:?}", resp.status());
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(eyre!("Webhook responded with {}", resp.status()))
+    }
+}
@@ -7,6 +7,7 @@ accordance with the terms of the Adobe license agreement accompanying
 it.
 */
 use crate::cli::FrlProxy;
+use crate::cops::{self, Kind};
 use config::{Config, Environment, File as ConfigFile, FileFormat};
 use dialoguer::{Confirm, Input, Password, Select};
 use eyre::{eyre, Report, Result, WrapErr};
@@ -23,12 +24,150 @@ pub struct Proxy {
     pub ssl_port: String,
     pub remote_host: String,
     pub ssl: bool,
+    /// When SSL is enabled, also listen for plain http on `port`.
+    pub also_plain: bool,
+    pub max_response_bytes: usize,
+    /// The `Host` header sent to COPS, when it should differ from the
+    /// host in `remote_host` (which is still where we connect).
+    pub cops_host_header: Option<String>,
+    /// The server name used for TLS (SNI and certificate validation) when
+    /// it should differ from the host in `remote_host`, e.g. an IP address.
+    pub cops_sni_hostname: Option<String>,
+    /// Whether to tell clients the id of their request in a response header.
+    pub send_request_id: bool,
+    /// The name of the response header carrying the request id.
+    pub request_id_header: String,
+    /// A request header (e.g. `X-Correlation-Id`) whose value is used as
+    /// the request id when a request has no `X-Request-Id` (empty for none).
+    pub correlation_header: String,
+    /// Give requests with no id (from either header) a generated one,
+    /// rather than rejecting them.
+    pub generate_request_ids: bool,
+    /// Rules applied, in order, to the path of each incoming request
+    /// before it is matched against the COPS endpoints.
+    pub path_rewrites: Vec<PathRewrite>,
+    /// Remove slashes from the end of each incoming request's path
+    /// (before any rewrites), so `/v2/` is the same endpoint as `/v2`.
+    pub strip_trailing_slash: bool,
+    /// Lower-case the path of each incoming request (before any
+    /// rewrites), so paths which differ only in case are the same.
+    pub fold_path_case: bool,
+    /// Pass COPS response bodies on to clients as they arrive, when the
+    /// response won't be cached (in passthrough mode, or for live kinds).
+    pub stream_uncached: bool,
+    /// The content type of the error responses made by the proxy (other
+    /// than problem details).
+    pub error_content_type: String,
+    /// Give the proxy's own error responses RFC 7807 problem bodies
+    /// (`application/problem+json`), rather than `statusCode` and
+    /// `message` ones.
+    pub problem_details: bool,
+    /// Fixed responses served for particular paths (after rewriting),
+    /// without calling COPS or using the cache.
+    pub static_responses: Vec<StaticResponse>,
+    /// Replacements for the statuses of COPS failures passed on to
+    /// clients, e.g. a 401 for a 403.
+    pub status_remaps: Vec<StatusRemap>,
+    /// Answer `OPTIONS` requests to the COPS endpoints with the methods
+    /// they allow, rather than rejecting them.
+    pub answer_options: bool,
+    /// Answer `HEAD` requests to the COPS endpoints with an empty
+    /// success, rather than rejecting them (e.g. for health checkers).
+    pub answer_head: bool,
+    /// A response header (e.g. `X-FRL-Upstream-Ms`) telling clients how
+    /// long COPS took to respond, or where the response came from if not
+    /// from COPS (empty for none).
+    pub upstream_latency_header: String,
+    /// Finish the COPS call for a request (and cache its response) even
+    /// if the client disconnects first, rather than cancelling it.
+    pub complete_on_disconnect: bool,
+    /// Whether `start --self-test` fails when COPS can't be reached.
+    pub self_test_cops: SelfTestCops,
+    /// Whether client requests or a forwarding run are drained first
+    /// on shutdown.
+    pub shutdown_order: ShutdownOrder,
+    /// Seconds after the shutdown signal that draining is abandoned
+    /// (0 to wait for as long as it takes).
+    pub shutdown_timeout_secs: u64,
+    /// Fraction of the shutdown deadline given to the part drained first
+    /// before the other is told to stop as well, from 0 to 1.
+    pub shutdown_first_share: f64,
+    /// Milliseconds without licensing requests after which the proxy
+    /// shuts down (0 to keep running).
+    pub idle_shutdown_ms: u64,
+    /// Identifies this proxy in its log lines and metrics, where those
+    /// of several proxies are gathered together (empty for none).
+    pub instance_id: String,
+}
+
+/// A fixed response served for requests to a path, e.g. a maintenance
+/// notice.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StaticResponse {
+    pub path: String,
+    pub status: u16,
+    pub content_type: String,
+    pub body: String,
+    /// Serve this response even though the path is a COPS endpoint
+    /// (so licensing requests get it rather than a COPS response).
+    pub shadow_cops: bool,
+}
+
+impl Default for StaticResponse {
+    fn default() -> Self {
+        StaticResponse {
+            path: String::new(),
+            status: 200,
+            content_type: "text/plain".to_string(),
+            body: String::new(),
+            shadow_cops: false,
+        }
+    }
+}
+
+/// A replacement for the status (and, optionally, the body) of COPS
+/// failures with a particular status.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusRemap {
+    pub from: u16,
+    pub to: u16,
+    /// The body sent instead of the one from COPS (if any).
+    pub body: Option<String>,
+    /// The content type of `body` (if it differs from that of COPS).
+    pub content_type: Option<String>,
+}
+
+/// A rewrite of incoming request paths.  Each rule does one of: strip
+/// a prefix, add a prefix, or replace matches of a regular expression.
+/// A rule whose prefix or pattern doesn't match leaves the path alone.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathRewrite {
+    pub strip_prefix: String,
+    pub add_prefix: String,
+    pub pattern: String,
+    /// The replacement for `pattern` matches, which may refer to
+    /// capture groups as `$1` or `${name}`.
+    pub replacement: String,
+    /// `pattern`, compiled when the settings are validated.
+    #[serde(skip)]
+    pub compiled: Option<regex::Regex>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Ssl {
     pub cert_path: String,
-    pub cert_password: String,
+    pub cert_password: Secret,
+    pub min_tls_version: TlsVersion,
+    /// No maximum means the highest version supported by the platform.
+    pub max_tls_version: Option<TlsVersion>,
+    /// Serve HTTP/2 as well as HTTP/1.1 on the secure listener.
+    ///
+    /// The platform TLS libraries don't let us advertise `h2` via ALPN,
+    /// so only clients that use HTTP/2 with prior knowledge will use it.
+    pub http2: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,11 +175,156 @@ pub struct Logging {
     pub level: LogLevel,
     pub destination: LogDestination,
     pub file_path: String,
+    /// The `host:port` of a remote syslog server (empty for the local syslog).
+    pub syslog_address: String,
+    pub syslog_app_name: String,
+    /// Log the settings in effect (with secrets redacted) at startup.
+    pub log_settings: bool,
+    /// Write a line for each request served to a log of its own.
+    pub access_log: AccessLogFormat,
+    pub access_log_path: String,
+    /// End access log lines with the request id and COPS latency.
+    pub access_log_extensions: bool,
+    /// JSON paths (e.g. `$.deviceDetails.deviceId`) of body fields whose
+    /// values are replaced with `***` when bodies are logged.
+    pub redact_paths: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Cache {
     pub db_path: String,
+    /// Seconds a cached response may be served (0 means forever).
+    pub response_ttl_secs: u64,
+    /// Seconds a stored request remains eligible for forwarding (0 means forever).
+    pub max_forward_age_secs: u64,
+    /// Seconds by which the clock may step back (or a cached response's
+    /// timestamp be in the future) without a warning.  Responses dated
+    /// further in the future than this are treated as expired.
+    pub clock_skew_secs: u64,
+    /// Seconds between background purges while serving (0 means never).
+    pub purge_interval_secs: u64,
+    /// Partition of the cache db used by this proxy (empty for the default).
+    pub namespace: String,
+    /// Only the latest response for each request is served, but with this
+    /// set every response received is also kept in a history table.
+    pub keep_response_history: bool,
+    /// Seconds to wait for another proxy to release the cache db.
+    pub lock_timeout_secs: u64,
+    /// Most un-forwarded requests kept in the cache (0 means no limit).
+    pub max_stored_requests: u64,
+    /// What to do with a new request when `max_stored_requests` is reached.
+    pub when_full: FullPolicy,
+    /// What to do with a request that can't be stored because the cache
+    /// db can't be written (a disk error, say).
+    pub when_unavailable: UnavailablePolicy,
+    /// Request headers whose values are part of the key of cached
+    /// activation responses (e.g. a tenant header).
+    ///
+    /// Changing the key factors makes existing cached activations misses,
+    /// because they were stored under a different key.  Stored requests
+    /// keep the key they arrived with.
+    pub key_headers: Vec<String>,
+    /// Serve cached responses which have expired, but by no more than the
+    /// stale window, without waiting for COPS, refreshing them from COPS
+    /// in the background.  (Fresh responses are used as usual.)  This
+    /// needs a response TTL.
+    pub stale_while_revalidate: bool,
+    /// Seconds past `response_ttl_secs` that a response may still be
+    /// served while it's refreshed.
+    pub stale_window_secs: u64,
+    /// Request fields left out of the activation key: `app_id` and/or
+    /// `ngl_version`.  (The package and device are always part of it.)
+    pub key_ignore: Vec<String>,
+    /// Media types a successful COPS response must have to be used and
+    /// cached (empty to accept any).  Others are treated as failures.
+    pub content_types: Vec<String>,
+    /// When COPS can't answer, return its failure rather than a cached
+    /// response.  Requests are still stored for forwarding.  In store
+    /// mode this means every request gets the offline response, even if
+    /// a cached response to it exists.
+    pub fail_closed: bool,
+    /// A request header (e.g. `X-FRL-Prefer-Cache`) with which clients can
+    /// ask to get a cached response, when there is one, without COPS
+    /// being called (empty to ignore such requests).  It's ignored when
+    /// the cache fails closed.
+    pub prefer_cache_header: String,
+    /// What to do with a successful COPS response that has no body.
+    pub empty_success_body: EmptyBodyPolicy,
+    /// Largest COPS response body that's cached, in bytes (0 for no
+    /// limit).  Bigger responses are still returned to clients.
+    pub max_cacheable_response_bytes: usize,
+    /// Ask COPS to revalidate a cached response which has an `ETag` or
+    /// `Last-Modified`, rather than send it again: when COPS answers 304
+    /// Not Modified, the cached response is served and made fresh again.
+    pub revalidate: bool,
+    /// How cached responses are checksummed when they are stored.
+    pub checksum: Checksum,
+    /// Check cached responses against their checksums before serving
+    /// them, treating those that don't match as not cached.
+    pub verify_checksums: bool,
+    /// Delete cached responses whose checksums don't match.
+    pub delete_corrupt: bool,
+    /// Keys of cached responses which never expire or get purged (as
+    /// listed by the `diff --details` command).
+    pub pinned_keys: Vec<String>,
+    /// Serve from a cache db that another proxy writes to, without
+    /// changing it (e.g. on a warm standby).  Nothing is stored, and the
+    /// cache isn't locked.
+    pub read_only: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Forward {
+    /// Space out forwarded requests to match the gaps between their arrivals.
+    pub preserve_timing: bool,
+    /// Factor applied to the preserved gaps (less than 1 replays faster).
+    pub timing_scale: f64,
+    /// Longest pause between forwarded requests, in seconds (0 for no cap).
+    pub max_gap_secs: u64,
+    /// Seconds to wait before retrying a request whose forwarding failed,
+    /// doubling with each failure (0 to retry on every run).
+    pub retry_backoff_secs: u64,
+    /// Longest wait before retrying a request (0 for no cap).
+    pub retry_backoff_max_secs: u64,
+    /// Fraction by which retry waits are randomly varied, from 0 to 1.
+    pub retry_jitter: f64,
+    /// Failed attempts after which a request is dead-lettered: set aside
+    /// until it's requeued (0 to keep retrying forever).
+    pub max_attempts: u32,
+    /// URL sent a summary of each forwarding run (empty for none).
+    /// It's a secret, since webhook URLs often carry a token.
+    pub webhook_url: Secret,
+    /// Times to retry a webhook notification that fails.
+    pub webhook_retries: u32,
+    /// Seconds to wait for the webhook to respond.
+    pub webhook_timeout_secs: u64,
+    /// Seconds a forwarding run interrupted by a shutdown signal waits
+    /// for the request in flight to be answered.
+    pub shutdown_grace_secs: u64,
+    /// When a serving proxy shuts down, make one last forwarding run
+    /// (within the shutdown deadline) if COPS can be reached.
+    pub forward_on_shutdown: bool,
+    /// Log, for each successfully forwarded request, whether its response
+    /// differs from the one cached before.
+    pub audit_changes: bool,
+    /// While serving, start a forwarding run once the oldest stored
+    /// request waiting to be forwarded is older than this, in seconds,
+    /// and COPS is reachable (0 for no such trigger).
+    pub trigger_age_secs: u64,
+    /// Seconds between checks of the age of the oldest stored request.
+    pub trigger_check_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Auto {
+    /// Consecutive failed COPS calls after which an auto-mode proxy
+    /// works offline.
+    pub offline_after_failures: u32,
+    /// Consecutive successful probes of COPS after which an offline
+    /// auto-mode proxy works connected again.
+    pub online_after_probes: u32,
+    /// Seconds between probes of COPS while working offline.
+    pub probe_interval_secs: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -50,7 +334,196 @@ pub struct Network {
     pub proxy_port: String,
     pub use_basic_auth: bool,
     pub proxy_username: String,
-    pub proxy_password: String,
+    pub proxy_password: Secret,
+    /// Whether forwarding runs first check that COPS can be reached
+    /// through the proxy, rather than failing each request in turn.
+    pub proxy_precheck: bool,
+    /// Milliseconds to wait for a connection to the proxy (0 for no
+    /// limit but `cops_timeout_ms`, which covers the whole call).
+    pub proxy_connect_timeout_ms: u64,
+    /// Seconds to reuse resolved addresses for hosts we connect to (0 disables).
+    pub dns_cache_ttl_secs: u64,
+    /// Whether to use HTTP/2 when talking to COPS.
+    pub cops_http2: Http2Mode,
+    /// Most idle connections to COPS kept open for reuse (no limit if unset).
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection to COPS is kept open (0 for no limit).
+    pub pool_idle_timeout_secs: u64,
+    /// Seconds after which pooled connections to COPS are retired, however
+    /// busy they are, so new ones are opened (0 for no limit).
+    pub pool_max_connection_age_secs: u64,
+    /// Milliseconds to wait for COPS to respond to a licensing request,
+    /// including any time spent connecting to it or to the proxy.
+    pub cops_timeout_ms: u64,
+    /// A request header (e.g. `X-FRL-Timeout-Ms`) in which clients can
+    /// ask for a different COPS timeout (empty to ignore such requests).
+    pub timeout_header: String,
+    /// The longest COPS timeout clients can ask for.
+    pub max_timeout_ms: u64,
+    /// Times to retry a COPS call that gets no response or a server error.
+    pub cops_retries: u32,
+    /// Most retries (of COPS calls and of stored requests) allowed per
+    /// minute, across the whole proxy (0 for no limit).
+    pub retry_budget_per_minute: u32,
+    /// Consecutive failed COPS calls after which COPS is considered down
+    /// (0 to not track whether COPS is up).
+    pub cops_down_after_failures: u32,
+    /// URL sent a notification when COPS goes down or comes back up
+    /// (empty for none).  It's a secret, as `forward.webhook_url` is.
+    pub health_webhook_url: Secret,
+    /// Least number of seconds between notifications of COPS health.
+    pub health_debounce_secs: u64,
+    /// Milliseconds a probe of COPS may take before it counts as failed.
+    pub probe_timeout_ms: u64,
+    /// The method and path of probes of COPS, which should be cheap.
+    pub probe_method: ProbeMethod,
+    pub probe_path: String,
+    /// Client headers passed on to COPS ("*" for all).  The headers COPS
+    /// needs are always sent, so by default no others are.
+    pub client_headers_allow: Vec<String>,
+    /// Client headers never passed on to COPS, even if allowed.
+    pub client_headers_deny: Vec<String>,
+    /// Tell COPS the client's address in an `X-Forwarded-For` and/or
+    /// (RFC 7239) `Forwarded` header.  Off by default, for privacy.
+    pub forwarded_header: ForwardedHeader,
+    /// Whether the client's address is added to the chain in any such
+    /// header the client sent, or replaces it.
+    pub forwarded_mode: ForwardedMode,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Limits {
+    /// Most connections open at once, from all clients (0 for no limit).
+    pub max_connections: usize,
+    /// Most connections open at once from any one IP address (0 for no limit).
+    pub max_connections_per_ip: usize,
+    /// IP addresses which are exempt from the connection limits.
+    pub connection_allowlist: Vec<String>,
+    /// Connections the OS may queue for each listener before they are
+    /// accepted (0 for the default of 1024).
+    pub listen_backlog: u32,
+    /// Most connections accepted per second, from all clients (0 for no
+    /// limit).  Connections beyond that wait in the backlog.
+    pub max_accepts_per_sec: u32,
+    /// Most bytes of request bodies held in memory at once, across all
+    /// requests (0 for no limit).  Requests beyond that get a 503.
+    pub max_in_flight_bytes: u64,
+    /// Most bytes of header names and values in a request (0 for hyper's
+    /// own limit).  Requests beyond that get a 431.
+    ///
+    /// The limit applies while headers are read only for HTTP/1.1, so
+    /// with it set the plain listener doesn't accept HTTP/2 (with prior
+    /// knowledge), and `ssl.http2` can't be set: an HTTP/2 client could
+    /// otherwise send headers of any size before they were checked.
+    pub max_header_bytes: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metrics {
+    /// Push metrics to statsd (requires a build with the `statsd` feature).
+    pub statsd: bool,
+    pub statsd_host: String,
+    pub statsd_port: String,
+    pub statsd_prefix: String,
+    pub statsd_interval_secs: u64,
+    /// Seconds between log lines summarizing the metrics (0 for none).
+    pub log_interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Admin {
+    /// Serve the admin endpoints, on a listener of their own.
+    pub enabled: bool,
+    /// Address of the admin listener, which should not be reachable by
+    /// licensing clients.
+    pub host: String,
+    pub port: String,
+    /// Serve `/status`: the proxy mode, cache summary, and auto-mode state.
+    pub status: bool,
+    /// Serve `/metrics`: the metrics, in the Prometheus text format.
+    pub metrics: bool,
+    /// Serve `/version`.
+    pub version: bool,
+    /// Serve `/readyz`: whether the proxy is healthy, for orchestrators.
+    pub readyz: bool,
+    /// Un-forwarded requests in the cache beyond which `/readyz` reports
+    /// the proxy unhealthy (0 to ignore the backlog).
+    pub max_unforwarded_requests: u64,
+    /// Probe COPS for each `/readyz` request, and report the proxy
+    /// unhealthy when the probe fails.
+    pub readyz_probe: bool,
+    /// Serve `/maintenance`, which turns maintenance mode on (`POST`)
+    /// and off (`DELETE`).
+    pub maintenance: bool,
+    /// Serve `/admin/mode`, which changes the proxy mode until a restart
+    /// (`POST`), or back to the configured one (`DELETE`).
+    pub mode: bool,
+    /// Serve `/admin/forward`, which starts a forwarding run (`POST`),
+    /// and `/admin/forward/<run-id>`, which reports on one.
+    pub forward: bool,
+    /// Serve `/requests`: the stored requests waiting to be forwarded.
+    pub requests: bool,
+    /// Serve `/admin/cache/<key>`, which removes the cached responses with a
+    /// key (`DELETE`).
+    pub cache: bool,
+    /// When set, admin requests other than `GET`s must carry this token
+    /// as `Authorization: Bearer <token>`.
+    pub token: Secret,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Maintenance {
+    /// Start the proxy in maintenance mode, answering every licensing
+    /// request with the response below rather than calling COPS.
+    pub enabled: bool,
+    /// The status of the maintenance response.
+    pub status: u16,
+    /// The message in the maintenance response.
+    pub message: String,
+    /// Store requests received in maintenance mode, for forwarding later.
+    pub store_requests: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Signing {
+    /// Reject licensing requests that don't carry a valid signature.
+    pub enabled: bool,
+    /// The key of the signatures, shared with the clients.
+    pub secret: Secret,
+    /// The request header carrying the (hex-encoded) signature.
+    pub header: String,
+    pub algorithm: SignatureAlgorithm,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KindPolicy {
+    pub policy: CachePolicy,
+    /// Seconds a cached response of this kind may be served, overriding
+    /// `cache.response_ttl_secs` (0 means forever).
+    pub response_ttl_secs: Option<u64>,
+    /// Whether failed COPS calls for this kind are retried (per
+    /// `network.cops_retries`).  Only kinds which are safe to send
+    /// twice should be.
+    pub retry: bool,
+    /// Cached responses of this kind never expire or get purged,
+    /// whatever their TTL.
+    pub pinned: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Kinds {
+    pub activation: KindPolicy,
+    pub deactivation: KindPolicy,
+}
+
+impl Kinds {
+    /// The configured handling for a given kind of request.
+    pub fn policy(&self, kind: &Kind) -> &KindPolicy {
+        match kind {
+            Kind::Activation => &self.activation,
+            Kind::Deactivation => &self.deactivation,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -59,55 +532,117 @@ pub struct Settings {
     pub ssl: Ssl,
     pub logging: Logging,
     pub cache: Cache,
+    pub forward: Forward,
+    pub auto: Auto,
     pub network: Network,
+    pub limits: Limits,
+    pub metrics: Metrics,
+    pub admin: Admin,
+    pub maintenance: Maintenance,
+    pub signing: Signing,
+    pub kinds: Kinds,
+}
+
+/// The largest listen backlog the OS will honor, if we can tell.
+fn max_listen_backlog() -> Option<u32> {
+    if cfg!(target_os = "linux") {
+        let max = std::fs::read_to_string("/proc/sys/net/core/somaxconn").ok()?;
+        max.trim().parse().ok()
+    } else {
+        Some(i32::MAX as u32)
+    }
+}
+
+/// The request fields which may be left out of the activation key.
+pub const IGNORABLE_KEY_FIELDS: [&str; 2] = ["app_id", "ngl_version"];
+
+const MAX_DNS_CACHE_TTL_SECS: u64 = 3600;
+
+/// Namespaces prefix cache keys, so they are limited to characters
+/// which can't be confused with the key separators.
+pub fn validate_namespace(ns: &str) -> Result<()> {
+    if ns.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Namespace '{}' may only contain letters, digits, '-', '_' and '.'",
+            ns
+        ))
+    }
 }
 
 impl Settings {
+    /// Load the config files named on the command line, or (if there is
+    /// a single one, and it doesn't exist) create it and return `None`.
     pub fn load_config(args: &FrlProxy) -> Result<Option<Self>> {
-        let path = args.config_file.as_str();
-        if std::fs::metadata(path).is_ok() {
-            let mut s = Config::new();
-            s.merge(ConfigFile::from_str(
-                include_str!("res/defaults.toml"),
-                FileFormat::Toml,
-            ))?;
+        let paths = &args.config_file;
+        if let [path] = paths.as_slice() {
+            if std::fs::metadata(path).is_err() {
+                eprintln!("Creating initial configuration file...");
+                let template = include_str!("res/defaults.toml");
+                let mut file = File::create(path)
+                    .wrap_err(format!("Cannot create config file: {}", path))?;
+                file.write_all(template.as_bytes())
+                    .wrap_err(format!("Cannot write config file: {}", path))?;
+                return Ok(None);
+            }
+        }
+        // only a lone config file is created: a missing layer is a mistake
+        for path in paths.iter() {
+            std::fs::metadata(path)
+                .wrap_err(format!("Can't read config file: {}", path))?;
+        }
+        let mut conf = Self::read_config(paths)?;
+        match args.debug {
+            1 => conf.logging.level = LogLevel::Debug,
+            2 => conf.logging.level = LogLevel::Trace,
+            _ => {}
+        }
+        if let Some(log_to) = &args.log_to {
+            let destination: LogDestination = log_to
+                .as_str()
+                .try_into()
+                .wrap_err(format!("Not a recognized log destination: {}", log_to))?;
+            conf.logging.destination = destination;
+        }
+        Ok(Some(conf))
+    }
+
+    /// Read existing config files, over the defaults and under any
+    /// environment overrides.
+    ///
+    /// The files are merged in order, so a setting in a later file
+    /// overrides the same setting in an earlier one.  Sections are
+    /// merged field by field: an override file only needs the settings
+    /// it changes.  (Lists, such as `proxy.static_responses`, are
+    /// replaced as a whole.)  `FRL_PROXY_*` environment variables then
+    /// override all the files, and command-line flags all of those.
+    pub fn read_config(paths: &[String]) -> Result<Self> {
+        let mut s = Config::new();
+        s.merge(ConfigFile::from_str(
+            include_str!("res/defaults.toml"),
+            FileFormat::Toml,
+        ))?;
+        for path in paths.iter() {
             s.merge(ConfigFile::with_name(path).format(FileFormat::Toml))?;
-            s.merge(Environment::with_prefix("frl_proxy"))?;
-            let mut conf: Self = s.try_into()?;
-            match args.debug {
-                1 => conf.logging.level = LogLevel::Debug,
-                2 => conf.logging.level = LogLevel::Trace,
-                _ => {}
-            }
-            if let Some(log_to) = &args.log_to {
-                let destination: LogDestination = log_to
-                    .as_str()
-                    .try_into()
-                    .wrap_err(format!("Not a recognized log destination: {}", log_to))?;
-                conf.logging.destination = destination;
-            }
-            Ok(Some(conf))
-        } else {
-            eprintln!("Creating initial configuration file...");
-            let template = include_str!("res/defaults.toml");
-            let mut file = File::create(path)
-                .wrap_err(format!("Cannot create config file: {}", path))?;
-            file.write_all(template.as_bytes())
-                .wrap_err(format!("Cannot write config file: {}", path))?;
-            Ok(None)
         }
+        s.merge(Environment::with_prefix("frl_proxy"))?;
+        Ok(s.try_into()?)
     }
 
     pub fn update_config_file(&mut self, path: &str) -> Result<()> {
         // update proxy settings including cache db
-        eprintln!("The proxy has four modes: cache, store, forward, and passthrough.");
+        eprintln!(
+            "The proxy has five modes: cache, store, forward, passthrough, and auto."
+        );
         eprintln!("Read the user guide to understand which is right for each situation.");
-        let choices = vec!["cache", "store", "forward", "passthrough"];
+        let choices = vec!["cache", "store", "forward", "passthrough", "auto"];
         let default = match self.proxy.mode {
             ProxyMode::Cache => 0,
             ProxyMode::Store => 1,
             ProxyMode::Forward => 2,
             ProxyMode::Passthrough => 3,
+            ProxyMode::Auto => 4,
         };
         let choice = Select::new()
             .items(&choices)
@@ -116,7 +651,10 @@ impl Settings {
             .interact()?;
         let choice: ProxyMode = choices[choice].try_into().unwrap();
         self.proxy.mode = choice;
-        if let ProxyMode::Cache | ProxyMode::Store | ProxyMode::Forward = self.proxy.mode
+        if let ProxyMode::Cache
+        | ProxyMode::Store
+        | ProxyMode::Forward
+        | ProxyMode::Auto = self.proxy.mode
         {
             eprintln!("The proxy uses a SQLite database to keep track of requests and responses.");
             eprintln!(
@@ -179,6 +717,12 @@ impl Settings {
                 .with_initial_text(&self.proxy.ssl_port)
                 .interact_text()?;
             self.proxy.ssl_port = choice;
+            let choice = Confirm::new()
+                .default(self.proxy.also_plain)
+                .wait_for_newline(false)
+                .with_prompt("Also accept http (non-ssl) connections on the http port?")
+                .interact()?;
+            self.proxy.also_plain = choice;
             eprintln!(
                 "The proxy requires a certificate store in PKCS format to use SSL."
             );
@@ -209,7 +753,7 @@ impl Settings {
             eprintln!(
                 "in the value of an environment variable (FRL_PROXY_SSL.CERT_PASSWORD)."
             );
-            let prompt = if self.ssl.cert_password.is_empty() {
+            let prompt = if self.ssl.cert_password.expose().is_empty() {
                 "Do you want to store a password in your configuration file?"
             } else {
                 "Do you want to update the password in your configuration file?"
@@ -225,7 +769,7 @@ impl Settings {
                     .with_confirmation("Confirm password", "Passwords don't match")
                     .allow_empty_password(true)
                     .interact()?;
-                self.ssl.cert_password = choice;
+                self.ssl.cert_password = choice.into();
             }
         }
         // update network settings
@@ -262,9 +806,9 @@ impl Settings {
                 self.network.proxy_username = choice;
                 let choice: String = Input::new()
                     .with_prompt("Proxy password")
-                    .with_initial_text(&self.network.proxy_password)
+                    .with_initial_text(self.network.proxy_password.expose())
                     .interact_text()?;
-                self.network.proxy_password = choice;
+                self.network.proxy_password = choice.into();
             }
         }
         // update log settings
@@ -279,15 +823,29 @@ impl Settings {
             .with_prompt(prompt)
             .interact()?;
         if choice {
-            eprintln!("The proxy can log to the console (standard output) or to a file on disk.");
-            let choices = vec!["console", "disk file"];
+            eprintln!("The proxy can log to the console (standard output), to a file on disk, or to syslog.");
+            let choices = vec!["console", "disk file", "syslog"];
             let choice = Select::new()
                 .items(&choices)
                 .default(1)
                 .with_prompt("Log destination")
                 .interact()?;
-            self.logging.destination =
-                if choice == 0 { LogDestination::Console } else { LogDestination::File };
+            self.logging.destination = match choice {
+                0 => LogDestination::Console,
+                1 => LogDestination::File,
+                _ => LogDestination::Syslog,
+            };
+            if choice == 2 {
+                eprintln!(
+                    "Leave the syslog server empty to use the local syslog (Unix only)."
+                );
+                let choice: String = Input::new()
+                    .allow_empty(true)
+                    .with_prompt("Syslog server (host:port)")
+                    .with_initial_text(&self.logging.syslog_address)
+                    .interact_text()?;
+                self.logging.syslog_address = choice;
+            }
             if choice == 1 {
                 let choice: String = Input::new()
                     .allow_empty(false)
@@ -348,15 +906,223 @@ impl Settings {
             }
             std::fs::metadata(path)
                 .wrap_err(format!("Invalid certificate path: {}", path))?;
+            if let TlsVersion::Tls13 = self.ssl.min_tls_version {
+                return Err(eyre!("A minimum TLS version of 1.3 is not supported"));
+            }
+            if let Some(max) = &self.ssl.max_tls_version {
+                if *max < self.ssl.min_tls_version {
+                    return Err(eyre!(
+                        "No usable TLS versions: maximum ({}) is below minimum ({})",
+                        max,
+                        self.ssl.min_tls_version
+                    ));
+                }
+            }
+            // hyper can't limit HTTP/2 header lists while reading them
+            if self.ssl.http2 && self.limits.max_header_bytes > 0 {
+                return Err(eyre!(
+                    "HTTP/2 on the secure listener can't be used with a limit on header bytes"
+                ));
+            }
+        }
+        if self.proxy.ssl
+            && self.proxy.also_plain
+            && self.proxy.port == self.proxy.ssl_port
+        {
+            return Err(eyre!("The 'port' and 'ssl_port' must differ to listen on both"));
+        }
+        if let Some(host) = &self.proxy.cops_host_header {
+            if host.is_empty() || hyper::header::HeaderValue::from_str(host).is_err() {
+                return Err(eyre!("Invalid COPS host header: '{}'", host));
+            }
+        }
+        if self.proxy.send_request_id
+            && hyper::header::HeaderName::from_bytes(
+                self.proxy.request_id_header.as_bytes(),
+            )
+            .is_err()
+        {
+            return Err(eyre!(
+                "Invalid request id header name: '{}'",
+                self.proxy.request_id_header
+            ));
+        }
+        if !self.proxy.correlation_header.is_empty()
+            && hyper::header::HeaderName::from_bytes(
+                self.proxy.correlation_header.as_bytes(),
+            )
+            .is_err()
+        {
+            return Err(eyre!(
+                "Invalid correlation header name: '{}'",
+                self.proxy.correlation_header
+            ));
+        }
+        let webhooks = [
+            ("forward.webhook_url", &self.forward.webhook_url),
+            ("network.health_webhook_url", &self.network.health_webhook_url),
+        ];
+        for (name, url) in webhooks {
+            let url = url.expose();
+            if !url.is_empty() {
+                let uri = url.parse::<hyper::Uri>();
+                if !matches!(
+                    uri.as_ref().map(|u| u.scheme_str()),
+                    Ok(Some("http" | "https"))
+                ) {
+                    return Err(eyre!("Invalid webhook URL in {}", name));
+                }
+            }
+        }
+        if let Some(name) = &self.proxy.cops_sni_hostname {
+            if name.is_empty() {
+                return Err(eyre!("The COPS SNI hostname can't be empty"));
+            }
+            if self.network.use_proxy {
+                return Err(eyre!(
+                    "The COPS SNI hostname can't be used with an upstream proxy"
+                ));
+            }
+        }
+        match self.proxy.remote_host.parse::<hyper::Uri>() {
+            Ok(uri) if uri.host().is_some() => {}
+            _ => return Err(eyre!("Invalid COPS URL: '{}'", self.proxy.remote_host)),
+        }
+        for rule in self.proxy.path_rewrites.iter_mut() {
+            let actions = [&rule.strip_prefix, &rule.add_prefix, &rule.pattern];
+            if actions.iter().filter(|a| !a.is_empty()).count() != 1 {
+                return Err(eyre!(
+                    "Each path rewrite must have one of strip_prefix, add_prefix, or pattern"
+                ));
+            }
+            if !rule.pattern.is_empty() {
+                let pattern = regex::Regex::new(&rule.pattern).wrap_err(format!(
+                    "Invalid path rewrite pattern: '{}'",
+                    rule.pattern
+                ))?;
+                rule.compiled = Some(pattern);
+            }
+        }
+        if hyper::header::HeaderValue::from_str(&self.proxy.error_content_type).is_err() {
+            return Err(eyre!(
+                "Invalid error content type: '{}'",
+                self.proxy.error_content_type
+            ));
+        }
+        let name = &self.cache.prefer_cache_header;
+        if !name.is_empty()
+            && hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+        {
+            return Err(eyre!("Invalid prefer-cache header name: '{}'", name));
+        }
+        let name = &self.proxy.upstream_latency_header;
+        if !name.is_empty()
+            && hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+        {
+            return Err(eyre!("Invalid upstream latency header name: '{}'", name));
+        }
+        for remap in self.proxy.status_remaps.iter() {
+            for status in [remap.from, remap.to] {
+                match hyper::StatusCode::from_u16(status) {
+                    Ok(status) if status.is_client_error() || status.is_server_error() => (),
+                    _ => {
+                        return Err(eyre!(
+                            "Status remap from {} to {}: only 4xx and 5xx statuses can be remapped",
+                            remap.from,
+                            remap.to
+                        ))
+                    }
+                }
+            }
+            if let Some(content_type) = &remap.content_type {
+                if remap.body.is_none() {
+                    return Err(eyre!(
+                        "Status remap from {} has a content type but no body",
+                        remap.from
+                    ));
+                }
+                if hyper::header::HeaderValue::from_str(content_type).is_err() {
+                    return Err(eyre!(
+                        "Invalid content type for status remap from {}",
+                        remap.from
+                    ));
+                }
+            }
+        }
+        for route in self.proxy.static_responses.iter() {
+            if !route.path.starts_with('/') {
+                return Err(eyre!(
+                    "Static response path must start with '/': '{}'",
+                    route.path
+                ));
+            }
+            if hyper::StatusCode::from_u16(route.status).is_err() {
+                return Err(eyre!(
+                    "Invalid status {} for static response to {}",
+                    route.status,
+                    route.path
+                ));
+            }
+            if hyper::header::HeaderValue::from_str(&route.content_type).is_err() {
+                return Err(eyre!(
+                    "Invalid content type for static response to {}",
+                    route.path
+                ));
+            }
+            if cops::is_endpoint(&route.path) && !route.shadow_cops {
+                return Err(eyre!(
+                    "Static response to {} would shadow a COPS endpoint (set shadow_cops to allow this)",
+                    route.path
+                ));
+            }
         }
         if self.proxy.host.contains(':') {
             return Err(eyre!("Host must not contain a port (use the 'port' and 'ssl_port' config options)"));
         }
-        if let ProxyMode::Cache | ProxyMode::Store | ProxyMode::Forward = self.proxy.mode
+        if let ProxyMode::Auto = self.proxy.mode {
+            if self.auto.offline_after_failures == 0 || self.auto.online_after_probes == 0
+            {
+                return Err(eyre!(
+                    "Auto mode needs at least one failure and one probe to switch"
+                ));
+            }
+            if self.auto.probe_interval_secs == 0 {
+                return Err(eyre!("The auto mode probe interval can't be 0"));
+            }
+        }
+        if let ProxyMode::Cache
+        | ProxyMode::Store
+        | ProxyMode::Forward
+        | ProxyMode::Auto = self.proxy.mode
         {
             if self.cache.db_path.is_empty() {
                 return Err(eyre!("Database path can't be empty when cache is enabled"));
             }
+            validate_namespace(&self.cache.namespace)?;
+        }
+        if !self.forward.timing_scale.is_finite() || self.forward.timing_scale <= 0.0 {
+            return Err(eyre!("Forward timing scale must be a positive number"));
+        }
+        for path in self.logging.redact_paths.iter() {
+            if crate::logging::redact::parse_path(path).is_none() {
+                return Err(eyre!("Invalid redaction path: '{}'", path));
+            }
+        }
+        let id = &self.proxy.instance_id;
+        if !id.chars().all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)) {
+            return Err(eyre!(
+                "Instance id '{}' may only contain letters, digits, '-' and '_'",
+                id
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.proxy.shutdown_first_share) {
+            return Err(eyre!("Shutdown first share must be between 0 and 1"));
+        }
+        if self.forward.trigger_age_secs > 0 && self.forward.trigger_check_secs == 0 {
+            return Err(eyre!("The forward trigger check interval can't be 0"));
+        }
+        if !(0.0..=1.0).contains(&self.forward.retry_jitter) {
+            return Err(eyre!("Forward retry jitter must be between 0 and 1"));
         }
         if self.network.use_proxy {
             if self.network.proxy_host.is_empty() {
@@ -369,6 +1135,156 @@ impl Settings {
                 return Err(eyre!("Proxy username can't be empty"));
             }
         }
+        if self.network.cops_timeout_ms == 0 {
+            return Err(eyre!("COPS timeout must be positive"));
+        }
+        if !self.network.timeout_header.is_empty() {
+            let name = self.network.timeout_header.as_bytes();
+            if hyper::header::HeaderName::from_bytes(name).is_err() {
+                return Err(eyre!(
+                    "Invalid timeout header name: '{}'",
+                    self.network.timeout_header
+                ));
+            }
+            if self.network.max_timeout_ms == 0 {
+                return Err(eyre!("Maximum COPS timeout must be positive"));
+            }
+        }
+        if self.network.probe_timeout_ms == 0 {
+            return Err(eyre!("Probe timeout must be positive"));
+        }
+        if !self.network.probe_path.starts_with('/')
+            || self.network.probe_path.parse::<hyper::Uri>().is_err()
+        {
+            return Err(eyre!("Invalid probe path: '{}'", self.network.probe_path));
+        }
+        for name in self
+            .network
+            .client_headers_allow
+            .iter()
+            .chain(self.network.client_headers_deny.iter())
+            .filter(|name| name.as_str() != "*")
+        {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(eyre!("Invalid client header name: '{}'", name));
+            }
+        }
+        for name in self.cache.key_headers.iter() {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(eyre!("Invalid cache key header name: '{}'", name));
+            }
+        }
+        for field in self.cache.key_ignore.iter() {
+            if !IGNORABLE_KEY_FIELDS.contains(&field.as_str()) {
+                return Err(eyre!(
+                    "Cache key field '{}' can't be ignored (only {:?} can)",
+                    field,
+                    IGNORABLE_KEY_FIELDS
+                ));
+            }
+        }
+        if self.cache.fail_closed && self.cache.stale_while_revalidate {
+            return Err(eyre!("A fail-closed cache can't serve stale responses"));
+        }
+        if self.cache.stale_while_revalidate {
+            let ttl = self.cache.response_ttl_secs;
+            let kinds = [&self.kinds.activation, &self.kinds.deactivation];
+            if kinds.iter().all(|k| k.response_ttl_secs.unwrap_or(ttl) == 0) {
+                return Err(eyre!(
+                    "Stale responses can only be served if responses expire (set a response TTL)"
+                ));
+            }
+        }
+        if self.cache.content_types.iter().any(|t| !t.contains('/')) {
+            return Err(eyre!("Cache content types must look like 'type/subtype'"));
+        }
+        if self.cache.read_only
+            && matches!(self.proxy.mode, ProxyMode::Store | ProxyMode::Forward)
+        {
+            return Err(eyre!(
+                "A read-only cache can't be used in {:?} mode",
+                self.proxy.mode
+            ));
+        }
+        if self.cache.delete_corrupt && !self.cache.verify_checksums {
+            return Err(eyre!(
+                "Corrupt responses can only be deleted if checksums are verified"
+            ));
+        }
+        if let Some(max) = max_listen_backlog() {
+            if self.limits.listen_backlog > max {
+                return Err(eyre!(
+                    "The listen backlog ({}) exceeds the OS limit (net.core.somaxconn) of {}",
+                    self.limits.listen_backlog,
+                    max
+                ));
+            }
+        }
+        for ip in self.limits.connection_allowlist.iter() {
+            if ip.parse::<std::net::IpAddr>().is_err() {
+                return Err(eyre!(
+                    "Connection allowlist entry is not an IP address: {}",
+                    ip
+                ));
+            }
+        }
+        if let Http2Mode::Alpn = self.network.cops_http2 {
+            if self.network.use_proxy {
+                return Err(eyre!(
+                    "HTTP/2 negotiation with COPS can't be used with an upstream proxy"
+                ));
+            }
+        }
+        if self.network.dns_cache_ttl_secs > MAX_DNS_CACHE_TTL_SECS {
+            return Err(eyre!(
+                "The DNS cache TTL can be at most {} seconds, so DNS changes are noticed",
+                MAX_DNS_CACHE_TTL_SECS
+            ));
+        }
+        if self.metrics.statsd {
+            if !cfg!(feature = "statsd") {
+                return Err(eyre!("This proxy was built without statsd support"));
+            }
+            if self.metrics.statsd_host.is_empty() || self.metrics.statsd_port.is_empty()
+            {
+                return Err(eyre!("Statsd host and port must be specified"));
+            }
+        }
+        if self.admin.enabled {
+            if self.admin.host.is_empty() || self.admin.port.is_empty() {
+                return Err(eyre!("Admin host and port must be specified"));
+            }
+            let mut ports = vec![&self.proxy.port];
+            if self.proxy.ssl {
+                ports.push(&self.proxy.ssl_port);
+            }
+            if ports.contains(&&self.admin.port) {
+                return Err(eyre!("The admin port must differ from the proxy port(s)"));
+            }
+        }
+        if hyper::StatusCode::from_u16(self.maintenance.status).is_err() {
+            return Err(eyre!("Invalid maintenance status: {}", self.maintenance.status));
+        }
+        if self.maintenance.store_requests && self.cache.read_only {
+            return Err(eyre!(
+                "A read-only cache can't store requests in maintenance mode"
+            ));
+        }
+        if self.signing.enabled {
+            if self.signing.secret.expose().is_empty() {
+                return Err(eyre!(
+                    "A signing secret must be specified to check signatures"
+                ));
+            }
+            if hyper::header::HeaderName::from_bytes(self.signing.header.as_bytes())
+                .is_err()
+            {
+                return Err(eyre!(
+                    "Invalid signature header name: '{}'",
+                    self.signing.header
+                ));
+            }
+        }
         if let LogDestination::File = self.logging.destination {
             if self.logging.file_path.is_empty() {
                 return Err(eyre!("File path must be specified when logging to a file"));
@@ -378,19 +1294,15 @@ impl Settings {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ProxyMode {
+    #[default]
     Cache,
     Store,
     Forward,
     Passthrough,
-}
-
-impl Default for ProxyMode {
-    fn default() -> Self {
-        ProxyMode::Cache
-    }
+    Auto,
 }
 
 impl TryFrom<&str> for ProxyMode {
@@ -406,25 +1318,35 @@ impl TryFrom<&str> for ProxyMode {
             Ok(ProxyMode::Forward)
         } else if "passthrough".starts_with(&sl) {
             Ok(ProxyMode::Passthrough)
+        } else if "auto".starts_with(&sl) {
+            Ok(ProxyMode::Auto)
         } else {
-            Err(eyre!("proxy mode '{}' must be a prefix of cache, store, forward or passthrough", s))
+            Err(eyre!("proxy mode '{}' must be a prefix of cache, store, forward, passthrough or auto", s))
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The format of the access log: `Common` or `Combined` Log Format (the
+/// latter adds the referrer and user agent), or `Off` for none.
+pub enum AccessLogFormat {
+    #[default]
+    Off,
+    Common,
+    Combined,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogDestination {
+    #[default]
     #[serde(alias = "c")]
     Console,
     #[serde(alias = "f")]
     File,
-}
-
-impl Default for LogDestination {
-    fn default() -> Self {
-        LogDestination::Console
-    }
+    #[serde(alias = "s")]
+    Syslog,
 }
 
 impl TryFrom<&str> for LogDestination {
@@ -436,29 +1358,29 @@ impl TryFrom<&str> for LogDestination {
             Ok(LogDestination::Console)
         } else if "file".starts_with(&sl) {
             Ok(LogDestination::File)
+        } else if "syslog".starts_with(&sl) {
+            Ok(LogDestination::Syslog)
         } else {
-            Err(eyre!("log destination '{}' must be a prefix of console or file", s))
+            Err(eyre!(
+                "log destination '{}' must be a prefix of console, file or syslog",
+                s
+            ))
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Off,
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        LogLevel::Info
-    }
-}
-
 impl TryFrom<&str> for LogLevel {
     type Error = Report;
 
@@ -481,3 +1403,207 @@ impl TryFrom<&str> for LogLevel {
         }
     }
 }
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How responses to a kind of request interact with the cache.
+///
+/// `Cache` responses are stored and served when COPS can't be reached;
+/// `Live` responses are always obtained from COPS and never served from cache.
+pub enum CachePolicy {
+    #[default]
+    Cache,
+    Live,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// What happens to a new request when the cache holds as many
+/// un-forwarded requests as it may.
+///
+/// `Reject` doesn't store the new request; `EvictOldest` removes the
+/// oldest un-forwarded request to make room for it.
+pub enum FullPolicy {
+    #[default]
+    Reject,
+    EvictOldest,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// What happens to a request that the cache fails to store.
+///
+/// `Serve` answers it anyway, as best it can, though it won't be
+/// forwarded later; `Reject` answers it with a 503, so the client can
+/// retry it against a healthy proxy.
+pub enum UnavailablePolicy {
+    #[default]
+    Serve,
+    Reject,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// The order in which a serving proxy drains on shutdown.
+pub enum ShutdownOrder {
+    #[default]
+    Concurrent,
+    ServingFirst,
+    ForwardingFirst,
+}
+
+impl ShutdownOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownOrder::Concurrent => "concurrent",
+            ShutdownOrder::ServingFirst => "serving-first",
+            ShutdownOrder::ForwardingFirst => "forwarding-first",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// How the startup self-test treats a COPS probe that fails.
+///
+/// `Auto` fails the self-test in modes that need COPS to answer
+/// clients, and only warns in store and auto modes, which can work
+/// offline.  `Skip` doesn't probe COPS at all.
+pub enum SelfTestCops {
+    #[default]
+    Auto,
+    Require,
+    Warn,
+    Skip,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What is done with a successful COPS response that has no body.
+///
+/// `Pass` returns it to the client without caching it, so it's never
+/// replayed; `Cache` caches it like any other; `Fail` treats it as a
+/// failed call.  A forwarded request that gets an uncached empty
+/// response counts as a failure, so it's forwarded again later.
+pub enum EmptyBodyPolicy {
+    #[default]
+    Pass,
+    Cache,
+    Fail,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The checksum stored with each cached response.
+///
+/// Responses are verified with the algorithm they were stored with,
+/// so changing this doesn't invalidate existing responses.
+pub enum Checksum {
+    None,
+    Crc32,
+    #[default]
+    Sha1,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// The HMAC used to sign requests.
+pub enum SignatureAlgorithm {
+    HmacSha1,
+    #[default]
+    HmacSha256,
+}
+
+/// A configured secret, which is shown as redacted when settings are
+/// logged.  It's saved as is.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"<redacted>\"")
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// The headers which tell COPS the address of the client.
+pub enum ForwardedHeader {
+    #[default]
+    Off,
+    XForwardedFor,
+    Forwarded,
+    Both,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What happens to a forwarding header sent by the client.
+pub enum ForwardedMode {
+    #[default]
+    Append,
+    Replace,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// The method of a probe of COPS.
+pub enum ProbeMethod {
+    #[default]
+    Get,
+    Head,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+/// How HTTP/2 is used for connections to COPS.
+///
+/// `PriorKnowledge` always uses HTTP/2, so COPS must support it;
+/// `Alpn` offers HTTP/2 when connecting with TLS, and uses HTTP/1.1
+/// if COPS doesn't choose it.
+pub enum Http2Mode {
+    #[default]
+    Off,
+    PriorKnowledge,
+    Alpn,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsVersion::Tls10 => "1.0".fmt(f),
+            TlsVersion::Tls11 => "1.1".fmt(f),
+            TlsVersion::Tls12 => "1.2".fmt(f),
+            TlsVersion::Tls13 => "1.3".fmt(f),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+/*
+Copyright 2020 Adobe
+All Rights Reserved.
+
+NOTICE: Adobe permits you to use, modify, and distribute this file in
+accordance with the terms of the Adobe license agreement accompanying
+it.
+*/
+use crate::cops::Kind;
+use crate::metrics;
+use crate::settings::Settings;
+use eyre::{Result, WrapErr};
+use log::{debug, info};
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+struct Sink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+static SINK: OnceCell<Sink> = OnceCell::new();
+static LAST_SENT: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(Default::default);
+
+/// Start sending metrics to statsd, if that's been configured.
+///
+/// Counters are sent as deltas on the configured interval, and COPS
+/// latencies are sent as timings as soon as they are observed.
+pub fn spawn_emitter(conf: &Settings) -> Result<Option<JoinHandle<()>>> {
+    if !conf.metrics.statsd {
+        return Ok(None);
+    }
+    let target = format!("{}:{}", conf.metrics.statsd_host, conf.metrics.statsd_port);
+    let socket = UdpSocket::bind("0.0.0.0:0").wrap_err("Can't create statsd socket")?;
+    socket.connect(&target).wrap_err(format!("Can't reach statsd server: {}", target))?;
+    socket.set_nonblocking(true).wrap_err("Can't configure statsd socket")?;
+    // each instance's metrics go under a name of their own
+    let prefix = match conf.proxy.instance_id.as_str() {
+        "" => conf.metrics.statsd_prefix.clone(),
+        id => format!("{}.{}", conf.metrics.statsd_prefix, id),
+    };
+    if SINK.set(Sink { socket, prefix }).is_err() {
+        debug!("Statsd sink was already initialized");
+    }
+    info!("Sending metrics to statsd at {}", target);
+    let secs = conf.metrics.statsd_interval_secs.max(1);
+    Ok(Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            flush();
+        }
+    })))
+}
+
+/// Stop the periodic emitter, sending any counts not yet sent.
+pub fn stop(emitter: Option<JoinHandle<()>>) {
+    if let Some(emitter) = emitter {
+        emitter.abort();
+        flush();
+    }
+}
+
+/// Send a timing, if statsd is enabled.
+pub fn timing(name: &str, kind: Option<&Kind>, ms: u64) {
+    if let Some(sink) = SINK.get() {
+        sink.send(&format!("{}:{}|ms", sink.metric_name(name, kind), ms));
+    }
+}
+
+/// Send the change in every counter, and the value of every
+/// changed gauge, since the last flush.
+fn flush() {
+    let sink = match SINK.get() {
+        Some(sink) => sink,
+        None => return,
+    };
+    let mut last_sent = LAST_SENT.lock().unwrap();
+    for sample in metrics::samples() {
+        let name = sink.metric_name(sample.name, sample.kind.as_ref());
+        let last = last_sent.entry(name.clone()).or_insert(0);
+        if sample.gauge {
+            if sample.value != *last {
+                sink.send(&format!("{}:{}|g", name, sample.value));
+                *last = sample.value;
+            }
+        } else if sample.value > *last {
+            sink.send(&format!("{}:{}|c", name, sample.value - *last));
+            *last = sample.value;
+        }
+    }
+}
+
+impl Sink {
+    fn metric_name(&self, name: &str, kind: Option<&Kind>) -> String {
+        match kind {
+            Some(kind) => {
+                format!("{}.{}.{}", self.prefix, name, kind.to_string().to_lowercase())
+            }
+            None => format!("{}.{}", self.prefix, name),
+        }
+    }
+
+    fn send(&self, line: &str) {
+        // statsd is best-effort: never let it interfere with the proxy
+        if let Err(err) = self.socket.send(line.as_bytes()) {
+            debug!("Failed to send metric to statsd: {}", err);
+        }
+    }
+}